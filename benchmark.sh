@@ -12,6 +12,7 @@ REPORT_FILE="$WD/reports/$(date +"%Y-%m-%d_%H:%M:%S")_merge.txt"
 # Not a proxy anymore, is a dummy for this test
 PROXY=http://app1.atrium.127.0.0.1.nip.io:8080
 BENCH_CMD="rewrk -c 400 -t 8 -d 20s -h ${PROXY} --pct >> $REPORT_FILE"
+STATIC_APP=http://static-app.atrium.127.0.0.1.nip.io:8080
 
 axum_bench() {
   # Build for production
@@ -43,6 +44,35 @@ axum_bench() {
   kill $TEST_PROXY_PID
 }
 
+# CPU time (user + system) of a process, in clock ticks
+cpu_ticks() {
+  awk '{print $14 + $15}' /proc/$1/stat
+}
+
+compression_bench() {
+  cd ${WD}
+  cargo build --release
+  cd ${WD}/target/release/
+  # Static page of about 30 kB
+  mkdir -p tests/data
+  for i in $(seq 1 500); do
+    echo "<p>Lorem ipsum dolor sit amet, consectetur adipiscing elit</p>"
+  done >tests/data/index.html
+  for ENABLED in false true; do
+    cp ${WD}/atrium.yaml ${WD}/target/release/
+    sed -i "s/^    host: static-app$/    host: static-app\n    compression:\n      enabled: ${ENABLED}/" atrium.yaml
+    ./atrium &
+    TEST_PROXY_PID=$!
+    sleep 2
+    echo -e "####################\n### COMPRESSION ${ENABLED}  ###\n####################\n" >>$REPORT_FILE
+    CPU_BEFORE=$(cpu_ticks $TEST_PROXY_PID)
+    rewrk -c 400 -t 8 -d 20s -h ${STATIC_APP}/index.html -H "Accept-Encoding: gzip, br, zstd" --pct >>$REPORT_FILE
+    CPU_AFTER=$(cpu_ticks $TEST_PROXY_PID)
+    echo "CPU time: $(((CPU_AFTER - CPU_BEFORE) / $(getconf CLK_TCK)))s" >>$REPORT_FILE
+    kill $TEST_PROXY_PID
+  done
+}
+
 #####################################################################
 #                            INSTALL RWRK                           #
 #####################################################################
@@ -60,5 +90,6 @@ axum_bench 0.6.13
 axum_bench 0.6.14
 axum_bench 0.6.15
 axum_bench 0.6.12
+compression_bench
 
-cat $REPORT_FILE | grep -E 'AXUM|Req/Sec'
+cat $REPORT_FILE | grep -E 'AXUM|COMPRESSION|Req/Sec|CPU time'
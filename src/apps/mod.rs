@@ -1,31 +1,42 @@
 use axum::{
-    extract::{ConnectInfo, Host, Path, State},
+    extract::{Host, Path, State},
     http::{
         uri::{Authority, Scheme},
         Request, Response,
     },
-    response::IntoResponse,
-    Json,
+    Json, TypedHeader,
 };
 use axum_extra::extract::cookie::{Cookie, SameSite};
 use base64ct::Encoding;
-use headers::HeaderValue;
+use headers::{ETag, HeaderName, HeaderValue};
 use http::header::{AUTHORIZATION, SET_COOKIE};
 use hyper::{header::LOCATION, Body, StatusCode, Uri};
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use utoipa::ToSchema;
 
 use crate::{
-    appstate::{Client, ConfigFile, ConfigState},
-    configuration::{config_or_error, HostType},
-    users::{check_authorization, AdminToken, UserTokenWithoutXSRFCheck},
-    utils::{is_default, option_vec_trim_remove_empties, string_trim, vec_trim_remove_empties},
+    appstate::{Client, ConfigState},
+    audit::{AuditAction, AuditEvent, AuditLog},
+    client_ip::ClientIp,
+    compression::Compression,
+    config_writer::{ConfigError, ConfigTransaction, ConfigWriter},
+    configuration::{Config, HostType},
+    cors::Cors,
+    headers::OptionalIfMatch,
+    identity::{forward_identity, strip_identity_headers},
+    policy::{glob_match, Access, IpFilter, PolicyRule},
+    secrets::Secret,
+    users::{check_authorization, AdminToken, UserTokenWithoutXSRFCheck, REDACTED},
+    utils::{
+        is_default, merge_patch, option_vec_trim_remove_empties, string_trim,
+        vec_trim_remove_empties,
+    },
 };
 
-pub static AUTHENTICATED_USER_MAIL_HEADER: &str = "Remote-User";
-
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub struct App {
+    /// Assigned by the server when omitted
+    #[serde(default)]
     pub id: usize,
     #[serde(deserialize_with = "string_trim")]
     pub name: String,
@@ -46,12 +57,9 @@ pub struct App {
         deserialize_with = "string_trim"
     )]
     pub login: String,
-    #[serde(
-        default,
-        skip_serializing_if = "is_default",
-        deserialize_with = "string_trim"
-    )]
-    pub password: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[schema(value_type = String)]
+    pub password: Secret,
     #[serde(
         default,
         skip_serializing_if = "is_default",
@@ -66,14 +74,67 @@ pub struct App {
     pub roles: Vec<String>,
     #[serde(default, skip_serializing_if = "is_default")]
     pub inject_security_headers: bool,
+    /// Security headers profile, the default one if empty
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "string_trim"
+    )]
+    pub security_headers: String,
     #[serde(
         default,
         skip_serializing_if = "is_default",
         deserialize_with = "option_vec_trim_remove_empties"
     )]
     pub subdomains: Option<Vec<String>>,
+    /// Forward the email of the user in the Remote-User header
     #[serde(default, skip_serializing_if = "is_default")]
     pub forward_user_mail: bool,
+    /// Forward the login, roles and email of the user in the Remote-User, Remote-Groups and Remote-Email headers
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub forward_user_identity: bool,
+    /// Key signing the short-lived HS256 JWT of the user forwarded in the Remote-Jwt header, no JWT is forwarded if empty
+    #[serde(default, skip_serializing_if = "is_default")]
+    #[schema(value_type = String)]
+    pub identity_jwt_secret: Secret,
+    /// Rules evaluated in order before the roles of the app, the first one matching the request deciding
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub policy: Vec<PolicyRule>,
+    /// Globs of the paths reachable without login in a secured app, `*` matching within a path segment and `**` across segments
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub public_paths: Vec<String>,
+    /// Clients allowed to reach the app, in addition to the global filter
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub ip_filter: IpFilter,
+    /// Cross-origin requests allowed to the app, left to the app if not set
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub cors: Option<Cors>,
+    /// Compression of the responses of the app
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub compression: Compression,
+}
+
+impl App {
+    /// Tells if a path, normalized by `normalize_path`, is reachable without login
+    pub fn is_public(&self, path: &str) -> bool {
+        self.public_paths
+            .iter()
+            .any(|p| glob_match(p.as_bytes(), path.as_bytes()))
+    }
+
+    /// Replaces the sensitive fields, so that the app can be sent to the clients
+    pub fn redacted(mut self) -> Self {
+        for secret in [&mut self.password, &mut self.identity_jwt_secret] {
+            if !secret.is_empty() {
+                *secret = Secret::new(REDACTED.to_owned());
+            }
+        }
+        self
+    }
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -129,7 +190,7 @@ impl AppWithUri {
 
 pub async fn proxy_handler(
     user: Option<UserTokenWithoutXSRFCheck>,
-    ConnectInfo(_): ConnectInfo<SocketAddr>,
+    ClientIp(ip): ClientIp,
     app: HostType,
     Host(hostname): Host,
     State(config): State<ConfigState>,
@@ -137,8 +198,13 @@ pub async fn proxy_handler(
     mut req: Request<Body>,
 ) -> Result<Response<Body>, ()> {
     let domain = hostname.split(':').next().unwrap_or_default();
+    let access = Access {
+        method: req.method(),
+        path: req.uri().path(),
+        source: ip,
+    };
     if let Some(mut value) =
-        check_authorization(&app, &user.as_ref().map(|u| &u.0), domain, req.uri().path())
+        check_authorization(&app, &user.as_ref().map(|u| &u.0), domain, &access)
     {
         // Redirect to login page if user is not logged, write where to get back after login in a cookie
         if value.status() == StatusCode::UNAUTHORIZED {
@@ -182,9 +248,20 @@ pub async fn proxy_handler(
         );
     }
 
+    // Personal tokens are meant for atrium, do not leak them to the app
+    if user.as_ref().is_some_and(|u| u.0.scope.is_some()) {
+        req.headers_mut().remove(AUTHORIZATION);
+    }
+
+    // Only atrium may tell the app who the user is
+    strip_identity_headers(req.headers_mut());
+    if let Some(user) = &user {
+        forward_identity(req.headers_mut(), &app.inner, &user.0, &config.hostname);
+    }
+
     // If the app contains basic auth information, forge a basic auth header
-    if !app.inner.login.is_empty() && !app.inner.password.is_empty() {
-        let bauth = format!("{}:{}", app.inner.login, app.inner.password);
+    if !app.inner.login.is_empty() && !app.inner.password.expose().is_empty() {
+        let bauth = format!("{}:{}", app.inner.login, app.inner.password.expose());
         req.headers_mut().insert(
             AUTHORIZATION,
             HeaderValue::from_str(&format!(
@@ -203,55 +280,310 @@ pub async fn proxy_handler(
     Ok(response)
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/admin/apps",
+    tag = "apps",
+    security(("cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "All the apps, without their credentials", body = [App], headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 401, description = "User is not an administrator"),
+    ),
+)]
 pub async fn get_apps(
-    State(config_file): State<ConfigFile>,
+    State(writer): State<ConfigWriter>,
     _admin: AdminToken,
-) -> Result<Json<Vec<App>>, (StatusCode, &'static str)> {
-    let config = config_or_error(&config_file).await?;
-    // Return all the apps as Json
-    Ok(Json(config.apps))
+) -> Result<(TypedHeader<ETag>, Json<Vec<App>>), (StatusCode, &'static str)> {
+    let (config, etag) = writer.read().await?;
+    // Return all the apps as Json, without their credentials
+    Ok((
+        etag,
+        Json(config.apps.into_iter().map(App::redacted).collect()),
+    ))
 }
 
-pub async fn delete_app(
-    State(config_file): State<ConfigFile>,
+#[utoipa::path(
+    get,
+    path = "/api/admin/apps/{app_id}",
+    tag = "apps",
+    security(("cookie" = []), ("bearer" = [])),
+    params(("app_id" = usize, Path, description = "App id")),
+    responses(
+        (status = 200, description = "The app, without its credentials", body = App, headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "App does not exist"),
+    ),
+)]
+pub async fn get_app(
+    State(writer): State<ConfigWriter>,
     _admin: AdminToken,
     Path(app_id): Path<usize>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let mut config = config_or_error(&config_file).await?;
+) -> Result<(TypedHeader<ETag>, Json<App>), (StatusCode, &'static str)> {
+    let (config, etag) = writer.read().await?;
+    let app = config
+        .apps
+        .into_iter()
+        .find(|a| a.id == app_id)
+        .ok_or((StatusCode::NOT_FOUND, "app doesn't exist"))?;
+    Ok((etag, Json(app.redacted())))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/apps/{app_id}",
+    tag = "apps",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("app_id" = usize, Path, description = "App id"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    responses(
+        (status = 200, description = "App deleted", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "App does not exist"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
+pub async fn delete_app(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Path(app_id): Path<usize>,
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), ConfigError> {
+    let mut transaction = writer.transaction(if_match).await?;
+    let config = &mut transaction.config;
     // Find the app
-    if let Some(pos) = config.apps.iter().position(|a| a.id == app_id) {
+    let deleted = if let Some(pos) = config.apps.iter().position(|a| a.id == app_id) {
         // It is an existing app, delete it
-        config.apps.remove(pos);
+        config.apps.remove(pos)
     } else {
         // If the app doesn't exist, respond with an error
-        return Err((StatusCode::BAD_REQUEST, "app doesn't exist"));
-    }
+        return Err((StatusCode::NOT_FOUND, "app doesn't exist").into());
+    };
 
-    config
-        .to_file_or_internal_server_error(&config_file)
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::AppDeleted)
+                .target(app_id)
+                .change(Some(&deleted.redacted()), None),
+        )
         .await?;
 
-    Ok((StatusCode::OK, "app deleted successfully"))
+    Ok((StatusCode::OK, etag, "app deleted successfully"))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/admin/apps",
+    tag = "apps",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    request_body(content = App, description = "App to create, an id is assigned if it is omitted or 0"),
+    responses(
+        (status = 201, description = "App created", headers(("ETag" = String, description = "Entity tag of the configuration"), ("Location" = String, description = "URL of the created app"))),
+        (status = 400, description = "Configuration would be invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 409, description = "App already exists"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
 pub async fn add_app(
-    State(config_file): State<ConfigFile>,
-    State(config): State<ConfigState>,
-    _admin: AdminToken,
-    Json(payload): Json<App>,
-) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
-    // Clone the config
-    let mut config = (*config).clone();
-    // Find the app
-    if let Some(app) = config.apps.iter_mut().find(|a| a.id == payload.id) {
-        *app = payload;
-    } else {
-        config.apps.push(payload);
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Json(mut payload): Json<App>,
+) -> Result<
+    (
+        StatusCode,
+        TypedHeader<ETag>,
+        [(HeaderName, String); 1],
+        &'static str,
+    ),
+    ConfigError,
+> {
+    // Work on the file configuration, so that environment overrides are not written back
+    let mut transaction = writer.transaction(if_match).await?;
+    let config = &mut transaction.config;
+    if payload.id == 0 {
+        // The app has no id, assign the next free one
+        payload.id = config.apps.iter().map(|a| a.id).max().unwrap_or(0) + 1;
+    } else if config.apps.iter().any(|a| a.id == payload.id) {
+        return Err((StatusCode::CONFLICT, "app already exists").into());
     }
+    let id = payload.id;
+    let after = store_app(&mut transaction, payload).await?;
 
-    config
-        .to_file_or_internal_server_error(&config_file)
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::AppCreated)
+                .target(id)
+                .change(None, Some(&after)),
+        )
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        etag,
+        [(LOCATION, format!("/api/admin/apps/{id}"))],
+        "app created successfully",
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/admin/apps/{app_id}",
+    tag = "apps",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("app_id" = usize, Path, description = "App id"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    request_body(content = App, description = "New value of the app, a REDACTED password is left unchanged"),
+    responses(
+        (status = 200, description = "App replaced", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 400, description = "Configuration would be invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "App does not exist"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
+pub async fn replace_app(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Path(app_id): Path<usize>,
+    Json(mut payload): Json<App>,
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), ConfigError> {
+    let mut transaction = writer.transaction(if_match).await?;
+    let before = find_app(&transaction.config, app_id)?;
+    payload.id = app_id;
+    let after = store_app(&mut transaction, payload).await?;
+
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::AppUpdated)
+                .target(app_id)
+                .change(Some(&before), Some(&after)),
+        )
         .await?;
 
-    Ok((StatusCode::CREATED, "app created or updated successfully"))
+    Ok((StatusCode::OK, etag, "app updated successfully"))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/admin/apps/{app_id}",
+    tag = "apps",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("app_id" = usize, Path, description = "App id"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    request_body(content = Object, description = "JSON merge patch (RFC 7396) of the app"),
+    responses(
+        (status = 200, description = "App updated", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 400, description = "Configuration would be invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "App does not exist"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+        (status = 422, description = "Patched app is invalid"),
+    ),
+)]
+pub async fn patch_app(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Path(app_id): Path<usize>,
+    Json(patch): Json<serde_json::Value>,
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), ConfigError> {
+    let mut transaction = writer.transaction(if_match).await?;
+    let before = find_app(&transaction.config, app_id)?;
+    // Patch the redacted app, so that an untouched password stays as it is
+    let mut value = serde_json::to_value(&before)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "could not encode app"))?;
+    merge_patch(&mut value, patch);
+    let mut payload: App = serde_json::from_value(value)
+        .map_err(|_| (StatusCode::UNPROCESSABLE_ENTITY, "patched app is invalid"))?;
+    payload.id = app_id;
+    let after = store_app(&mut transaction, payload).await?;
+
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::AppUpdated)
+                .target(app_id)
+                .change(Some(&before), Some(&after)),
+        )
+        .await?;
+
+    Ok((StatusCode::OK, etag, "app updated successfully"))
+}
+
+/// Returns the redacted app with the given id
+fn find_app(config: &Config, app_id: usize) -> Result<App, (StatusCode, &'static str)> {
+    config
+        .apps
+        .iter()
+        .find(|a| a.id == app_id)
+        .map(|a| a.clone().redacted())
+        .ok_or((StatusCode::NOT_FOUND, "app doesn't exist"))
+}
+
+/// Creates or replaces an app in the configuration, returning it redacted
+pub(crate) async fn store_app(
+    transaction: &mut ConfigTransaction,
+    mut payload: App,
+) -> Result<App, (StatusCode, &'static str)> {
+    let stored = transaction.config.apps.iter().find(|a| a.id == payload.id);
+    let stored_password = stored.map(|a| a.password.clone()).unwrap_or_default();
+    let stored_jwt_secret = stored
+        .map(|a| a.identity_jwt_secret.clone())
+        .unwrap_or_default();
+    let secrets = [
+        (&mut payload.password, stored_password, "password"),
+        (
+            &mut payload.identity_jwt_secret,
+            stored_jwt_secret,
+            "identity_jwt_secret",
+        ),
+    ];
+    for (secret, stored, name) in secrets {
+        // A redacted secret means that it is unchanged
+        if secret.reference().is_none() && secret.expose() == REDACTED {
+            *secret = stored.clone();
+        }
+        // Secret references can only be set in the configuration file, as they give access to the server environment
+        if secret.reference().is_some() && *secret != stored {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "secret references can only be set in the configuration file",
+            ));
+        }
+        // Do not inline the secret in the configuration file
+        transaction
+            .persist_secret(secret, &format!("app_{}_{name}", payload.id))
+            .await?;
+    }
+    let redacted = payload.clone().redacted();
+    // Find the app
+    let apps = &mut transaction.config.apps;
+    if let Some(app) = apps.iter_mut().find(|a| a.id == payload.id) {
+        *app = payload;
+    } else {
+        apps.push(payload);
+    }
+    Ok(redacted)
 }
@@ -2,9 +2,20 @@ use axum::extract::FromRef;
 use axum_extra::extract::cookie::Key;
 use hyper_trust_dns::{RustlsHttpsConnector, TrustDnsResolver};
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
-use crate::configuration::{Config, HostType};
+use crate::{
+    audit::{audit_file, AuditLog},
+    config_writer::{ConfigWriter, LiveConfig},
+    configuration::{Config, HostType},
+    csp_reports::CspReports,
+    tokens::{tokens_file, TokenStore},
+    totp::TotpGuard,
+    webauthn::PendingCeremonies,
+};
 
 pub type ConfigMap = Arc<HashMap<String, HostType>>;
 pub type ConfigFile = Arc<String>;
@@ -14,9 +25,14 @@ pub type Client = hyper::client::Client<RustlsHttpsConnector>;
 #[derive(Clone)]
 pub struct AppState {
     key: Key,
-    config: ConfigState,
-    config_map: ConfigMap,
+    live: LiveConfig,
     config_file: ConfigFile,
+    config_writer: ConfigWriter,
+    audit: AuditLog,
+    tokens: TokenStore,
+    totp: TotpGuard,
+    webauthn: PendingCeremonies,
+    csp_reports: CspReports,
     client: Client,
 }
 
@@ -27,11 +43,18 @@ impl AppState {
         config_map: ConfigMap,
         config_file: String,
     ) -> Self {
+        let config_file = Arc::new(config_file);
+        let live = Arc::new(RwLock::new((config, config_map)));
         AppState {
             key,
-            config,
-            config_map,
-            config_file: Arc::new(config_file),
+            live: live.clone(),
+            config_file: config_file.clone(),
+            audit: AuditLog::new(audit_file(&config_file)),
+            tokens: TokenStore::new(tokens_file(&config_file)),
+            totp: TotpGuard::default(),
+            webauthn: PendingCeremonies::default(),
+            csp_reports: CspReports::default(),
+            config_writer: ConfigWriter::new(config_file, live),
             client: hyper::Client::builder()
                 .http1_title_case_headers(true)
                 .build::<_, hyper::Body>(
@@ -49,13 +72,25 @@ impl FromRef<AppState> for Key {
 
 impl FromRef<AppState> for ConfigState {
     fn from_ref(state: &AppState) -> Self {
-        Arc::clone(&state.config)
+        Arc::clone(
+            &state
+                .live
+                .read()
+                .expect("live configuration lock is poisoned")
+                .0,
+        )
     }
 }
 
 impl FromRef<AppState> for ConfigMap {
     fn from_ref(state: &AppState) -> Self {
-        Arc::clone(&state.config_map)
+        Arc::clone(
+            &state
+                .live
+                .read()
+                .expect("live configuration lock is poisoned")
+                .1,
+        )
     }
 }
 
@@ -65,6 +100,42 @@ impl FromRef<AppState> for ConfigFile {
     }
 }
 
+impl FromRef<AppState> for ConfigWriter {
+    fn from_ref(state: &AppState) -> Self {
+        state.config_writer.clone()
+    }
+}
+
+impl FromRef<AppState> for AuditLog {
+    fn from_ref(state: &AppState) -> Self {
+        state.audit.clone()
+    }
+}
+
+impl FromRef<AppState> for TokenStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.tokens.clone()
+    }
+}
+
+impl FromRef<AppState> for TotpGuard {
+    fn from_ref(state: &AppState) -> Self {
+        state.totp.clone()
+    }
+}
+
+impl FromRef<AppState> for PendingCeremonies {
+    fn from_ref(state: &AppState) -> Self {
+        state.webauthn.clone()
+    }
+}
+
+impl FromRef<AppState> for CspReports {
+    fn from_ref(state: &AppState) -> Self {
+        state.csp_reports.clone()
+    }
+}
+
 impl FromRef<AppState> for Client {
     fn from_ref(state: &AppState) -> Self {
         state.client.clone()
@@ -0,0 +1,369 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::users::AdminToken;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+/// Failed logins recorded per window, whatever the clients
+const MAX_FAILURES_PER_WINDOW: usize = 100;
+const FAILURE_WINDOW_SECONDS: i64 = 60;
+
+/// File where the audit trail is kept, next to the configuration file
+pub fn audit_file(config_file: &str) -> PathBuf {
+    Path::new(config_file).with_file_name("audit.jsonl")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    AppCreated,
+    AppUpdated,
+    AppDeleted,
+    UserCreated,
+    UserUpdated,
+    UserDeleted,
+    ConfigRolledBack,
+    ConfigExported,
+    ConfigImported,
+    ProfileUpdated,
+    PasswordChanged,
+    PasswordChangeFailed,
+    TokenCreated,
+    TokenRevoked,
+    TotpEnabled,
+    TotpDisabled,
+    TotpFailed,
+    RecoveryCodeUsed,
+    PasskeyRegistered,
+    PasskeyRemoved,
+    CookieKeyGenerated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct AuditEvent {
+    pub timestamp: i64,
+    /// Login of the user who performed the action, or tried to log in
+    pub actor: String,
+    pub ip: String,
+    pub action: AuditAction,
+    /// Identifier of the app, user or configuration version concerned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Redacted value of the target before the action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub before: Option<Value>,
+    /// Redacted value of the target after the action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub after: Option<Value>,
+}
+
+impl AuditEvent {
+    pub fn new(actor: &str, ip: IpAddr, action: AuditAction) -> Self {
+        AuditEvent {
+            timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+            actor: actor.to_owned(),
+            ip: ip.to_canonical().to_string(),
+            action,
+            target: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn target(mut self, target: impl ToString) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    /// Sets the before and after values, which must have their secrets redacted already
+    pub fn change<T: Serialize>(mut self, before: Option<&T>, after: Option<&T>) -> Self {
+        self.before = before.and_then(|v| serde_json::to_value(v).ok());
+        self.after = after.and_then(|v| serde_json::to_value(v).ok());
+        self
+    }
+}
+
+/// Logins and addresses of the failures already recorded in the current window
+#[derive(Default)]
+struct FailureWindow {
+    start: i64,
+    recorded: HashSet<(String, String)>,
+}
+
+impl FailureWindow {
+    fn admits(&mut self, event: &AuditEvent) -> bool {
+        if event.timestamp - self.start >= FAILURE_WINDOW_SECONDS {
+            self.start = event.timestamp;
+            self.recorded.clear();
+        }
+        self.recorded.len() < MAX_FAILURES_PER_WINDOW
+            && self
+                .recorded
+                .insert((event.actor.clone(), event.ip.clone()))
+    }
+}
+
+/// Append only log of the administrative and security events
+#[derive(Clone)]
+pub struct AuditLog {
+    file: PathBuf,
+    lock: Arc<Mutex<()>>,
+    failures: Arc<std::sync::Mutex<FailureWindow>>,
+}
+
+impl AuditLog {
+    pub fn new(file: PathBuf) -> Self {
+        AuditLog {
+            file,
+            lock: Arc::new(Mutex::new(())),
+            failures: Arc::default(),
+        }
+    }
+
+    /// Records the failed login of an unauthenticated client, once per login and address in a window and a bounded number of times overall,
+    /// so that anyone failing on purpose cannot flood the disk. Failing to record it does not fail the request.
+    pub async fn record_failure(&self, event: AuditEvent) {
+        let admitted = self
+            .failures
+            .lock()
+            .expect("audit failures lock is poisoned")
+            .admits(&event);
+        if admitted {
+            let _ = self.record(event).await;
+        }
+    }
+
+    pub async fn record(&self, event: AuditEvent) -> Result<()> {
+        let mut line = serde_json::to_string(&event)
+            .map_err(|e| anyhow!("could not serialize audit event: {e}"))?;
+        line.push('\n');
+        let _guard = self.lock.lock().await;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)
+            .await
+            .map_err(|e| anyhow!("could not open audit log: {e}"))?;
+        file.write_all(line.as_bytes())
+            .await
+            .map_err(|e| anyhow!("could not write audit log: {e}"))?;
+        file.sync_all()
+            .await
+            .map_err(|e| anyhow!("could not write audit log: {e}"))?;
+        Ok(())
+    }
+
+    /// Records an event, mapping a failure to an error response
+    pub async fn record_or_error(
+        &self,
+        event: AuditEvent,
+    ) -> Result<(), (StatusCode, &'static str)> {
+        self.record(event).await.map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not write audit log",
+            )
+        })
+    }
+
+    pub async fn events(&self) -> Result<Vec<AuditEvent>> {
+        let log = match tokio::fs::read_to_string(&self.file).await {
+            Ok(log) => log,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow!("could not read audit log: {e}")),
+        };
+        log.lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| serde_json::from_str(l).map_err(|e| anyhow!("could not parse audit log: {e}")))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Page number, starting at 1
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+    pub actor: Option<String>,
+    pub action: Option<AuditAction>,
+    pub target: Option<String>,
+    /// Unix timestamps bounding the events, inclusive
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl AuditQuery {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.actor.as_ref().is_none_or(|a| &event.actor == a)
+            && self.action.is_none_or(|a| event.action == a)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|t| event.target.as_ref() == Some(t))
+            && self.since.is_none_or(|s| event.timestamp >= s)
+            && self.until.is_none_or(|u| event.timestamp <= u)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AuditPage {
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+    pub events: Vec<AuditEvent>,
+}
+
+fn paginate(events: Vec<AuditEvent>, query: &AuditQuery) -> AuditPage {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    // Most recent first
+    let events: Vec<AuditEvent> = events
+        .into_iter()
+        .rev()
+        .filter(|e| query.matches(e))
+        .collect();
+    AuditPage {
+        total: events.len(),
+        page,
+        per_page,
+        events: events
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/audit",
+    tag = "audit",
+    security(("cookie" = []), ("bearer" = [])),
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Page of audit events, most recent first", body = AuditPage),
+        (status = 401, description = "User is not an administrator"),
+    ),
+)]
+pub async fn get_audit(
+    State(audit): State<AuditLog>,
+    _admin: AdminToken,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<AuditPage>, (StatusCode, &'static str)> {
+    let events = audit.events().await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not read audit log",
+        )
+    })?;
+    Ok(Json(paginate(events, &query)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::audit::{paginate, AuditAction, AuditEvent, AuditLog, AuditQuery};
+
+    #[tokio::test]
+    async fn test_record_and_filter() {
+        let file = std::env::temp_dir().join("atrium_audit_test.jsonl");
+        let _ = std::fs::remove_file(&file);
+        let audit = AuditLog::new(file.clone());
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        for i in 0..5 {
+            audit
+                .record(AuditEvent::new("admin", ip, AuditAction::AppCreated).target(i))
+                .await
+                .unwrap();
+        }
+        audit
+            .record(AuditEvent::new("intruder", ip, AuditAction::LoginFailed))
+            .await
+            .unwrap();
+        let events = audit.events().await.unwrap();
+        assert_eq!(events.len(), 6);
+        assert_eq!(events[0].ip, "127.0.0.1");
+
+        let page = paginate(
+            events.clone(),
+            &AuditQuery {
+                page: Some(2),
+                per_page: Some(2),
+                action: Some(AuditAction::AppCreated),
+                ..Default::default()
+            },
+        );
+        assert_eq!(page.total, 5);
+        let targets: Vec<_> = page.events.iter().map(|e| e.target.as_deref()).collect();
+        assert_eq!(targets, vec![Some("2"), Some("1")]);
+
+        let page = paginate(
+            events,
+            &AuditQuery {
+                actor: Some("intruder".to_owned()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(page.total, 1);
+        assert_eq!(page.events[0].action, AuditAction::LoginFailed);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_throttled() {
+        let file = std::env::temp_dir().join("atrium_audit_test_failures.jsonl");
+        let _ = std::fs::remove_file(&file);
+        let audit = AuditLog::new(file.clone());
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        for _ in 0..10 {
+            audit
+                .record_failure(AuditEvent::new("admin", ip, AuditAction::LoginFailed))
+                .await;
+        }
+        for i in 0..200 {
+            audit
+                .record_failure(AuditEvent::new(
+                    &format!("user{i}"),
+                    ip,
+                    AuditAction::LoginFailed,
+                ))
+                .await;
+        }
+        let events = audit.events().await.unwrap();
+        assert_eq!(events.len(), super::MAX_FAILURES_PER_WINDOW);
+        assert_eq!(events.iter().filter(|e| e.actor == "admin").count(), 1);
+        std::fs::remove_file(&file).unwrap();
+
+        // Failures are not recorded, but do not fail either, if the log cannot be written
+        let audit = AuditLog::new(std::env::temp_dir().join("does/not/exist.jsonl"));
+        audit
+            .record_failure(AuditEvent::new("admin", ip, AuditAction::LoginFailed))
+            .await;
+    }
+}
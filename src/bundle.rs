@@ -0,0 +1,546 @@
+use std::collections::HashSet;
+
+use argon2::PasswordHash;
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    apps::{store_app, App},
+    audit::{AuditAction, AuditEvent, AuditLog},
+    client_ip::ClientIp,
+    config_writer::{ConfigError, ConfigTransaction, ConfigWriter},
+    configuration::Config,
+    headers::OptionalIfMatch,
+    secrets::Secret,
+    tokens::TokenStore,
+    totp::store_imported_totp,
+    users::{hash_password, AdminToken, User, ADMINS_ROLE, REDACTED},
+};
+
+/// Apps and users of an atrium instance, to be moved to another one
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Bundle {
+    #[serde(default)]
+    pub apps: Vec<App>,
+    /// Users, with their password hashes
+    #[serde(default)]
+    pub users: Vec<User>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BundleFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    /// Format of the bundle, json by default
+    pub format: Option<BundleFormat>,
+    /// Whether the app passwords and user password hashes are exported, they are redacted by default
+    pub include_secrets: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Add the apps and users of the bundle to the existing ones
+    #[default]
+    Merge,
+    /// Replace all the apps and users by the ones of the bundle, revoking the personal tokens of the users removed
+    Replace,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    /// merge by default
+    pub mode: Option<ImportMode>,
+    /// Only report what the import would do
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    AppId,
+    AppHost,
+    UserLogin,
+    /// A new user has a redacted password
+    MissingPassword,
+    /// A new app has a redacted secret
+    MissingSecret,
+    /// The administrator importing the bundle would be removed, or would not be an administrator anymore
+    ImporterLockedOut,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ImportConflict {
+    pub kind: ConflictKind,
+    pub value: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ImportReport {
+    pub mode: ImportMode,
+    pub dry_run: bool,
+    pub apps_added: Vec<usize>,
+    pub apps_replaced: Vec<usize>,
+    pub apps_removed: Vec<usize>,
+    pub users_added: Vec<String>,
+    pub users_replaced: Vec<String>,
+    pub users_removed: Vec<String>,
+    /// The import is not applied if there is any conflict
+    pub conflicts: Vec<ImportConflict>,
+}
+
+impl ImportReport {
+    fn conflict(&mut self, kind: ConflictKind, value: impl ToString) {
+        self.conflicts.push(ImportConflict {
+            kind,
+            value: value.to_string(),
+        });
+    }
+}
+
+/// Works out the bundle of the configuration, with the secrets resolved or redacted
+pub fn export_bundle(config: &Config, include_secrets: bool) -> anyhow::Result<Bundle> {
+    let mut bundle = Bundle {
+        apps: config.apps.clone(),
+        users: config.users.clone(),
+    };
+    if include_secrets {
+        // Secret references only make sense on this instance, export their values
+        for app in bundle.apps.iter_mut() {
+            for secret in [&mut app.password, &mut app.identity_jwt_secret] {
+                secret.resolve()?;
+                *secret = Secret::new(secret.expose().to_owned());
+            }
+        }
+        for totp in bundle.users.iter_mut().filter_map(|u| u.totp.as_mut()) {
+            totp.secret.resolve()?;
+            totp.secret = Secret::new(totp.secret.expose().to_owned());
+        }
+    } else {
+        bundle.apps = bundle.apps.into_iter().map(App::redacted).collect();
+        bundle.users = bundle.users.into_iter().map(User::redacted).collect();
+    }
+    Ok(bundle)
+}
+
+/// Assigns the next free ids to the apps of the bundle that have none
+fn assign_app_ids(config: &Config, bundle: &mut Bundle, mode: ImportMode) {
+    let existing = match mode {
+        ImportMode::Merge => config.apps.iter(),
+        ImportMode::Replace => [].iter(),
+    };
+    let first_id = existing
+        .chain(bundle.apps.iter())
+        .map(|a| a.id)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let apps = bundle.apps.iter_mut().filter(|a| a.id == 0);
+    for (id, app) in (first_id..).zip(apps) {
+        app.id = id;
+    }
+}
+
+/// Reports what importing the bundle would change, and what prevents it
+pub fn plan_import(
+    config: &Config,
+    bundle: &Bundle,
+    mode: ImportMode,
+    importer: &str,
+) -> ImportReport {
+    let mut report = ImportReport {
+        mode,
+        ..Default::default()
+    };
+    let (mut ids, mut hosts, mut logins): (HashSet<usize>, HashSet<&str>, HashSet<&str>) =
+        match mode {
+            ImportMode::Merge => (
+                config.apps.iter().map(|a| a.id).collect(),
+                config.apps.iter().map(|a| a.host.as_str()).collect(),
+                config.users.iter().map(|u| u.login.as_str()).collect(),
+            ),
+            ImportMode::Replace => Default::default(),
+        };
+    for app in bundle.apps.iter() {
+        if !ids.insert(app.id) {
+            report.conflict(ConflictKind::AppId, app.id);
+        }
+        if !hosts.insert(&app.host) {
+            report.conflict(ConflictKind::AppHost, &app.host);
+        }
+        if config.apps.iter().any(|a| a.id == app.id) {
+            report.apps_replaced.push(app.id);
+        } else {
+            let redacted = [&app.password, &app.identity_jwt_secret]
+                .into_iter()
+                .any(|s| s.reference().is_none() && s.expose() == REDACTED);
+            if redacted {
+                report.conflict(ConflictKind::MissingSecret, app.id);
+            }
+            report.apps_added.push(app.id);
+        }
+    }
+    for user in bundle.users.iter() {
+        if !logins.insert(&user.login) {
+            report.conflict(ConflictKind::UserLogin, &user.login);
+        }
+        let exists = config.users.iter().any(|u| u.login == user.login);
+        if !exists && (user.password.is_empty() || user.password == REDACTED) {
+            report.conflict(ConflictKind::MissingPassword, &user.login);
+        }
+        if exists {
+            report.users_replaced.push(user.login.clone());
+        } else {
+            report.users_added.push(user.login.clone());
+        }
+    }
+    if mode == ImportMode::Replace {
+        report.apps_removed = config
+            .apps
+            .iter()
+            .filter(|a| !bundle.apps.iter().any(|b| b.id == a.id))
+            .map(|a| a.id)
+            .collect();
+        report.users_removed = config
+            .users
+            .iter()
+            .filter(|u| !bundle.users.iter().any(|b| b.login == u.login))
+            .map(|u| u.login.clone())
+            .collect();
+    }
+    // Administrators from an identity provider are not in the configuration, and cannot be locked out by an import
+    if config.users.iter().any(|u| u.login == importer) {
+        let kept = match bundle.users.iter().find(|u| u.login == importer) {
+            Some(user) => user.roles.iter().any(|r| r == ADMINS_ROLE),
+            None => mode == ImportMode::Merge,
+        };
+        if !kept {
+            report.conflict(ConflictKind::ImporterLockedOut, importer);
+        }
+    }
+    report
+}
+
+/// Applies a bundle, which must have been planned without conflicts
+async fn apply_import(
+    transaction: &mut ConfigTransaction,
+    bundle: Bundle,
+    mode: ImportMode,
+) -> Result<(), (StatusCode, &'static str)> {
+    if mode == ImportMode::Replace {
+        let config = &mut transaction.config;
+        config
+            .apps
+            .retain(|a| bundle.apps.iter().any(|b| b.id == a.id));
+        config
+            .users
+            .retain(|u| bundle.users.iter().any(|b| b.login == u.login));
+    }
+    for app in bundle.apps {
+        store_app(transaction, app).await?;
+    }
+    for mut user in bundle.users {
+        let stored_totp = transaction
+            .config
+            .users
+            .iter()
+            .find(|u| u.login == user.login)
+            .and_then(|u| u.totp.clone());
+        user.totp = store_imported_totp(transaction, &user.login, stored_totp, user.totp).await?;
+        let config = &mut transaction.config;
+        let stored = config.users.iter().position(|u| u.login == user.login);
+        if user.password.is_empty() || user.password == REDACTED {
+            // A redacted password means that it is unchanged
+            user.password = stored
+                .map(|pos| config.users[pos].password.clone())
+                .ok_or((StatusCode::BAD_REQUEST, "password is required"))?;
+        } else if PasswordHash::new(&user.password).is_err() {
+            // Exported users come with their password hashes, hash the plain passwords
+            user.password = hash_password(user.password.as_bytes())
+                .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "could not hash password"))?;
+        }
+        // Passkeys are only registered by the users themselves
+        user.passkeys = stored
+            .map(|pos| config.users[pos].passkeys.clone())
+            .unwrap_or_default();
+        match stored {
+            Some(pos) => config.users[pos] = user,
+            None => config.users.push(user),
+        }
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/export",
+    tag = "config",
+    security(("cookie" = []), ("bearer" = [])),
+    params(ExportQuery),
+    responses(
+        (status = 200, description = "Apps and users", body = Bundle, content_type = ["application/json", "application/yaml"]),
+        (status = 401, description = "User is not an administrator"),
+    ),
+)]
+pub async fn export(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let (config, etag) = writer.read().await?;
+    let include_secrets = query.include_secrets.unwrap_or(false);
+    let bundle = export_bundle(&config, include_secrets)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "could not read secrets"))?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::ConfigExported).target(
+                if include_secrets {
+                    "with secrets"
+                } else {
+                    "without secrets"
+                },
+            ),
+        )
+        .await?;
+    Ok(match query.format.unwrap_or_default() {
+        BundleFormat::Json => (etag, Json(bundle)).into_response(),
+        BundleFormat::Yaml => {
+            let yaml = serde_yaml::to_string(&bundle)
+                .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "could not encode bundle"))?;
+            (etag, [(CONTENT_TYPE, "application/yaml")], yaml).into_response()
+        }
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/import",
+    tag = "config",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ImportQuery,
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    request_body(content = Bundle, description = "Bundle as exported, in JSON or YAML"),
+    responses(
+        (status = 200, description = "Bundle imported, or what importing it would do for a dry run", body = ImportReport),
+        (status = 400, description = "Bundle or resulting configuration is invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 409, description = "Bundle conflicts with the configuration, nothing was imported", body = ImportReport),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
+pub async fn import(
+    State(writer): State<ConfigWriter>,
+    // Grouped to stay within the argument limit
+    (State(audit), State(tokens)): (State<AuditLog>, State<TokenStore>),
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Query(query): Query<ImportQuery>,
+    body: Bytes,
+) -> Result<Response, ConfigError> {
+    // JSON being YAML, both formats are read the same way
+    let mut bundle: Bundle = serde_yaml::from_slice(&body)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "could not parse bundle"))?;
+    let mode = query.mode.unwrap_or_default();
+    let mut transaction = writer.transaction(if_match).await?;
+    assign_app_ids(&transaction.config, &mut bundle, mode);
+    let mut report = plan_import(&transaction.config, &bundle, mode, &admin.0.login);
+    if query.dry_run.unwrap_or(false) {
+        report.dry_run = true;
+        return Ok(Json(report).into_response());
+    }
+    if !report.conflicts.is_empty() {
+        return Ok((StatusCode::CONFLICT, Json(report)).into_response());
+    }
+    apply_import(&mut transaction, bundle, mode).await?;
+
+    let etag = transaction.commit(&admin.0.login).await?;
+    for login in &report.users_removed {
+        tokens.revoke_all(login).await.map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not revoke personal tokens",
+            )
+        })?;
+    }
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::ConfigImported)
+                .change(None, Some(&report)),
+        )
+        .await?;
+
+    Ok((etag, Json(report)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use crate::{
+        apps::App,
+        bundle::{
+            apply_import, assign_app_ids, export_bundle, plan_import, Bundle, ConflictKind,
+            ImportMode,
+        },
+        config_writer::ConfigWriter,
+        configuration::{prepare_config, Config},
+        secrets::Secret,
+        users::{User, ADMINS_ROLE, REDACTED},
+    };
+
+    fn app(id: usize, host: &str) -> App {
+        App {
+            id,
+            host: host.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn user(login: &str, password: &str) -> User {
+        User {
+            login: login.to_owned(),
+            password: password.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn admin(password: &str) -> User {
+        User {
+            roles: vec![ADMINS_ROLE.to_owned()],
+            ..user("admin", password)
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            apps: vec![app(1, "app1"), app(2, "app2")],
+            users: vec![admin("hash")],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_export_redacts_secrets() {
+        let mut config = config();
+        config.apps[0].password = Secret::new("password".to_owned());
+        let bundle = export_bundle(&config, false).unwrap();
+        assert_eq!(bundle.apps[0].password.expose(), REDACTED);
+        assert_eq!(bundle.users[0].password, REDACTED);
+        let bundle = export_bundle(&config, true).unwrap();
+        assert_eq!(bundle.apps[0].password.expose(), "password");
+        assert_eq!(bundle.users[0].password, "hash");
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let mut bundle = Bundle {
+            apps: vec![app(2, "other"), app(0, "app1"), app(0, "app3")],
+            users: vec![user("admin", "x"), user("new", REDACTED)],
+        };
+        bundle.apps[2].password = Secret::new(REDACTED.to_owned());
+        assign_app_ids(&config(), &mut bundle, ImportMode::Merge);
+        assert_eq!(bundle.apps[1].id, 3);
+        assert_eq!(bundle.apps[2].id, 4);
+        let report = plan_import(&config(), &bundle, ImportMode::Merge, "admin");
+        let conflicts: Vec<_> = report
+            .conflicts
+            .iter()
+            .map(|c| (c.kind, c.value.as_str()))
+            .collect();
+        assert_eq!(
+            conflicts,
+            vec![
+                (ConflictKind::AppId, "2"),
+                (ConflictKind::AppHost, "app1"),
+                (ConflictKind::MissingSecret, "4"),
+                (ConflictKind::UserLogin, "admin"),
+                (ConflictKind::MissingPassword, "new"),
+                (ConflictKind::ImporterLockedOut, "admin"),
+            ]
+        );
+        assert_eq!(report.apps_added, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_replace() {
+        let bundle = Bundle {
+            apps: vec![app(2, "app1")],
+            users: vec![admin(REDACTED)],
+        };
+        let report = plan_import(&config(), &bundle, ImportMode::Replace, "admin");
+        assert!(report.conflicts.is_empty());
+        assert_eq!(report.apps_replaced, vec![2]);
+        assert_eq!(report.apps_removed, vec![1]);
+        assert_eq!(report.users_replaced, vec!["admin".to_owned()]);
+    }
+
+    #[test]
+    fn test_importer_is_not_locked_out() {
+        let bundle = Bundle {
+            apps: vec![],
+            users: vec![user("other", "x")],
+        };
+        let report = plan_import(&config(), &bundle, ImportMode::Replace, "admin");
+        assert_eq!(report.conflicts[0].kind, ConflictKind::ImporterLockedOut);
+        assert!(plan_import(&config(), &bundle, ImportMode::Merge, "admin")
+            .conflicts
+            .is_empty());
+        // Administrators from an identity provider are not concerned
+        assert!(
+            plan_import(&config(), &bundle, ImportMode::Replace, "oidc_admin")
+                .conflicts
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_passkeys_are_not_imported() {
+        let config = config();
+        let mut imported = admin(REDACTED);
+        imported.passkeys = vec![Default::default()];
+        let mut new = user("new", "password");
+        new.passkeys = vec![Default::default()];
+        let bundle = Bundle {
+            apps: vec![],
+            users: vec![imported, new],
+        };
+        let dir = std::env::temp_dir().join("atrium_bundle_test_passkeys");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("atrium.yaml").to_str().unwrap().to_owned();
+        config.to_file(&file).await.unwrap();
+        let live = prepare_config(config).unwrap();
+        let writer = ConfigWriter::new(Arc::new(file), Arc::new(RwLock::new(live)));
+        let mut transaction = writer.transaction(None).await.unwrap();
+        apply_import(&mut transaction, bundle, ImportMode::Merge)
+            .await
+            .unwrap();
+        assert!(transaction
+            .config
+            .users
+            .iter()
+            .all(|u| u.passkeys.is_empty()));
+        drop(transaction);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    io::BufRead,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    audit::{audit_file, AuditAction, AuditEvent, AuditLog},
+    config_writer::{ConfigTransaction, ConfigWriter},
+    configuration::{prepare_config, Config},
+    secrets::Secret,
+    server::Server,
+    tokens::{tokens_file, TokenStore},
+    users::{hash_password, remove_user, upsert_user, User, UserInfo},
+    utils::random_string,
+};
+
+pub const CONFIG_FILE: &str = "atrium.yaml";
+/// Author of the changes made from the command line, in the history and the audit trail
+const CLI_ACTOR: &str = "cli";
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "atrium",
+    version,
+    about = "Atrium web server and node administration"
+)]
+pub struct Cli {
+    /// Configuration file to use
+    #[arg(short, long, global = true, default_value = CONFIG_FILE)]
+    pub config: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Start the server (default when no subcommand is given)
+    Serve,
+    /// Validate the configuration file without starting the server
+    CheckConfig,
+    /// Print the argon2 hash of a password (read from stdin if not given)
+    HashPassword { password: Option<String> },
+    /// Create a user, or update it if the login already exists
+    AddUser {
+        login: String,
+        /// Password (read from stdin if not given for a new user)
+        #[arg(long)]
+        password: Option<String>,
+        /// Comma separated list of roles
+        #[arg(long, value_delimiter = ',')]
+        roles: Option<Vec<String>>,
+        #[arg(long)]
+        firstname: Option<String>,
+        #[arg(long)]
+        lastname: Option<String>,
+        #[arg(long)]
+        email: Option<String>,
+    },
+    /// Delete a user
+    DelUser { login: String },
+    /// Remove the second factor of a user who lost the authenticator and the recovery codes
+    ResetTotp { login: String },
+    /// List the configured apps
+    ListApps,
+    /// Generate a cookie signing key
+    GenCookieKey {
+        /// Store the key in the configuration file instead of printing it
+        #[arg(long)]
+        write: bool,
+    },
+}
+
+impl Cli {
+    pub async fn run(self) -> Result<()> {
+        match self.command.unwrap_or(Command::Serve) {
+            Command::Serve => serve(&self.config).await,
+            Command::CheckConfig => check_config(&self.config).await,
+            Command::HashPassword { password } => {
+                let password = password_or_stdin(password)?;
+                println!("{}", hash_password_or_error(&password)?);
+                Ok(())
+            }
+            Command::AddUser {
+                login,
+                password,
+                roles,
+                firstname,
+                lastname,
+                email,
+            } => {
+                let (writer, audit) = writer_and_audit(&self.config).await?;
+                let mut transaction = start(&writer).await?;
+                let existing = transaction
+                    .config
+                    .users
+                    .iter()
+                    .find(|u| u.login == login)
+                    .cloned();
+                let mut user = existing.clone().unwrap_or(User {
+                    login,
+                    ..Default::default()
+                });
+                user.password = match password {
+                    Some(password) => password,
+                    // Keep the stored password of an existing user
+                    None if existing.is_some() => String::new(),
+                    None => password_or_stdin(None)?,
+                };
+                if let Some(roles) = roles {
+                    user.roles = roles;
+                }
+                if firstname.is_some() || lastname.is_some() || email.is_some() {
+                    let info = user.info.get_or_insert_with(UserInfo::default);
+                    info.firstname = firstname.unwrap_or(info.firstname.clone());
+                    info.lastname = lastname.unwrap_or(info.lastname.clone());
+                    info.email = email.unwrap_or(info.email.clone());
+                }
+                let login = user.login.clone();
+                upsert_user(&mut transaction.config, user).map_err(|(_, msg)| anyhow!(msg))?;
+                let after = transaction
+                    .config
+                    .users
+                    .iter()
+                    .find(|u| u.login == login)
+                    .map(|u| u.clone().redacted());
+                commit(transaction).await?;
+                let action = if existing.is_some() {
+                    AuditAction::UserUpdated
+                } else {
+                    AuditAction::UserCreated
+                };
+                let before = existing.map(User::redacted);
+                record(
+                    &audit,
+                    AuditEvent::new(CLI_ACTOR, LOCALHOST, action)
+                        .target(&login)
+                        .change(before.as_ref(), after.as_ref()),
+                )
+                .await?;
+                println!("user {login} created or updated successfully");
+                Ok(())
+            }
+            Command::DelUser { login } => {
+                let (writer, audit) = writer_and_audit(&self.config).await?;
+                let mut transaction = start(&writer).await?;
+                let deleted = remove_user(&mut transaction.config, &login)
+                    .map_err(|(_, msg)| anyhow!(msg))?;
+                commit(transaction).await?;
+                TokenStore::new(tokens_file(&self.config))
+                    .revoke_all(&login)
+                    .await?;
+                record(
+                    &audit,
+                    AuditEvent::new(CLI_ACTOR, LOCALHOST, AuditAction::UserDeleted)
+                        .target(&login)
+                        .change(Some(&deleted.redacted()), None),
+                )
+                .await?;
+                println!("user {login} deleted successfully");
+                Ok(())
+            }
+            Command::ResetTotp { login } => {
+                let (writer, audit) = writer_and_audit(&self.config).await?;
+                let mut transaction = start(&writer).await?;
+                let user = transaction
+                    .config
+                    .users
+                    .iter_mut()
+                    .find(|u| u.login == login)
+                    .ok_or(anyhow!("user does not exist"))?;
+                if user.totp.take().is_none() {
+                    return Err(anyhow!("two-factor authentication is not enabled"));
+                }
+                commit(transaction).await?;
+                record(
+                    &audit,
+                    AuditEvent::new(CLI_ACTOR, LOCALHOST, AuditAction::TotpDisabled).target(&login),
+                )
+                .await?;
+                println!("two-factor authentication of {login} reset successfully");
+                Ok(())
+            }
+            Command::ListApps => {
+                let config = Config::from_file(&self.config).await?;
+                for app in config.apps {
+                    println!(
+                        "{id}\t{name}\t{host}\t{kind}\t{target}{secured}",
+                        id = app.id,
+                        name = app.name,
+                        host = app.host,
+                        kind = if app.is_proxy { "proxy" } else { "static" },
+                        target = app.target,
+                        secured = if app.secured { "\tsecured" } else { "" }
+                    );
+                }
+                Ok(())
+            }
+            Command::GenCookieKey { write } => {
+                let key = random_string(64);
+                if write {
+                    let (writer, audit) = writer_and_audit(&self.config).await?;
+                    let mut transaction = start(&writer).await?;
+                    let mut key = Secret::new(key);
+                    transaction
+                        .persist_secret(&mut key, "cookie_key")
+                        .await
+                        .map_err(|(_, msg)| anyhow!(msg))?;
+                    let reference = key.reference().unwrap_or_default().to_owned();
+                    transaction.config.cookie_key = Some(key);
+                    commit(transaction).await?;
+                    record(
+                        &audit,
+                        AuditEvent::new(CLI_ACTOR, LOCALHOST, AuditAction::CookieKeyGenerated),
+                    )
+                    .await?;
+                    println!("cookie key written to {reference}");
+                } else {
+                    println!("{key}");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Writer of the configuration file and audit trail, for the commands modifying the configuration
+async fn writer_and_audit(config_file: &str) -> Result<(ConfigWriter, AuditLog)> {
+    let live = prepare_config(Config::from_file(config_file).await?)?;
+    Ok((
+        ConfigWriter::new(
+            Arc::new(config_file.to_owned()),
+            Arc::new(RwLock::new(live)),
+        ),
+        AuditLog::new(audit_file(config_file)),
+    ))
+}
+
+async fn start(writer: &ConfigWriter) -> Result<ConfigTransaction> {
+    writer
+        .transaction(None)
+        .await
+        .map_err(|(_, msg)| anyhow!(msg))
+}
+
+async fn commit(transaction: ConfigTransaction) -> Result<()> {
+    transaction
+        .commit(CLI_ACTOR)
+        .await
+        .map(|_| ())
+        .map_err(|e| anyhow!("{e}"))
+}
+
+async fn record(audit: &AuditLog, event: AuditEvent) -> Result<()> {
+    audit
+        .record(event)
+        .await
+        .map_err(|e| anyhow!("configuration saved, but {e}"))
+}
+
+async fn serve(config_file: &str) -> Result<()> {
+    let server = Server::build(config_file).await?;
+    let addr = format!("[::]:{}", server.port) // On linux bind to ipv6 binds to ipv4 as well
+        .parse::<SocketAddr>()
+        .map_err(|e| anyhow!("could not parse listening address: {e}"))?;
+    let app = server
+        .router
+        .into_make_service_with_connect_info::<SocketAddr>();
+
+    axum_server::bind(addr)
+        .acceptor(server.acceptor)
+        .serve(app)
+        .await
+        .map_err(|e| anyhow!("server error: {e}"))
+}
+
+async fn check_config(config_file: &str) -> Result<()> {
+    let mut config = Config::from_file(config_file).await?;
+    let mut problems = Vec::new();
+    if let Err(e) = config.resolve_secrets() {
+        problems.push(e.to_string());
+    }
+    problems.append(&mut config.check());
+    if problems.is_empty() {
+        println!("{config_file} is valid");
+        Ok(())
+    } else {
+        for problem in &problems {
+            eprintln!("{problem}");
+        }
+        Err(anyhow!("{config_file} has {} problem(s)", problems.len()))
+    }
+}
+
+fn password_or_stdin(password: Option<String>) -> Result<String> {
+    match password {
+        Some(password) => Ok(password),
+        None => {
+            let mut line = String::new();
+            std::io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .map_err(|e| anyhow!("could not read password from stdin: {e}"))?;
+            let password = line.trim_end_matches(['\r', '\n']).to_owned();
+            if password.is_empty() {
+                return Err(anyhow!("password is required"));
+            }
+            Ok(password)
+        }
+    }
+}
+
+fn hash_password_or_error(password: &str) -> Result<String> {
+    hash_password(password.as_bytes()).map_err(|e| anyhow!("could not hash password: {e}"))
+}
@@ -0,0 +1,222 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts},
+};
+use http::{request::Parts, HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    appstate::ConfigState,
+    configuration::{Config, TlsMode},
+    policy::cidr_contains,
+    proxy_protocol::ProxiedPeer,
+};
+
+static FORWARDED: &str = "Forwarded";
+static FORWARDED_FOR: &str = "X-Forwarded-For";
+
+/// Header where the trusted proxies write the addresses they forward the requests for, the other one being ignored
+/// as a proxy passes it through from the client unchanged
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`, as nginx `proxy_add_x_forwarded_for` and Traefik write it
+    #[default]
+    XForwardedFor,
+    /// Standard `Forwarded` header, of RFC 7239
+    Forwarded,
+}
+
+/// Address of the client, given by the trusted proxies if atrium is behind some
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+    ConfigState: FromRef<S>,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = ConfigState::from_ref(state);
+        // The PROXY protocol gives the client of the connection, that would otherwise be the proxy
+        let peer = match parts.extensions.get::<ProxiedPeer>() {
+            Some(ProxiedPeer(addr)) => *addr,
+            None => {
+                parts
+                    .extensions
+                    .get::<ConnectInfo<SocketAddr>>()
+                    .ok_or((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "could not find socket address",
+                    ))?
+                    .0
+            }
+        };
+        Ok(ClientIp(client_ip(&config, peer.ip(), &parts.headers)))
+    }
+}
+
+/// Tells if the peer is a proxy whose forwarding headers can be trusted, loopback addresses being trusted if no proxy is configured
+pub(crate) fn is_trusted_proxy(config: &Config, ip: IpAddr) -> bool {
+    if config.trusted_proxies.is_empty() {
+        return match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        }
+        .is_loopback();
+    }
+    config.trusted_proxies.iter().any(|c| cidr_contains(c, ip))
+}
+
+/// Works out the address of the client, which is the peer unless atrium is behind trusted proxies
+pub fn client_ip(config: &Config, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if config.tls_mode != TlsMode::BehindProxy {
+        return peer;
+    }
+    forwarded_client(config, peer, headers)
+}
+
+/// Follows the forwarding headers from the peer as long as the hops are trusted proxies
+pub fn forwarded_client(config: &Config, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    // Each proxy appends the address it got the request from : the client is the last address that is not a trusted proxy
+    let forwarded = forwarded_for(headers, config.forwarded_header);
+    let mut client = peer;
+    for entry in forwarded.iter().rev() {
+        if !is_trusted_proxy(config, client) {
+            break;
+        }
+        match entry {
+            Some(ip) => client = *ip,
+            None => break,
+        }
+    }
+    client
+}
+
+/// Addresses that the proxies forwarded the request for, from the configured header only, unknown or obfuscated ones being `None`
+fn forwarded_for(headers: &HeaderMap, header: ForwardedHeader) -> Vec<Option<IpAddr>> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    match header {
+        ForwardedHeader::XForwardedFor => values(FORWARDED_FOR)
+            .into_iter()
+            .map(|v| v.parse().ok())
+            .collect(),
+        ForwardedHeader::Forwarded => values(FORWARDED)
+            .into_iter()
+            .map(|element| {
+                let node = element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for")
+                        .then_some(value.trim_matches('"'))
+                })?;
+                // Nodes are IPv4 addresses or bracketed IPv6 addresses, both with an optional port
+                match node.strip_prefix('[') {
+                    Some(v6) => v6.split(']').next()?.parse().ok(),
+                    None => node.split(':').next()?.parse().ok(),
+                }
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use http::HeaderMap;
+
+    use crate::{
+        client_ip::{client_ip, forwarded_for, ForwardedHeader},
+        configuration::{Config, TlsMode},
+    };
+
+    #[test]
+    fn test_client_ip() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-For",
+            "6.6.6.6, 1.2.3.4, 10.0.0.2".parse().unwrap(),
+        );
+        let mut config = Config::default();
+        // Not behind a proxy, the headers are not trusted
+        assert_eq!(
+            client_ip(&config, ip("127.0.0.1"), &headers),
+            ip("127.0.0.1")
+        );
+
+        config.tls_mode = TlsMode::BehindProxy;
+        assert_eq!(
+            client_ip(&config, ip("127.0.0.1"), &headers),
+            ip("10.0.0.2")
+        );
+        assert_eq!(client_ip(&config, ip("5.5.5.5"), &headers), ip("5.5.5.5"));
+
+        config.trusted_proxies = vec!["10.0.0.0/8".to_owned(), "127.0.0.1".to_owned()];
+        assert_eq!(client_ip(&config, ip("127.0.0.1"), &headers), ip("1.2.3.4"));
+        assert_eq!(
+            client_ip(&config, ip("::ffff:10.0.0.1"), &headers),
+            ip("1.2.3.4")
+        );
+        assert_eq!(
+            client_ip(&config, ip("127.0.0.1"), &HeaderMap::new()),
+            ip("127.0.0.1")
+        );
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Forwarded",
+            r#"for=192.0.2.60;proto=http;by=203.0.113.43, For="[2001:db8:cafe::17]:4711""#
+                .parse()
+                .unwrap(),
+        );
+        headers.append("Forwarded", "for=10.0.0.1:80, for=unknown".parse().unwrap());
+        // Ignored, as the proxies write the standard header
+        headers.insert("X-Forwarded-For", "6.6.6.6".parse().unwrap());
+        assert_eq!(
+            forwarded_for(&headers, ForwardedHeader::Forwarded),
+            vec![
+                ip("192.0.2.60"),
+                ip("2001:db8:cafe::17"),
+                ip("10.0.0.1"),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spoofed_forwarded() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        // The client sends its own Forwarded header, that a proxy only appending to X-Forwarded-For passes through
+        let mut headers = HeaderMap::new();
+        headers.insert("Forwarded", "for=10.0.0.1".parse().unwrap());
+        headers.insert("X-Forwarded-For", "6.6.6.6".parse().unwrap());
+        let mut config = Config {
+            tls_mode: TlsMode::BehindProxy,
+            trusted_proxies: vec!["10.0.0.0/8".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(client_ip(&config, ip("10.0.0.2"), &headers), ip("6.6.6.6"));
+        // Neither does the standard header fall back to X-Forwarded-For
+        config.forwarded_header = ForwardedHeader::Forwarded;
+        headers.remove("Forwarded");
+        assert_eq!(client_ip(&config, ip("10.0.0.2"), &headers), ip("10.0.0.2"));
+    }
+}
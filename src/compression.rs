@@ -0,0 +1,173 @@
+use axum::body::HttpBody as Body;
+use http::{
+    header::{CONTENT_RANGE, CONTENT_TYPE},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tower_http::compression::{predicate::SizeAbove, Predicate};
+use utoipa::ToSchema;
+
+use crate::utils::{is_default, vec_trim_remove_empties};
+
+/// Minimum size of the compressed responses if not configured, as smaller ones would grow
+const DEFAULT_MIN_SIZE: u16 = 32;
+const DEFAULT_MIME_TYPES: [&str; 7] = [
+    "text/*",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/wasm",
+    "application/manifest+json",
+    "image/svg+xml",
+];
+
+const SERVER_SENT_EVENTS: &str = "text/event-stream";
+
+/// Compression of the responses, negotiated with the client among gzip, brotli and zstd
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Compression {
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub enabled: bool,
+    /// Smaller responses are not compressed, defaults to 32 bytes
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub min_size: u16,
+    /// MIME types of the compressed responses, as `text/html` or `text/*`, defaults to text, JSON, JavaScript, XML, WebAssembly and SVG.
+    /// Event streams only match if listed as `text/event-stream`
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub mime_types: Vec<String>,
+}
+
+impl Compression {
+    fn allows_mime_type(&self, content_type: &str) -> bool {
+        let mime_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        // Event streams are sent as they come, which compression would hold back, as with tower-http's default predicate
+        let matches = |pattern: &str| match pattern.strip_suffix('*') {
+            Some(prefix) => {
+                mime_type != SERVER_SENT_EVENTS
+                    && mime_type.starts_with(&prefix.to_ascii_lowercase())
+            }
+            None => mime_type.eq_ignore_ascii_case(pattern),
+        };
+        match self.mime_types.as_slice() {
+            [] => DEFAULT_MIME_TYPES.into_iter().any(matches),
+            mime_types => mime_types.iter().any(|m| matches(m)),
+        }
+    }
+}
+
+/// Compresses the responses that carry the compression settings of atrium or of their app in their extensions, the compression layer being shared
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConfiguredCompression;
+
+impl Predicate for ConfiguredCompression {
+    fn should_compress<B>(&self, response: &http::Response<B>) -> bool
+    where
+        B: Body,
+    {
+        let Some(compression) = response.extensions().get::<Compression>() else {
+            return false;
+        };
+        let min_size = match compression.min_size {
+            0 => DEFAULT_MIN_SIZE,
+            min_size => min_size,
+        };
+        // Ranges are of the uncompressed content
+        response.status() != StatusCode::PARTIAL_CONTENT
+            && !response.headers().contains_key(CONTENT_RANGE)
+            && response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|t| compression.allows_mime_type(t))
+            && SizeAbove::new(min_size).should_compress(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header::CONTENT_TYPE, Response, StatusCode};
+    use hyper::Body;
+    use tower_http::compression::Predicate;
+
+    use crate::compression::{Compression, ConfiguredCompression};
+
+    fn should_compress(compression: Option<Compression>, content_type: &str, body: &str) -> bool {
+        let mut response = Response::new(Body::from(body.to_owned()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, content_type.parse().unwrap());
+        if let Some(compression) = compression {
+            response.extensions_mut().insert(compression);
+        }
+        ConfiguredCompression.should_compress(&response)
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let long = "a".repeat(100);
+        let enabled = Compression {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(!should_compress(None, "text/html", &long));
+        assert!(should_compress(
+            Some(enabled.clone()),
+            "text/html; charset=utf-8",
+            &long
+        ));
+        assert!(should_compress(
+            Some(enabled.clone()),
+            "application/json",
+            &long
+        ));
+        assert!(!should_compress(
+            Some(enabled.clone()),
+            "text/html",
+            "short"
+        ));
+        assert!(!should_compress(Some(enabled.clone()), "image/png", &long));
+        assert!(!should_compress(
+            Some(enabled.clone()),
+            "text/event-stream",
+            &long
+        ));
+
+        let custom = Compression {
+            enabled: true,
+            min_size: 200,
+            mime_types: vec!["application/x-custom".to_owned(), "font/*".to_owned()],
+        };
+        assert!(!should_compress(Some(custom.clone()), "font/woff", &long));
+        let long = "a".repeat(300);
+        assert!(should_compress(Some(custom.clone()), "font/woff", &long));
+        assert!(should_compress(
+            Some(custom.clone()),
+            "application/x-custom",
+            &long
+        ));
+        assert!(!should_compress(Some(custom), "text/html", &long));
+        let events = Compression {
+            enabled: true,
+            mime_types: vec!["text/event-stream".to_owned()],
+            ..Default::default()
+        };
+        assert!(should_compress(Some(events), "text/event-stream", &long));
+
+        let mut partial = Response::new(Body::from(long));
+        *partial.status_mut() = StatusCode::PARTIAL_CONTENT;
+        partial
+            .headers_mut()
+            .insert(CONTENT_TYPE, "text/html".parse().unwrap());
+        partial.extensions_mut().insert(enabled);
+        assert!(!ConfiguredCompression.should_compress(&partial));
+    }
+}
@@ -0,0 +1,410 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path as UrlPath, State},
+    Json, TypedHeader,
+};
+use headers::ETag;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+use utoipa::ToSchema;
+
+use crate::{
+    audit::{AuditAction, AuditEvent, AuditLog},
+    client_ip::ClientIp,
+    config_writer::{ConfigError, ConfigWriter},
+    configuration::Config,
+    headers::OptionalIfMatch,
+    users::AdminToken,
+};
+
+static INDEX_FILE: &str = "index.jsonl";
+
+/// Directory where the versions of the configuration file are kept, next to the configuration file and named after it
+pub fn history_dir(config_file: &str) -> PathBuf {
+    let path = Path::new(config_file);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    path.with_file_name(format!("{name}.history"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct ConfigVersion {
+    pub version: usize,
+    /// Login of the administrator who made the change, absent for the configuration as it was before the first change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConfigVersionDiff {
+    #[serde(flatten)]
+    pub version: ConfigVersion,
+    /// Unified diff from the previous version
+    pub diff: String,
+}
+
+/// Append only store of the successive contents of the configuration file
+#[derive(Clone)]
+pub struct ConfigHistory {
+    dir: PathBuf,
+}
+
+impl ConfigHistory {
+    pub fn new(dir: PathBuf) -> Self {
+        ConfigHistory { dir }
+    }
+
+    pub async fn versions(&self) -> Result<Vec<ConfigVersion>> {
+        let index = match tokio::fs::read_to_string(self.dir.join(INDEX_FILE)).await {
+            Ok(index) => index,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(anyhow!("could not read configuration history: {e}")),
+        };
+        index
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|l| {
+                serde_json::from_str(l)
+                    .map_err(|e| anyhow!("could not parse configuration history: {e}"))
+            })
+            .collect::<Result<Vec<ConfigVersion>>>()
+            .map(|mut versions| {
+                // Concurrent writers may append out of order
+                versions.sort_by_key(|v| v.version);
+                versions
+            })
+    }
+
+    pub async fn contents(&self, version: usize) -> Result<String> {
+        tokio::fs::read_to_string(self.snapshot_path(version))
+            .await
+            .map_err(|e| anyhow!("could not read configuration version {version}: {e}"))
+    }
+
+    /// Records a new version of the configuration file.
+    ///
+    /// The first time, the previous contents are recorded too, so that it is always possible to go back to them.
+    /// Snapshots are created exclusively, so that processes writing at the same time never share a version.
+    pub async fn record(&self, previous: &str, contents: &str, author: &str) -> Result<usize> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| anyhow!("could not create configuration history directory: {e}"))?;
+        let versions = self.versions().await?;
+        if versions.is_empty() && self.create_snapshot(1, previous).await? {
+            self.append(1, None).await?;
+        }
+        let mut version = versions.last().map(|v| v.version + 1).unwrap_or(2);
+        while !self.create_snapshot(version, contents).await? {
+            version += 1;
+        }
+        self.append(version, Some(author.to_owned())).await?;
+        Ok(version)
+    }
+
+    /// Writes the snapshot of a version, returning false if the version is already taken
+    async fn create_snapshot(&self, version: usize, contents: &str) -> Result<bool> {
+        let path = self.snapshot_path(version);
+        let mut file = match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+            Err(e) => {
+                return Err(anyhow!(
+                    "could not write configuration version {version}: {e}"
+                ))
+            }
+        };
+        let write = async {
+            file.write_all(contents.as_bytes()).await?;
+            file.sync_all().await
+        };
+        if let Err(e) = write.await {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(anyhow!(
+                "could not write configuration version {version}: {e}"
+            ));
+        }
+        Ok(true)
+    }
+
+    async fn append(&self, version: usize, author: Option<String>) -> Result<ConfigVersion> {
+        let entry = ConfigVersion {
+            version,
+            author,
+            timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        let mut line = serde_json::to_string(&entry)
+            .map_err(|e| anyhow!("could not serialize configuration version: {e}"))?;
+        line.push('\n');
+        let mut index = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(INDEX_FILE))
+            .await
+            .map_err(|e| anyhow!("could not open configuration history: {e}"))?;
+        index
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|e| anyhow!("could not write configuration history: {e}"))?;
+        index
+            .sync_all()
+            .await
+            .map_err(|e| anyhow!("could not write configuration history: {e}"))?;
+        Ok(entry)
+    }
+
+    fn snapshot_path(&self, version: usize) -> PathBuf {
+        self.dir.join(format!("{version}.yaml"))
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/config/history",
+    tag = "config",
+    security(("cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Versions of the configuration, most recent first", body = [ConfigVersion]),
+        (status = 401, description = "User is not an administrator"),
+    ),
+)]
+pub async fn get_config_history(
+    State(writer): State<ConfigWriter>,
+    _admin: AdminToken,
+) -> Result<Json<Vec<ConfigVersion>>, (StatusCode, &'static str)> {
+    let mut versions = writer.history().versions().await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not read configuration history",
+        )
+    })?;
+    // Most recent first
+    versions.reverse();
+    Ok(Json(versions))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/config/history/{version}",
+    tag = "config",
+    security(("cookie" = []), ("bearer" = [])),
+    params(("version" = usize, Path, description = "Configuration version")),
+    responses(
+        (status = 200, description = "Version of the configuration, with the diff from the previous one", body = ConfigVersionDiff),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "Version does not exist"),
+    ),
+)]
+pub async fn get_config_version(
+    State(writer): State<ConfigWriter>,
+    _admin: AdminToken,
+    UrlPath(version): UrlPath<usize>,
+) -> Result<Json<ConfigVersionDiff>, (StatusCode, &'static str)> {
+    let history = writer.history();
+    let entry = history
+        .versions()
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not read configuration history",
+            )
+        })?
+        .into_iter()
+        .find(|v| v.version == version)
+        .ok_or((
+            StatusCode::NOT_FOUND,
+            "configuration version does not exist",
+        ))?;
+    let contents = history
+        .contents(version)
+        .await
+        .ok()
+        .and_then(|c| redacted(&c))
+        .ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not read configuration version",
+        ))?;
+    let previous = if version > 1 {
+        history
+            .contents(version - 1)
+            .await
+            .ok()
+            .and_then(|c| redacted(&c))
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+    let diff = TextDiff::from_lines(&previous, &contents)
+        .unified_diff()
+        .header(
+            &format!("version {}", version.saturating_sub(1)),
+            &format!("version {version}"),
+        )
+        .to_string();
+    Ok(Json(ConfigVersionDiff {
+        version: entry,
+        diff,
+    }))
+}
+
+/// Redacts the secrets of a version, which the snapshots of the configuration file may hold
+fn redacted(contents: &str) -> Option<String> {
+    serde_yaml::from_str::<Config>(contents)
+        .ok()?
+        .redacted()
+        .to_yaml()
+        .ok()
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/config/rollback/{version}",
+    tag = "config",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("version" = usize, Path, description = "Configuration version to go back to"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    responses(
+        (status = 200, description = "Configuration rolled back", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 400, description = "Configuration would be invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "Version does not exist"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
+pub async fn rollback_config(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    UrlPath(version): UrlPath<usize>,
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), ConfigError> {
+    let mut transaction = writer.transaction(if_match).await?;
+    let contents = writer.history().contents(version).await.map_err(|_| {
+        (
+            StatusCode::NOT_FOUND,
+            "configuration version does not exist",
+        )
+    })?;
+    transaction.config = serde_yaml::from_str::<Config>(&contents).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not parse configuration version",
+        )
+    })?;
+
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::ConfigRolledBack).target(version),
+        )
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        etag,
+        "configuration rolled back successfully",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        apps::App,
+        config_history::{history_dir, redacted, ConfigHistory},
+        configuration::Config,
+        secrets::Secret,
+        users::{User, REDACTED},
+    };
+
+    #[tokio::test]
+    async fn test_record() {
+        let dir = std::env::temp_dir().join("atrium_config_history_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let history = ConfigHistory::new(dir.clone());
+        assert_eq!(history.record("a\n", "b\n", "admin").await.unwrap(), 2);
+        assert_eq!(history.record("b\n", "c\n", "other").await.unwrap(), 3);
+        let versions = history.versions().await.unwrap();
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[0].author, None);
+        assert_eq!(versions[1].author.as_deref(), Some("admin"));
+        assert_eq!(history.contents(1).await.unwrap(), "a\n");
+        assert_eq!(history.contents(3).await.unwrap(), "c\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_records() {
+        let dir = std::env::temp_dir().join("atrium_config_history_test_concurrent");
+        let _ = std::fs::remove_dir_all(&dir);
+        // Each process has its own history, sharing the directory
+        let tasks = (0..5).map(|i| {
+            let history = ConfigHistory::new(dir.clone());
+            tokio::spawn(async move { history.record("a\n", &format!("{i}\n"), "admin").await })
+        });
+        let mut recorded = Vec::new();
+        for task in tasks {
+            recorded.push(task.await.unwrap().unwrap());
+        }
+        recorded.sort();
+        assert_eq!(recorded, vec![2, 3, 4, 5, 6]);
+        let history = ConfigHistory::new(dir.clone());
+        let versions = history.versions().await.unwrap();
+        assert_eq!(
+            versions.iter().map(|v| v.version).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5, 6]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_history_dir() {
+        assert_ne!(
+            history_dir("/etc/atrium/atrium.yaml"),
+            history_dir("/etc/atrium/other.yaml")
+        );
+        assert_eq!(
+            history_dir("atrium.yaml").to_str(),
+            Some("atrium.yaml.history")
+        );
+    }
+
+    #[test]
+    fn test_redacted() {
+        let contents = Config {
+            apps: vec![App {
+                id: 1,
+                password: Secret::new("ff54fds6f".to_owned()),
+                ..Default::default()
+            }],
+            users: vec![User {
+                login: "admin".to_owned(),
+                password: "$argon2id$hash".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .to_yaml()
+        .unwrap();
+        let snapshot = redacted(&contents).unwrap();
+        assert!(!snapshot.contains("ff54fds6f"));
+        assert!(!snapshot.contains("argon2"));
+        assert!(snapshot.contains(REDACTED));
+        assert!(redacted("not: [a configuration").is_none());
+    }
+}
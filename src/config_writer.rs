@@ -0,0 +1,418 @@
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    response::{IntoResponse, Response},
+    TypedHeader,
+};
+use headers::{ETag, IfMatch};
+use http::StatusCode;
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::{
+    appstate::{ConfigFile, ConfigMap, ConfigState},
+    config_history::{history_dir, ConfigHistory},
+    configuration::{prepare_config, write_file_atomically, Config},
+    secrets::{secrets_dir, Secret},
+};
+
+/// The configuration currently served, replaced after each successful write
+pub type LiveConfig = Arc<RwLock<(ConfigState, ConfigMap)>>;
+
+/// Single entry point for the modifications of the configuration file.
+///
+/// Modifications are serialized : a transaction holds the writer until it is committed or dropped,
+/// so that concurrent read-modify-write cycles cannot lose each other's changes.
+#[derive(Clone)]
+pub struct ConfigWriter {
+    file: ConfigFile,
+    lock: Arc<Mutex<()>>,
+    live: LiveConfig,
+    history: ConfigHistory,
+}
+
+/// Error of a transaction, with the problems making the configuration invalid if any
+#[derive(Debug)]
+pub struct ConfigError(pub StatusCode, pub &'static str, pub Vec<String>);
+
+impl From<(StatusCode, &'static str)> for ConfigError {
+    fn from((status, msg): (StatusCode, &'static str)) -> Self {
+        ConfigError(status, msg, Vec::new())
+    }
+}
+
+/// Drops the problems, for the callers which must not disclose the configuration
+impl From<ConfigError> for (StatusCode, &'static str) {
+    fn from(e: ConfigError) -> Self {
+        (e.0, e.1)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.2.is_empty() {
+            write!(f, "{}", self.1)
+        } else {
+            write!(f, "{}: {}", self.1, self.2.join("; "))
+        }
+    }
+}
+
+impl IntoResponse for ConfigError {
+    fn into_response(self) -> Response {
+        (self.0, self.to_string()).into_response()
+    }
+}
+
+pub struct ConfigTransaction {
+    pub config: Config,
+    previous: String,
+    writer: ConfigWriter,
+    /// Files of the secrets persisted for the transaction, removed if it is not committed
+    secret_files: Vec<PathBuf>,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl ConfigWriter {
+    pub fn new(file: ConfigFile, live: LiveConfig) -> Self {
+        ConfigWriter {
+            history: ConfigHistory::new(history_dir(&file)),
+            file,
+            lock: Arc::new(Mutex::new(())),
+            live,
+        }
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    /// The configuration currently served, with its environment overrides and resolved secrets
+    pub fn current(&self) -> ConfigState {
+        Arc::clone(
+            &self
+                .live
+                .read()
+                .expect("live configuration lock is poisoned")
+                .0,
+        )
+    }
+
+    pub fn history(&self) -> &ConfigHistory {
+        &self.history
+    }
+
+    /// Reads the configuration file, along with the entity tag of its current content
+    pub async fn read(&self) -> Result<(Config, TypedHeader<ETag>), (StatusCode, &'static str)> {
+        let (config, etag, _) = self.read_with_contents().await?;
+        Ok((config, etag))
+    }
+
+    async fn read_with_contents(
+        &self,
+    ) -> Result<(Config, TypedHeader<ETag>, String), (StatusCode, &'static str)> {
+        let data = tokio::fs::read_to_string(self.file.as_str())
+            .await
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not read config file",
+                )
+            })?;
+        let config = serde_yaml::from_str::<Config>(&data).map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not parse config file",
+            )
+        })?;
+        let etag = etag(&data);
+        Ok((config, etag, data))
+    }
+
+    /// Starts a modification of the configuration file, failing if it does not match the `If-Match` precondition
+    pub async fn transaction(
+        &self,
+        if_match: Option<IfMatch>,
+    ) -> Result<ConfigTransaction, (StatusCode, &'static str)> {
+        let guard = self.lock.clone().lock_owned().await;
+        let (config, TypedHeader(etag), previous) = self.read_with_contents().await?;
+        if let Some(if_match) = if_match {
+            if !if_match.precondition_passes(&etag) {
+                return Err((
+                    StatusCode::PRECONDITION_FAILED,
+                    "configuration was modified in the meantime",
+                ));
+            }
+        }
+        Ok(ConfigTransaction {
+            config,
+            previous,
+            writer: self.clone(),
+            secret_files: Vec::new(),
+            _guard: guard,
+        })
+    }
+}
+
+impl ConfigTransaction {
+    /// Moves a plain secret out of the configuration, into a file of the secrets directory named after `name`
+    pub async fn persist_secret(
+        &mut self,
+        secret: &mut Secret,
+        name: &str,
+    ) -> Result<(), (StatusCode, &'static str)> {
+        let file = secret
+            .persist(&secrets_dir(&self.writer.file), name)
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "could not store secret"))?;
+        self.secret_files.extend(file);
+        Ok(())
+    }
+
+    /// Validates and saves the modified configuration, keeping a version of it in the history, then starts serving it
+    pub async fn commit(mut self, author: &str) -> Result<TypedHeader<ETag>, ConfigError> {
+        self.config
+            .apps
+            .sort_by(|a, b| a.id.partial_cmp(&b.id).unwrap());
+        let problems = self.config.check();
+        if !problems.is_empty() {
+            return Err(ConfigError(
+                StatusCode::BAD_REQUEST,
+                "configuration is invalid",
+                problems,
+            ));
+        }
+        let live = prepare_config(self.config.clone()).map_err(|e| {
+            ConfigError(
+                StatusCode::BAD_REQUEST,
+                "configuration is invalid",
+                vec![e.to_string()],
+            )
+        })?;
+        let contents = self.config.to_yaml().map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not save configuration",
+            )
+        })?;
+        // The command line writes the file from another process, which the lock does not cover
+        if self.writer.read_with_contents().await?.2 != self.previous {
+            return Err(ConfigError::from((
+                StatusCode::PRECONDITION_FAILED,
+                "configuration was modified in the meantime",
+            )));
+        }
+        write_file_atomically(&self.writer.file, contents.as_bytes())
+            .await
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not save configuration",
+                )
+            })?;
+        *self
+            .writer
+            .live
+            .write()
+            .expect("live configuration lock is poisoned") = live;
+        // The secrets are now referenced by the configuration
+        self.secret_files.clear();
+        // The change is saved and served, failing now would only make the caller retry it
+        if let Err(e) = self
+            .writer
+            .history
+            .record(&self.previous, &contents, author)
+            .await
+        {
+            eprintln!("configuration saved, but could not record it in history: {e}");
+        }
+        Ok(etag(&contents))
+    }
+}
+
+impl Drop for ConfigTransaction {
+    fn drop(&mut self) {
+        for file in &self.secret_files {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+fn etag(contents: &str) -> TypedHeader<ETag> {
+    let hash = Sha256::digest(contents.as_bytes());
+    TypedHeader(
+        format!("\"{hash:x}\"")
+            .parse()
+            .expect("a sha256 hash is a valid entity tag"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        sync::{Arc, RwLock},
+    };
+
+    use axum::TypedHeader;
+    use headers::IfMatch;
+    use http::StatusCode;
+
+    use crate::{
+        apps::App,
+        config_history::history_dir,
+        config_writer::ConfigWriter,
+        configuration::{prepare_config, Config},
+        secrets::Secret,
+    };
+
+    /// Writer of a configuration file in its own directory, so that the history and secrets of the tests are apart
+    async fn writer(name: &str) -> ConfigWriter {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("atrium.yaml");
+        let config = Config {
+            hostname: "atrium.io".to_owned(),
+            ..Default::default()
+        };
+        config.to_file(file.to_str().unwrap()).await.unwrap();
+        let live = prepare_config(config).unwrap();
+        ConfigWriter::new(
+            Arc::new(file.to_str().unwrap().to_owned()),
+            Arc::new(RwLock::new(live)),
+        )
+    }
+
+    fn app(id: usize) -> App {
+        App {
+            id,
+            host: format!("app{id}"),
+            target: format!("localhost:808{id}"),
+            is_proxy: true,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_transactions_are_serialized() {
+        let writer = writer("atrium_config_writer_test_concurrent").await;
+        let tasks = (1..=5).map(|id| {
+            let writer = writer.clone();
+            tokio::spawn(async move {
+                let mut transaction = writer.transaction(None).await.unwrap();
+                transaction.config.apps.push(app(id));
+                assert!(transaction.commit("admin").await.is_ok());
+            })
+        });
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let (config, _) = writer.read().await.unwrap();
+        assert_eq!(config.apps.len(), 5);
+        // The committed configuration is served
+        assert_eq!(writer.live.read().unwrap().1.len(), 5);
+        std::fs::remove_dir_all(Path::new(writer.file()).parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_if_match() {
+        let writer = writer("atrium_config_writer_test_if_match").await;
+        let (_, TypedHeader(etag)) = writer.read().await.unwrap();
+        let mut transaction = writer
+            .transaction(Some(IfMatch::from(etag.clone())))
+            .await
+            .unwrap();
+        transaction.config.apps.push(app(1));
+        let TypedHeader(new_etag) = transaction.commit("admin").await.unwrap();
+        assert_ne!(etag, new_etag);
+        // The old entity tag does not match anymore
+        let res = writer.transaction(Some(IfMatch::from(etag))).await;
+        assert_eq!(res.err().unwrap().0, StatusCode::PRECONDITION_FAILED);
+        std::fs::remove_dir_all(Path::new(writer.file()).parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_external_modification_is_not_overwritten() {
+        let writer = writer("atrium_config_writer_test_external").await;
+        let mut transaction = writer.transaction(None).await.unwrap();
+        transaction.config.apps.push(app(1));
+        // Another process writes the file while the transaction is open
+        let (mut config, _) = writer.read().await.unwrap();
+        config.apps.push(app(2));
+        config.to_file(writer.file()).await.unwrap();
+        assert_eq!(
+            transaction.commit("admin").await.err().unwrap().0,
+            StatusCode::PRECONDITION_FAILED
+        );
+        let (config, _) = writer.read().await.unwrap();
+        assert_eq!(config.apps, vec![app(2)]);
+        std::fs::remove_dir_all(Path::new(writer.file()).parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_secrets_of_failed_transactions_are_removed() {
+        let writer = writer("atrium_config_writer_test_secrets").await;
+        let mut transaction = writer.transaction(None).await.unwrap();
+        let mut secret = Secret::new("secret".to_owned());
+        transaction
+            .persist_secret(&mut secret, "test_secret")
+            .await
+            .unwrap();
+        let file = secret.reference().unwrap().strip_prefix("file:").unwrap();
+        assert!(Path::new(file).exists());
+        // The transaction is refused, the secret is not kept
+        transaction.config.apps.push(app(1));
+        transaction.config.apps.push(app(1));
+        assert!(transaction.commit("admin").await.is_err());
+        assert!(!Path::new(file).exists());
+
+        let mut transaction = writer.transaction(None).await.unwrap();
+        let mut secret = Secret::new("secret".to_owned());
+        transaction
+            .persist_secret(&mut secret, "test_secret")
+            .await
+            .unwrap();
+        let mut app = app(1);
+        app.password = secret.clone();
+        transaction.config.apps.push(app);
+        assert!(transaction.commit("admin").await.is_ok());
+        let file = secret.reference().unwrap().strip_prefix("file:").unwrap();
+        assert!(Path::new(file).exists());
+        std::fs::remove_dir_all(Path::new(writer.file()).parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_configuration_is_not_written() {
+        let writer = writer("atrium_config_writer_test_invalid").await;
+        let mut transaction = writer.transaction(None).await.unwrap();
+        transaction.config.apps.push(app(1));
+        transaction.config.apps.push(app(1));
+        let e = transaction.commit("admin").await.err().unwrap();
+        assert_eq!(e.0, StatusCode::BAD_REQUEST);
+        // The problems are told, not only that the configuration is invalid
+        assert!(!e.2.is_empty());
+        assert!(e.to_string().starts_with("configuration is invalid: "));
+        let (config, _) = writer.read().await.unwrap();
+        assert!(config.apps.is_empty());
+        std::fs::remove_dir_all(Path::new(writer.file()).parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_failure_does_not_fail_commit() {
+        let writer = writer("atrium_config_writer_test_history_failure").await;
+        // The history directory cannot be created
+        std::fs::write(history_dir(writer.file()), "").unwrap();
+        let mut transaction = writer.transaction(None).await.unwrap();
+        transaction.config.apps.push(app(1));
+        let TypedHeader(etag) = transaction.commit("admin").await.unwrap();
+        let (config, TypedHeader(current)) = writer.read().await.unwrap();
+        assert_eq!(config.apps, vec![app(1)]);
+        assert_eq!(etag, current);
+        std::fs::remove_dir_all(Path::new(writer.file()).parent().unwrap()).unwrap();
+    }
+}
@@ -1,35 +1,54 @@
 use crate::{
     apps::{App, AppWithUri},
     appstate::{ConfigMap, ConfigState},
-    users::User,
-    utils::{is_default, option_string_trim, string_trim},
+    client_ip::ForwardedHeader,
+    compression::Compression,
+    cors::Cors,
+    ldap::LdapConfig,
+    policy::{parse_cidr, IpFilter},
+    secrets::{option_secret, secrets_dir, Secret},
+    security_headers::{SecurityHeaders, DEFAULT_PROFILE},
+    users::{User, REDACTED},
+    utils::{is_default, string_trim, vec_trim_remove_empties},
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use axum::{
     async_trait,
     extract::{FromRef, FromRequestParts},
 };
 use http::request::Parts;
-use hyper::StatusCode;
+use hyper::{StatusCode, Uri};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+use tokio::io::AsyncWriteExt;
 
 fn hostname() -> String {
     "atrium.io".to_owned()
 }
 
+fn http_port() -> u16 {
+    8080
+}
+
+/// Prefix of the environment variables overriding configuration fields
+pub const ENV_PREFIX: &str = "ATRIUM_";
+
 #[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct OnlyOfficeConfig {
     #[serde(default, skip_serializing_if = "is_default")]
     pub title: Option<String>,
     pub server: String,
-    pub jwt_secret: String,
+    pub jwt_secret: Secret,
 }
 
 #[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct OpenIdConfig {
     pub client_id: String,
-    pub client_secret: String,
+    pub client_secret: Secret,
     pub auth_url: String,
     pub token_url: String,
     pub userinfo_url: String,
@@ -61,6 +80,10 @@ pub struct Config {
     pub hostname: String,
     #[serde(default, skip_serializing_if = "is_default")]
     pub domain: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub debug_mode: bool,
+    #[serde(default = "http_port")]
+    pub http_port: u16,
     #[serde(default)]
     pub tls_mode: TlsMode,
     #[serde(
@@ -72,14 +95,50 @@ pub struct Config {
     #[serde(
         default,
         skip_serializing_if = "is_default",
-        deserialize_with = "option_string_trim"
+        deserialize_with = "option_secret"
     )]
-    pub cookie_key: Option<String>,
+    pub cookie_key: Option<Secret>,
     #[serde(default, skip_serializing_if = "is_default")]
     pub log_to_file: bool,
     #[serde(default, skip_serializing_if = "is_default")]
     pub session_duration_days: Option<i64>,
     #[serde(default, skip_serializing_if = "is_default")]
+    pub totp_required_for_admins: bool,
+    /// Proxies whose forwarding header is trusted when behind a proxy, loopback addresses if empty
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub trusted_proxies: Vec<String>,
+    /// Header where the trusted proxies write the address of the client, the other one being ignored
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub forwarded_header: ForwardedHeader,
+    /// The trusted proxies send a PROXY protocol (v1 or v2) header at the start of their connections
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub proxy_protocol: bool,
+    /// Clients allowed to reach atrium and its apps
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub ip_filter: IpFilter,
+    /// Clients allowed to reach the administration API, in addition to the global filter
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub admin_ip_filter: IpFilter,
+    /// Cross-origin requests allowed to atrium itself
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub cors: Cors,
+    /// Security header profiles referenced by the apps, the default one applying to atrium itself
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub security_headers: BTreeMap<String, SecurityHeaders>,
+    /// Compression of the responses of atrium itself
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub compression: Compression,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub onlyoffice_config: Option<OnlyOfficeConfig>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub openid_config: Option<OpenIdConfig>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub ldap_config: Option<LdapConfig>,
+    #[serde(default, skip_serializing_if = "is_default")]
     pub apps: Vec<App>,
 
     #[serde(default, skip_serializing_if = "is_default")]
@@ -88,31 +147,164 @@ pub struct Config {
 
 impl Config {
     pub async fn from_file(filepath: &str) -> Result<Self> {
-        let data = tokio::fs::read_to_string(filepath).await.unwrap();
-        let config = serde_yaml::from_str::<Config>(&data).unwrap();
+        let data = tokio::fs::read_to_string(filepath)
+            .await
+            .map_err(|e| anyhow!("could not read {filepath}: {e}"))?;
+        let config = serde_yaml::from_str::<Config>(&data)
+            .map_err(|e| anyhow!("could not parse {filepath}: {e}"))?;
         Ok(config)
     }
 
     pub async fn to_file(&self, filepath: &str) -> Result<()> {
-        let contents = serde_yaml::to_string::<Config>(self).unwrap();
-        tokio::fs::write(filepath, contents).await.unwrap();
-        Ok(())
+        write_file_atomically(filepath, self.to_yaml()?.as_bytes()).await
+    }
+
+    pub fn to_yaml(&self) -> Result<String> {
+        serde_yaml::to_string::<Config>(self)
+            .map_err(|e| anyhow!("could not serialize configuration: {e}"))
+    }
+
+    /// Replaces the secrets, so that the configuration can be shown to the administrators
+    pub fn redacted(mut self) -> Self {
+        self.apps = self.apps.into_iter().map(App::redacted).collect();
+        self.users = self.users.into_iter().map(User::redacted).collect();
+        let secrets = self
+            .cookie_key
+            .iter_mut()
+            .chain(self.onlyoffice_config.iter_mut().map(|c| &mut c.jwt_secret))
+            .chain(self.openid_config.iter_mut().map(|c| &mut c.client_secret))
+            .chain(
+                self.ldap_config
+                    .iter_mut()
+                    .filter_map(|c| c.bind_password.as_mut()),
+            );
+        for secret in secrets {
+            // References only tell where the secret is
+            if secret.reference().is_none() && !secret.is_empty() {
+                *secret = Secret::new(REDACTED.to_owned());
+            }
+        }
+        self
     }
 
-    pub async fn to_file_or_internal_server_error(
-        mut self,
-        filepath: &str,
-    ) -> Result<(), (StatusCode, &'static str)> {
-        self.apps.sort_by(|a, b| a.id.partial_cmp(&b.id).unwrap());
-        self.to_file(filepath).await.map_err(|_| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "could not save configuration",
+    /// Reads the values of the secrets given as references (`env:NAME` or `file:/path`)
+    pub fn resolve_secrets(&mut self) -> Result<()> {
+        let secrets = self
+            .cookie_key
+            .iter_mut()
+            .chain(self.onlyoffice_config.iter_mut().map(|c| &mut c.jwt_secret))
+            .chain(self.openid_config.iter_mut().map(|c| &mut c.client_secret))
+            .chain(
+                self.ldap_config
+                    .iter_mut()
+                    .filter_map(|c| c.bind_password.as_mut()),
             )
-        })?;
+            .chain(
+                self.apps
+                    .iter_mut()
+                    .flat_map(|a| [&mut a.password, &mut a.identity_jwt_secret]),
+            )
+            .chain(
+                self.users
+                    .iter_mut()
+                    .filter_map(|u| u.totp.as_mut().map(|t| &mut t.secret)),
+            );
+        for secret in secrets {
+            secret.resolve()?;
+        }
         Ok(())
     }
 
+    /// Lists the problems that would prevent this configuration from being served
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.tls_mode == TlsMode::Auto && self.letsencrypt_email.is_empty() {
+            problems.push("letsencrypt_email is required when tls_mode is Auto".to_owned());
+        }
+        if let Some(key) = &self.cookie_key {
+            // A referenced key is checked on the value it resolves to
+            let mut key = key.clone();
+            match key.resolve() {
+                Err(e) => problems.push(format!("cookie_key is invalid: {e}")),
+                Ok(()) if key.expose().len() < 64 => {
+                    problems.push("cookie_key must be at least 64 characters long".to_owned())
+                }
+                Ok(()) => (),
+            }
+        }
+        for proxy in self.trusted_proxies.iter() {
+            if parse_cidr(proxy).is_none() {
+                problems.push(format!(
+                    "trusted proxy {proxy} is not an IP address or CIDR range"
+                ));
+            }
+        }
+        for problem in self.ip_filter.check() {
+            problems.push(format!("ip_filter is invalid: {problem}"));
+        }
+        for problem in self.admin_ip_filter.check() {
+            problems.push(format!("admin_ip_filter is invalid: {problem}"));
+        }
+        for problem in self.cors.check() {
+            problems.push(format!("cors is invalid: {problem}"));
+        }
+        for (i, app) in self.apps.iter().enumerate() {
+            if self.apps[..i].iter().any(|a| a.id == app.id) {
+                problems.push(format!("app id {} is used more than once", app.id));
+            }
+            if self.apps[..i].iter().any(|a| a.host == app.host) {
+                problems.push(format!("app host {} is used more than once", app.host));
+            }
+            for problem in app.ip_filter.check() {
+                problems.push(format!(
+                    "app {} has an invalid ip_filter: {problem}",
+                    app.id
+                ));
+            }
+            if !app.security_headers.is_empty()
+                && app.security_headers != DEFAULT_PROFILE
+                && !self.security_headers.contains_key(&app.security_headers)
+            {
+                problems.push(format!(
+                    "app {} references an unknown security headers profile: {}",
+                    app.id, app.security_headers
+                ));
+            }
+            for problem in app.cors.iter().flat_map(|c| c.check()) {
+                problems.push(format!("app {} has an invalid cors: {problem}", app.id));
+            }
+            for problem in app.policy.iter().flat_map(|r| r.check()) {
+                problems.push(format!("app {} has an invalid policy: {problem}", app.id));
+            }
+            if app.is_proxy
+                && !matches!(app.target.parse::<Uri>(), Ok(uri) if uri.authority().is_some())
+            {
+                problems.push(format!(
+                    "app {} has an invalid proxy target: {}",
+                    app.id, app.target
+                ));
+            }
+        }
+        for (i, user) in self.users.iter().enumerate() {
+            if user.login.is_empty() {
+                problems.push("a user has an empty login".to_owned());
+            }
+            if self.users[..i].iter().any(|u| u.login == user.login) {
+                problems.push(format!("user login {} is used more than once", user.login));
+            }
+            for passkey in user.passkeys.iter() {
+                if self.users[..i]
+                    .iter()
+                    .flat_map(|u| u.passkeys.iter())
+                    .any(|p| p.id == passkey.id)
+                {
+                    problems.push(format!("passkey {} is used more than once", passkey.id));
+                }
+            }
+        }
+        problems
+    }
+
     pub fn scheme(&self) -> &str {
         if self.tls_mode.is_secure() {
             "https"
@@ -121,13 +313,34 @@ impl Config {
         }
     }
 
+    /// Finds the security header profile, an empty name standing for the default profile
+    pub fn security_headers(&self, name: &str) -> Cow<'_, SecurityHeaders> {
+        let name = if name.is_empty() {
+            DEFAULT_PROFILE
+        } else {
+            name
+        };
+        match self.security_headers.get(name) {
+            Some(profile) => Cow::Borrowed(profile),
+            None => Cow::Owned(SecurityHeaders::default()),
+        }
+    }
+
     pub fn full_domain(&self) -> String {
+        self.url_of(&self.domain)
+    }
+
+    /// URL of atrium itself, where its API is served
+    pub fn full_hostname(&self) -> String {
+        self.url_of(&self.hostname)
+    }
+
+    fn url_of(&self, host: &str) -> String {
         format!(
-            "{s}://{h}{p}",
+            "{s}://{host}{p}",
             s = self.scheme(),
-            h = self.domain,
             p = &(if self.tls_mode == TlsMode::No {
-                format!(":{}", 8080)
+                format!(":{}", self.http_port)
             } else {
                 "".to_owned()
             })
@@ -155,23 +368,51 @@ impl Config {
 }
 
 pub async fn load_config(config_file: &str) -> Result<(ConfigState, ConfigMap), anyhow::Error> {
-    let mut config = Config::from_file(config_file).await?;
-    // if the cookie encryption key is not present, generate it and store it
-    if config.cookie_key.is_none() {
-        config.cookie_key = Some(crate::utils::random_string(64));
-        config.to_file(config_file).await?;
+    let mut file_config = Config::from_file(config_file).await?;
+    let mut changed = false;
+    // if the cookie encryption key is not present, generate it and store it outside of the configuration file
+    if apply_env_overrides(file_config.clone(), std::env::vars())?
+        .cookie_key
+        .is_none()
+    {
+        let mut key = Secret::new(crate::utils::random_string(64));
+        key.persist(&secrets_dir(config_file), "cookie_key").await?;
+        file_config.cookie_key = Some(key);
+        changed = true;
     }
-    // Allow overriding the hostname with env variable
-    if let Ok(h) = std::env::var("MAIN_HOSTNAME") {
-        config.hostname = h
+    // App secrets written by hand are moved out of the configuration file too, so that they never reach its history
+    for app in file_config.apps.iter_mut() {
+        let id = app.id;
+        for (secret, name) in [
+            (&mut app.password, "password"),
+            (&mut app.identity_jwt_secret, "identity_jwt_secret"),
+        ] {
+            if secret.reference().is_none() && !secret.is_empty() {
+                secret
+                    .persist(&secrets_dir(config_file), &format!("app_{id}_{name}"))
+                    .await?;
+                changed = true;
+            }
+        }
+    }
+    if changed {
+        file_config.to_file(config_file).await?;
     }
+    prepare_config(file_config)
+}
+
+/// Works out the configuration to serve from the configuration read from the file
+pub fn prepare_config(file_config: Config) -> Result<(ConfigState, ConfigMap), anyhow::Error> {
+    // Overrides from the environment are applied in memory only, they are never written back to the file
+    let mut config = apply_env_overrides(file_config, std::env::vars())?;
+    config.resolve_secrets()?;
     if is_default(&config.domain) {
         config.domain = config.hostname.clone()
     };
     let port = if config.tls_mode.is_secure() {
         None
     } else {
-        Some(8080)
+        Some(config.http_port)
     };
     let mut hashmap: HashMap<String, HostType> =
         filter_services(&config.apps, &config.hostname, &config.domain)
@@ -194,6 +435,203 @@ pub async fn load_config(config_file: &str) -> Result<(ConfigState, ConfigMap),
     Ok((Arc::new(config), Arc::new(hashmap)))
 }
 
+/// Writes to a temporary file that replaces the target once synced, so that a crash never leaves a truncated file
+pub(crate) async fn write_file_atomically(filepath: &str, contents: &[u8]) -> Result<()> {
+    let path = std::path::Path::new(filepath);
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name()
+            .ok_or_else(|| anyhow!("{filepath} is not a file path"))?
+            .to_string_lossy(),
+        crate::utils::random_string(8)
+    ));
+    let write = async {
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, path).await
+    };
+    if let Err(e) = write.await {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+        return Err(anyhow!("could not write {filepath}: {e}"));
+    }
+    // Sync the directory so that the rename itself is durable
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            std::path::Path::new(".")
+        } else {
+            dir
+        };
+        if let Ok(dir) = tokio::fs::File::open(dir).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+    Ok(())
+}
+
+/// Overrides configuration fields with `ATRIUM_` prefixed variables.
+///
+/// The variable name is the upper cased field path, nested fields and list indexes being separated by `__`
+/// (for example `ATRIUM_TLS_MODE`, `ATRIUM_OPENID_CONFIG__CLIENT_SECRET` or `ATRIUM_APPS__0__PASSWORD`).
+/// Values are parsed as YAML, falling back to plain strings.
+/// If the name ends with `_FILE` and is not a field itself, the value is the path of a file whose content is used for the field
+/// without the suffix, so that secrets can be mounted Docker secrets style (for example `ATRIUM_COOKIE_KEY_FILE`).
+/// Names that do not match a field are rejected, so that typos do not go unnoticed.
+/// `MAIN_HOSTNAME` is still honoured as an alias of `ATRIUM_HOSTNAME`.
+pub fn apply_env_overrides(
+    config: Config,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<Config> {
+    let mut vars: Vec<(String, String)> = vars
+        .filter_map(|(k, v)| {
+            if k == "MAIN_HOSTNAME" {
+                Some(("HOSTNAME".to_owned(), v))
+            } else {
+                k.strip_prefix(ENV_PREFIX).map(|k| (k.to_owned(), v))
+            }
+        })
+        .collect();
+    if vars.is_empty() {
+        return Ok(config);
+    }
+    // Apply the variables in a stable order
+    vars.sort();
+    let mut value = serde_yaml::to_value(&config)
+        .map_err(|e| anyhow!("could not serialize configuration: {e}"))?;
+    let original = value.clone();
+    let mut overrides = Vec::with_capacity(vars.len());
+    for (key, raw) in vars {
+        let (key, raw) = match key.strip_suffix("_FILE") {
+            Some(stripped) if !is_field(&original, &key) => {
+                let content = std::fs::read_to_string(&raw)
+                    .map_err(|e| anyhow!("could not read {raw} for {ENV_PREFIX}{key}: {e}"))?;
+                (
+                    stripped.to_owned(),
+                    content.trim_end_matches(['\r', '\n']).to_owned(),
+                )
+            }
+            _ => (key, raw),
+        };
+        if !is_field(&original, &key) {
+            return Err(anyhow!(
+                "{ENV_PREFIX}{key} does not match a configuration field"
+            ));
+        }
+        let path = env_key_to_path(&key);
+        // Values are parsed as YAML (numbers, booleans, lists...) first
+        let parsed = match serde_yaml::from_str::<serde_yaml::Value>(&raw) {
+            Ok(v) if !v.is_null() => v,
+            _ => serde_yaml::Value::String(raw.clone()),
+        };
+        if !set_value_at_path(&mut value, &path, parsed) {
+            return Err(anyhow!(
+                "{ENV_PREFIX}{key} does not match a configuration field"
+            ));
+        }
+        overrides.push((key, raw));
+    }
+    // then fall back to plain strings for the fields that do not accept the parsed value
+    loop {
+        match serde_path_to_error::deserialize::<_, Config>(value.clone()) {
+            Ok(config) => return Ok(config),
+            Err(e) => {
+                let error_path = e.path().to_string();
+                let Some((key, raw)) = overrides
+                    .iter()
+                    .find(|(key, _)| display_path(key) == error_path)
+                else {
+                    return Err(anyhow!(
+                        "could not apply environment overrides: {error_path}: {}",
+                        e.inner()
+                    ));
+                };
+                let path = env_key_to_path(key);
+                if value_at_path(&value, &path).is_some_and(|v| v.is_string()) {
+                    return Err(anyhow!(
+                        "invalid value for {ENV_PREFIX}{key}: {}",
+                        e.inner()
+                    ));
+                }
+                set_value_at_path(&mut value, &path, serde_yaml::Value::String(raw.clone()));
+            }
+        }
+    }
+}
+
+/// Tells if the variable name designates a field of the configuration, fields that do not exist being ignored by serde.
+///
+/// A value that no field accepts is put at the path, which fails the deserialization at that path only if the field exists.
+fn is_field(config: &serde_yaml::Value, key: &str) -> bool {
+    let mut probe = config.clone();
+    let invalid = serde_yaml::Value::Tagged(Box::new(serde_yaml::value::TaggedValue {
+        tag: serde_yaml::value::Tag::new("atrium_probe"),
+        value: serde_yaml::Value::Null,
+    }));
+    if !set_value_at_path(&mut probe, &env_key_to_path(key), invalid) {
+        return false;
+    }
+    serde_path_to_error::deserialize::<_, Config>(probe)
+        .is_err_and(|e| e.path().to_string() == display_path(key))
+}
+
+fn env_key_to_path(key: &str) -> Vec<String> {
+    key.split("__").map(|s| s.to_lowercase()).collect()
+}
+
+fn display_path(key: &str) -> String {
+    let mut display = String::new();
+    for segment in env_key_to_path(key) {
+        if segment.parse::<usize>().is_ok() {
+            display.push_str(&format!("[{segment}]"));
+        } else {
+            if !display.is_empty() {
+                display.push('.');
+            }
+            display.push_str(&segment);
+        }
+    }
+    display
+}
+
+fn value_at_path<'a>(
+    value: &'a serde_yaml::Value,
+    path: &[String],
+) -> Option<&'a serde_yaml::Value> {
+    path.iter().try_fold(value, |v, segment| match v {
+        serde_yaml::Value::Sequence(seq) => seq.get(segment.parse::<usize>().ok()?),
+        _ => v.get(segment.as_str()),
+    })
+}
+
+fn set_value_at_path(
+    target: &mut serde_yaml::Value,
+    path: &[String],
+    value: serde_yaml::Value,
+) -> bool {
+    let Some((segment, rest)) = path.split_first() else {
+        *target = value;
+        return true;
+    };
+    if segment.is_empty() {
+        return false;
+    }
+    if target.is_null() {
+        *target = serde_yaml::Value::Mapping(Default::default());
+    }
+    let child = match target {
+        serde_yaml::Value::Sequence(seq) => match segment.parse::<usize>() {
+            Ok(i) if i < seq.len() => &mut seq[i],
+            _ => return false,
+        },
+        serde_yaml::Value::Mapping(map) => map
+            .entry(serde_yaml::Value::String(segment.to_owned()))
+            .or_insert(serde_yaml::Value::Null),
+        _ => return false,
+    };
+    set_value_at_path(child, rest, value)
+}
+
 pub(crate) fn trim_host(host: &str) -> String {
     host.split_once('.').unwrap_or((host, "")).0.to_owned()
 }
@@ -206,20 +644,10 @@ fn app_to_host_type(app: &App, config: &Config, port: Option<u16>) -> HostType {
             port,
         )))
     } else {
-        HostType::StaticApp(app.clone())
+        HostType::StaticApp(Box::new(app.clone()))
     }
 }
 
-pub async fn config_or_error(config_file: &str) -> Result<Config, (StatusCode, &'static str)> {
-    let config = Config::from_file(config_file).await.map_err(|_| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "could not read config file",
-        )
-    })?;
-    Ok(config)
-}
-
 pub trait Service {
     fn host(&self) -> &str;
 }
@@ -248,7 +676,7 @@ fn filter_services<'a, T: Service + 'a>(
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum HostType {
-    StaticApp(App),
+    StaticApp(Box<App>),
     ReverseApp(Box<AppWithUri>),
 }
 
@@ -260,6 +688,20 @@ impl HostType {
         }
     }
 
+    pub fn id(&self) -> usize {
+        match self {
+            HostType::ReverseApp(app) => app.inner.id,
+            HostType::StaticApp(app) => app.id,
+        }
+    }
+
+    pub fn app(&self) -> &App {
+        match self {
+            HostType::ReverseApp(app) => &app.inner,
+            HostType::StaticApp(app) => app,
+        }
+    }
+
     pub fn roles(&self) -> &Vec<String> {
         match self {
             HostType::ReverseApp(app) => &app.inner.roles,
@@ -311,3 +753,257 @@ where
         Ok(target)
     }
 }
+
+#[cfg(test)]
+mod check_tests {
+    use crate::{apps::App, configuration::Config, users::User};
+
+    #[test]
+    fn test_check_ok() {
+        let config = Config {
+            apps: vec![App {
+                id: 1,
+                host: "app1".to_owned(),
+                target: "localhost:8081".to_owned(),
+                is_proxy: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(config.check().is_empty());
+    }
+
+    #[test]
+    fn test_check_duplicates_and_invalid_target() {
+        let app = App {
+            id: 1,
+            host: "app1".to_owned(),
+            target: "/not/an/authority".to_owned(),
+            is_proxy: true,
+            ..Default::default()
+        };
+        let user = User {
+            login: "admin".to_owned(),
+            ..Default::default()
+        };
+        let config = Config {
+            apps: vec![app.clone(), app],
+            users: vec![user.clone(), user],
+            ..Default::default()
+        };
+        let problems = config.check();
+        assert_eq!(problems.len(), 5);
+        assert!(problems.contains(&"app id 1 is used more than once".to_owned()));
+        assert!(problems.contains(&"user login admin is used more than once".to_owned()));
+    }
+
+    #[test]
+    fn test_check_referenced_cookie_key() {
+        let config = |reference: &str| Config {
+            cookie_key: serde_yaml::from_str(&format!("cookie_key: {reference}"))
+                .map(|c: Config| c.cookie_key)
+                .unwrap(),
+            ..Default::default()
+        };
+        std::env::set_var("CHECK_TEST_SHORT_COOKIE_KEY", "short");
+        std::env::set_var("CHECK_TEST_LONG_COOKIE_KEY", "k".repeat(64));
+        assert_eq!(
+            config("env:CHECK_TEST_SHORT_COOKIE_KEY").check(),
+            vec!["cookie_key must be at least 64 characters long"]
+        );
+        assert!(config("env:CHECK_TEST_LONG_COOKIE_KEY").check().is_empty());
+        assert_eq!(
+            config("env:CHECK_TEST_UNSET_COOKIE_KEY").check(),
+            vec!["cookie_key is invalid: environment variable CHECK_TEST_UNSET_COOKIE_KEY is not set"]
+        );
+    }
+}
+
+#[cfg(test)]
+mod load_config_tests {
+    use crate::{
+        apps::App,
+        configuration::{load_config, Config, OpenIdConfig},
+        secrets::Secret,
+        users::REDACTED,
+    };
+
+    #[tokio::test]
+    async fn test_inline_app_secrets_are_moved_out() {
+        let dir = std::env::temp_dir().join("atrium_load_config_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("atrium.yaml");
+        let file = file.to_str().unwrap();
+        Config {
+            hostname: "atrium.io".to_owned(),
+            apps: vec![App {
+                id: 1,
+                host: "app1".to_owned(),
+                target: "localhost:8081".to_owned(),
+                is_proxy: true,
+                password: Secret::new("inline".to_owned()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+        .to_file(file)
+        .await
+        .unwrap();
+
+        let (config, _) = load_config(file).await.unwrap();
+        assert_eq!(config.apps[0].password.expose(), "inline");
+        let contents = std::fs::read_to_string(file).unwrap();
+        assert!(!contents.contains("inline"));
+        let written = Config::from_file(file).await.unwrap();
+        assert!(written.apps[0].password.reference().is_some());
+        assert!(written.cookie_key.as_ref().unwrap().reference().is_some());
+        // References are shown, plain values are redacted
+        let redacted = Config {
+            openid_config: Some(OpenIdConfig {
+                client_secret: Secret::new("plain".to_owned()),
+                ..Default::default()
+            }),
+            ..written
+        }
+        .redacted();
+        assert_eq!(redacted.apps[0].password.expose(), REDACTED);
+        assert!(redacted.cookie_key.unwrap().reference().is_some());
+        assert_eq!(
+            redacted.openid_config.unwrap().client_secret.expose(),
+            REDACTED
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod env_overrides_tests {
+    use crate::{
+        apps::App,
+        configuration::{apply_env_overrides, Config, TlsMode},
+    };
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_scalar_overrides() {
+        let config = apply_env_overrides(
+            Config::default(),
+            vars(&[
+                ("ATRIUM_TLS_MODE", "BehindProxy"),
+                ("ATRIUM_HTTP_PORT", "9090"),
+                ("ATRIUM_SESSION_DURATION_DAYS", "7"),
+                ("ATRIUM_COOKIE_KEY", "1234"),
+                ("ATRIUM_DOMAIN", "atrium.io"),
+                ("MAIN_HOSTNAME", "main.atrium.io"),
+                ("OTHER_VARIABLE", "ignored"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.tls_mode, TlsMode::BehindProxy);
+        assert_eq!(config.http_port, 9090);
+        assert_eq!(config.session_duration_days, Some(7));
+        assert_eq!(config.cookie_key.unwrap().expose(), "1234");
+        assert_eq!(config.domain, "atrium.io");
+        assert_eq!(config.hostname, "main.atrium.io");
+    }
+
+    #[test]
+    fn test_nested_and_indexed_overrides() {
+        let config = Config {
+            apps: vec![App::default()],
+            ..Default::default()
+        };
+        let config = apply_env_overrides(
+            config,
+            vars(&[
+                ("ATRIUM_APPS__0__PASSWORD", "secret"),
+                ("ATRIUM_OPENID_CONFIG__CLIENT_ID", "id"),
+                ("ATRIUM_OPENID_CONFIG__CLIENT_SECRET", "secret"),
+                ("ATRIUM_OPENID_CONFIG__AUTH_URL", "http://idp/auth"),
+                ("ATRIUM_OPENID_CONFIG__TOKEN_URL", "http://idp/token"),
+                ("ATRIUM_OPENID_CONFIG__USERINFO_URL", "http://idp/userinfo"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.apps[0].password.expose(), "secret");
+        assert_eq!(
+            config.openid_config.unwrap().client_secret.expose(),
+            "secret"
+        );
+    }
+
+    #[test]
+    fn test_file_overrides() {
+        let path = std::env::temp_dir().join("atrium_env_overrides_test_cookie_key");
+        std::fs::write(&path, "key_from_file\n").unwrap();
+        let config = apply_env_overrides(
+            Config::default(),
+            vars(&[
+                ("ATRIUM_COOKIE_KEY_FILE", path.to_str().unwrap()),
+                ("ATRIUM_LOG_TO_FILE", "true"),
+            ]),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.cookie_key.unwrap().expose(), "key_from_file");
+        assert!(config.log_to_file);
+        assert!(apply_env_overrides(
+            Config::default(),
+            vars(&[("ATRIUM_COOKIE_KEY_FILE", "/does/not/exist")])
+        )
+        .is_err());
+        assert!(apply_env_overrides(
+            Config::default(),
+            vars(&[("ATRIUM_COOKIE_KEY_FILE", "relative_secret")])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let config = Config {
+            apps: vec![App::default()],
+            ..Default::default()
+        };
+        for name in [
+            "ATRIUM_HTTP_PROT",
+            "ATRIUM_APPS__0__PASSWROD",
+            "ATRIUM_APPS__1__PASSWORD",
+            "ATRIUM_OPENID_CONFIG__CLIENT_SECRT",
+            "ATRIUM_TLS_MODE__AUTO",
+        ] {
+            let err = apply_env_overrides(config.clone(), vars(&[(name, "value")])).unwrap_err();
+            assert!(
+                err.to_string()
+                    .ends_with("does not match a configuration field"),
+                "{name}: {err}"
+            );
+        }
+        // Entries of maps are free
+        let config = apply_env_overrides(
+            config,
+            vars(&[
+                ("ATRIUM_SECURITY_HEADERS__STRICT__REPORT_ONLY", "true"),
+                ("ATRIUM_DEBUG_MODE", "false"),
+            ]),
+        )
+        .unwrap();
+        assert!(config.security_headers["strict"].report_only);
+    }
+
+    #[test]
+    fn test_invalid_override() {
+        assert!(apply_env_overrides(
+            Config::default(),
+            vars(&[("ATRIUM_HTTP_PORT", "not_a_port")])
+        )
+        .is_err());
+    }
+}
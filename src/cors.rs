@@ -0,0 +1,201 @@
+use http::{
+    header::{
+        ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE, VARY,
+    },
+    HeaderMap, HeaderName, HeaderValue, Method,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    configuration::Config,
+    utils::{is_default, vec_trim_remove_empties},
+};
+
+const DEFAULT_METHODS: &str =
+    "POST, GET, OPTIONS, PUT, DELETE, PROPFIND, PROPPATCH, MKCOL, MOVE, COPY";
+const DEFAULT_HEADERS: &str = "Accept, Content-Type, Content-Length, Accept-Encoding, XSRF-TOKEN, Authorization, Depth, Destination, Overwrite, X-OC-Mtime";
+/// Lifetime of the preflight responses in the cache of the browsers, in seconds
+const MAX_AGE: &str = "600";
+
+/// Cross-origin requests allowed, atrium and its subdomains being always allowed
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Cors {
+    /// Other origins allowed, as `https://host:port`, `https://*.domain` allowing any subdomain
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub allowed_origins: Vec<String>,
+    /// Defaults to the methods of the REST and WebDAV APIs
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub allowed_methods: Vec<String>,
+    /// Defaults to the headers of the REST and WebDAV APIs
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub allowed_headers: Vec<String>,
+}
+
+impl Cors {
+    /// Tells if the origin is atrium, one of its subdomains or a configured origin
+    pub fn allows_origin(&self, origin: &str, config: &Config) -> bool {
+        let atrium = config.full_domain().replacen("://", "://*.", 1);
+        origin_matches(&atrium, origin)
+            || self
+                .allowed_origins
+                .iter()
+                .any(|o| origin_matches(o, origin))
+    }
+
+    /// Sets the headers allowing the origin
+    pub fn allow(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+
+    /// Sets the headers of a preflight response allowing the origin
+    pub fn allow_preflight(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        self.allow(headers, origin);
+        let list = |values: &[String], default: &'static str| match values {
+            [] => Some(HeaderValue::from_static(default)),
+            values => HeaderValue::from_str(&values.join(", ")).ok(),
+        };
+        if let Some(methods) = list(&self.allowed_methods, DEFAULT_METHODS) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Some(allowed) = list(&self.allowed_headers, DEFAULT_HEADERS) {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+        }
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(MAX_AGE));
+    }
+
+    /// Lists the problems of the configuration
+    pub fn check(&self) -> Vec<String> {
+        let origins = self
+            .allowed_origins
+            .iter()
+            .filter(|o| !is_origin(o))
+            .map(|o| format!("{o} is not an origin"));
+        let methods = self
+            .allowed_methods
+            .iter()
+            .filter(|m| Method::from_bytes(m.as_bytes()).is_err())
+            .map(|m| format!("{m} is not an HTTP method"));
+        let headers = self
+            .allowed_headers
+            .iter()
+            .filter(|h| HeaderName::from_bytes(h.as_bytes()).is_err())
+            .map(|h| format!("{h} is not an HTTP header name"));
+        origins.chain(methods).chain(headers).collect()
+    }
+}
+
+/// Matches the origin exactly, or as a subdomain of the pattern if its host starts with `*.`
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let (Some((scheme, host)), Some((origin_scheme, origin_host))) =
+        (pattern.split_once("://"), origin.split_once("://"))
+    else {
+        return false;
+    };
+    if !scheme.eq_ignore_ascii_case(origin_scheme) {
+        return false;
+    }
+    let origin_host = origin_host.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    match host.strip_prefix("*.") {
+        Some(domain) => {
+            origin_host == domain
+                || origin_host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.'))
+        }
+        None => origin_host == host,
+    }
+}
+
+fn is_origin(origin: &str) -> bool {
+    match origin.split_once("://") {
+        Some((scheme, host)) => {
+            matches!(scheme, "http" | "https")
+                && !host.is_empty()
+                && !host.contains(['/', '?', '#'])
+                && !host[host.starts_with("*.") as usize * 2..].contains('*')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        configuration::{Config, TlsMode},
+        cors::Cors,
+    };
+
+    #[test]
+    fn test_allows_origin() {
+        let mut config = Config {
+            domain: "atrium.io".to_owned(),
+            http_port: 8080,
+            ..Default::default()
+        };
+        let cors = Cors {
+            allowed_origins: vec![
+                "https://app.example.com".to_owned(),
+                "https://*.example.org".to_owned(),
+            ],
+            ..Default::default()
+        };
+        assert!(cors.allows_origin("http://atrium.io:8080", &config));
+        assert!(cors.allows_origin("http://app1.atrium.io:8080", &config));
+        assert!(!cors.allows_origin("http://app1.atrium.io", &config));
+        assert!(!cors.allows_origin("https://app1.atrium.io:8080", &config));
+        assert!(!cors.allows_origin("http://evilatrium.io:8080", &config));
+        assert!(!cors.allows_origin("http://evil-atrium.io.attacker.com:8080", &config));
+        assert!(cors.allows_origin("https://app.example.com", &config));
+        assert!(!cors.allows_origin("https://app.example.com.attacker.com", &config));
+        assert!(cors.allows_origin("https://a.b.example.org", &config));
+        assert!(cors.allows_origin("https://example.org", &config));
+        assert!(!cors.allows_origin("https://badexample.org", &config));
+        assert!(!cors.allows_origin("null", &config));
+
+        config.tls_mode = TlsMode::Auto;
+        assert!(cors.allows_origin("https://app1.atrium.io", &config));
+        assert!(!cors.allows_origin("http://app1.atrium.io", &config));
+    }
+
+    #[test]
+    fn test_check() {
+        let cors = Cors {
+            allowed_origins: vec![
+                "https://*.example.org".to_owned(),
+                "example.org".to_owned(),
+                "https://a.*.example.org".to_owned(),
+            ],
+            allowed_methods: vec!["GET".to_owned(), "GE T".to_owned()],
+            allowed_headers: vec!["X-Custom".to_owned(), "X Custom".to_owned()],
+        };
+        assert_eq!(
+            cors.check(),
+            vec![
+                "example.org is not an origin",
+                "https://a.*.example.org is not an origin",
+                "GE T is not an HTTP method",
+                "X Custom is not an HTTP header name",
+            ]
+        );
+    }
+}
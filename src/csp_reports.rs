@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    Json,
+};
+use http::{StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    appstate::{ConfigMap, ConfigState},
+    users::AdminToken,
+};
+
+/// Distinct violations kept, the least recently seen being evicted beyond
+const MAX_VIOLATIONS: usize = 1000;
+/// Distinct violations kept per host, so that the reports of a host cannot evict the ones of the others
+const MAX_VIOLATIONS_PER_HOST: usize = 100;
+
+/// Violations of the Content-Security-Policy reported by the browsers, deduplicated by host, directive and blocked resource
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct CspViolation {
+    /// Host of the document, atrium or one of its apps
+    pub host: String,
+    /// Last document where the violation was reported, without its query
+    pub document_uri: String,
+    pub directive: String,
+    /// Resource that was blocked, `inline` or `eval` for scripts and styles of the document
+    pub blocked_uri: String,
+    /// `enforce`, or `report` if the policy is report only
+    pub disposition: String,
+    pub count: u64,
+    /// Unix timestamps of the first and last reports
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+/// Host, directive and blocked resource of a violation
+type ViolationKey = (String, String, String);
+
+/// Violations kept in memory, until atrium restarts or an administrator clears them
+#[derive(Clone, Default)]
+pub struct CspReports {
+    violations: Arc<Mutex<HashMap<ViolationKey, CspViolation>>>,
+}
+
+impl CspReports {
+    /// Records a violation, evicting the least recently seen one of its host, or of all hosts, if there are too many
+    pub fn record(&self, violation: CspViolation) {
+        let mut violations = self
+            .violations
+            .lock()
+            .expect("csp reports lock is poisoned");
+        let key = (
+            violation.host.clone(),
+            violation.directive.clone(),
+            violation.blocked_uri.clone(),
+        );
+        if let Some(known) = violations.get_mut(&key) {
+            known.count += violation.count;
+            known.last_seen = violation.last_seen;
+            known.document_uri = violation.document_uri;
+            known.disposition = violation.disposition;
+            return;
+        }
+        let of_host = violations
+            .keys()
+            .filter(|(h, _, _)| h == &violation.host)
+            .count();
+        let oldest = |violations: &HashMap<ViolationKey, CspViolation>, same_host: bool| {
+            violations
+                .iter()
+                .filter(|(_, v)| !same_host || v.host == violation.host)
+                .min_by_key(|(_, v)| v.last_seen)
+                .map(|(k, _)| k.clone())
+        };
+        let evicted = if of_host >= MAX_VIOLATIONS_PER_HOST {
+            oldest(&violations, true)
+        } else if violations.len() >= MAX_VIOLATIONS {
+            oldest(&violations, false)
+        } else {
+            None
+        };
+        if let Some(evicted) = evicted {
+            violations.remove(&evicted);
+        }
+        violations.insert(key, violation);
+    }
+
+    /// Lists the violations of the host, or of all hosts, most recent first
+    pub fn list(&self, host: Option<&str>) -> Vec<CspViolation> {
+        let violations = self
+            .violations
+            .lock()
+            .expect("csp reports lock is poisoned");
+        let mut list: Vec<CspViolation> = violations
+            .values()
+            .filter(|v| host.is_none_or(|h| v.host == h))
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.host.cmp(&b.host)));
+        list
+    }
+
+    pub fn clear(&self, host: Option<&str>) {
+        let mut violations = self
+            .violations
+            .lock()
+            .expect("csp reports lock is poisoned");
+        violations.retain(|(h, _, _), _| host.is_some_and(|host| h != host));
+    }
+}
+
+/// Reads the violations of a report, sent with `report-uri` (`application/csp-report`) or with `report-to` (`application/reports+json`)
+fn parse_report(report: &Value, now: i64) -> Vec<CspViolation> {
+    let bodies: Vec<(&Value, [&str; 4])> = match report {
+        Value::Object(o) => o
+            .get("csp-report")
+            .map(|body| {
+                (
+                    body,
+                    [
+                        "document-uri",
+                        "effective-directive",
+                        "blocked-uri",
+                        "disposition",
+                    ],
+                )
+            })
+            .into_iter()
+            .collect(),
+        Value::Array(reports) => reports
+            .iter()
+            .filter(|r| r["type"] == "csp-violation")
+            .map(|r| {
+                (
+                    &r["body"],
+                    [
+                        "documentURL",
+                        "effectiveDirective",
+                        "blockedURL",
+                        "disposition",
+                    ],
+                )
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    bodies
+        .into_iter()
+        .filter_map(|(body, [document, directive, blocked, disposition])| {
+            let field = |name: &str| body[name].as_str().unwrap_or_default();
+            // Older browsers only give the violated directive, with its sources
+            let directive = match field(directive) {
+                "" => field("violated-directive")
+                    .split(' ')
+                    .next()
+                    .unwrap_or_default(),
+                directive => directive,
+            };
+            let document: Uri = field(document).parse().ok()?;
+            Some(CspViolation {
+                host: document.host()?.to_owned(),
+                document_uri: without_query(&document.to_string()).to_owned(),
+                directive: directive.to_owned(),
+                blocked_uri: without_query(field(blocked)).to_owned(),
+                disposition: match field(disposition) {
+                    "" => "enforce".to_owned(),
+                    disposition => disposition.to_owned(),
+                },
+                count: 1,
+                first_seen: now,
+                last_seen: now,
+            })
+        })
+        .collect()
+}
+
+fn without_query(uri: &str) -> &str {
+    uri.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// Collects the reports of the browsers, sent to the `report-uri` and `report-to` directives of the security headers
+#[utoipa::path(
+    post,
+    path = "/api/csp-report",
+    tag = "csp",
+    request_body(content = Object, description = "Report of the browser, as application/csp-report or application/reports+json"),
+    responses(
+        (status = 204, description = "Report is collected, violations of hosts that atrium does not serve being ignored"),
+        (status = 400, description = "Report is not JSON"),
+    ),
+)]
+pub async fn csp_report(
+    State(reports): State<CspReports>,
+    State(config): State<ConfigState>,
+    State(configmap): State<ConfigMap>,
+    body: Bytes,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let report: Value = serde_json::from_slice(&body)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "report is not valid JSON"))?;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    for violation in parse_report(&report, now) {
+        // Anyone can send reports, only the hosts of atrium are worth keeping
+        if violation.host == config.hostname || configmap.contains_key(&violation.host) {
+            reports.record(violation);
+        }
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CspReportQuery {
+    /// Only the violations of this host
+    pub host: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/csp-reports",
+    tag = "csp",
+    security(("cookie" = []), ("bearer" = [])),
+    params(CspReportQuery),
+    responses(
+        (status = 200, description = "Violations reported since atrium started, most recent first", body = [CspViolation]),
+        (status = 401, description = "User is not an administrator"),
+    ),
+)]
+pub async fn get_csp_reports(
+    State(reports): State<CspReports>,
+    _admin: AdminToken,
+    Query(query): Query<CspReportQuery>,
+) -> Json<Vec<CspViolation>> {
+    Json(reports.list(query.host.as_deref()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/csp-reports",
+    tag = "csp",
+    security(("cookie" = []), ("bearer" = [])),
+    params(CspReportQuery),
+    responses(
+        (status = 204, description = "Violations are cleared"),
+        (status = 401, description = "User is not an administrator"),
+    ),
+)]
+pub async fn clear_csp_reports(
+    State(reports): State<CspReports>,
+    _admin: AdminToken,
+    Query(query): Query<CspReportQuery>,
+) -> StatusCode {
+    reports.clear(query.host.as_deref());
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::csp_reports::{
+        parse_report, CspReports, CspViolation, MAX_VIOLATIONS, MAX_VIOLATIONS_PER_HOST,
+    };
+
+    #[test]
+    fn test_parse_report() {
+        let report = json!({"csp-report": {
+            "document-uri": "https://app1.atrium.io/page?secret=1",
+            "violated-directive": "script-src-elem 'self'",
+            "blocked-uri": "https://cdn.example.com/lib.js?v=2",
+        }});
+        let violations = parse_report(&report, 10);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].host, "app1.atrium.io");
+        assert_eq!(violations[0].document_uri, "https://app1.atrium.io/page");
+        assert_eq!(violations[0].directive, "script-src-elem");
+        assert_eq!(violations[0].blocked_uri, "https://cdn.example.com/lib.js");
+        assert_eq!(violations[0].disposition, "enforce");
+
+        let report = json!([
+            {"type": "csp-violation", "body": {
+                "documentURL": "https://atrium.io/",
+                "effectiveDirective": "style-src-attr",
+                "blockedURL": "inline",
+                "disposition": "report",
+            }},
+            {"type": "deprecation", "body": {}},
+        ]);
+        let violations = parse_report(&report, 10);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].host, "atrium.io");
+        assert_eq!(violations[0].directive, "style-src-attr");
+        assert_eq!(violations[0].blocked_uri, "inline");
+        assert_eq!(violations[0].disposition, "report");
+
+        assert!(parse_report(&json!({"other": {}}), 10).is_empty());
+    }
+
+    #[test]
+    fn test_deduplication() {
+        let reports = CspReports::default();
+        let violation = |document: &str, blocked: &str, now: i64| {
+            let report = json!({"csp-report": {
+                "document-uri": document,
+                "effective-directive": "img-src",
+                "blocked-uri": blocked,
+            }});
+            parse_report(&report, now).remove(0)
+        };
+        reports.record(violation("https://app1.atrium.io/a", "https://img.com", 1));
+        reports.record(violation("https://app1.atrium.io/b", "https://img.com", 2));
+        reports.record(violation("https://app2.atrium.io/", "https://img.com", 3));
+
+        let list = reports.list(None);
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].host, "app2.atrium.io");
+        assert_eq!(list[1].count, 2);
+        assert_eq!(list[1].first_seen, 1);
+        assert_eq!(list[1].last_seen, 2);
+        assert_eq!(list[1].document_uri, "https://app1.atrium.io/b");
+
+        reports.clear(Some("app1.atrium.io"));
+        assert_eq!(reports.list(None).len(), 1);
+        assert!(reports.list(Some("app1.atrium.io")).is_empty());
+        reports.clear(None);
+        assert!(reports.list(None).is_empty());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let reports = CspReports::default();
+        let violation = |host: &str, blocked: usize, now: i64| CspViolation {
+            host: host.to_owned(),
+            document_uri: format!("https://{host}/"),
+            directive: "img-src".to_owned(),
+            blocked_uri: format!("https://img{blocked}.com"),
+            disposition: "enforce".to_owned(),
+            count: 1,
+            first_seen: now,
+            last_seen: now,
+        };
+        reports.record(violation("atrium.io", 0, 0));
+        // A host flooding reports only evicts its own violations
+        for i in 0..2 * MAX_VIOLATIONS_PER_HOST {
+            reports.record(violation("app1.atrium.io", i, 1 + i as i64));
+        }
+        let list = reports.list(Some("app1.atrium.io"));
+        assert_eq!(list.len(), MAX_VIOLATIONS_PER_HOST);
+        assert_eq!(
+            list.last().unwrap().blocked_uri,
+            format!("https://img{}.com", MAX_VIOLATIONS_PER_HOST)
+        );
+        assert_eq!(reports.list(Some("atrium.io")).len(), 1);
+
+        // Beyond the overall limit, the least recently seen violation is evicted
+        reports.clear(None);
+        for i in 0..MAX_VIOLATIONS {
+            reports.record(violation(&format!("app{i}.atrium.io"), 0, i as i64));
+        }
+        reports.record(violation("atrium.io", 0, 10_000));
+        assert_eq!(reports.list(None).len(), MAX_VIOLATIONS);
+        assert!(reports.list(Some("app0.atrium.io")).is_empty());
+        assert_eq!(reports.list(Some("atrium.io")).len(), 1);
+    }
+}
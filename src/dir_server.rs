@@ -1,16 +1,37 @@
 use axum::{
     body::{boxed, Body, BoxBody},
-    http::{Request, Response, StatusCode, Uri},
+    extract::Host,
+    http::{Method, Request, Response, StatusCode, Uri},
 };
 use tower::ServiceExt;
 use tower_http::services::ServeDir;
 
-use crate::configuration::HostType;
+use crate::{
+    client_ip::ClientIp,
+    configuration::HostType,
+    policy::Access,
+    users::{check_authorization, UserTokenWithoutXSRFCheck},
+};
 
 pub async fn dir_handler(
+    user: Option<UserTokenWithoutXSRFCheck>,
+    ClientIp(ip): ClientIp,
+    Host(hostname): Host,
+    method: Method,
     uri: Uri,
     app: HostType,
 ) -> Result<Response<BoxBody>, (StatusCode, String)> {
+    let domain = hostname.split(':').next().unwrap_or_default();
+    let access = Access {
+        method: &method,
+        path: uri.path(),
+        source: ip,
+    };
+    if let Some(response) = check_authorization(&app, &user.as_ref().map(|u| &u.0), domain, &access)
+    {
+        return Ok(response.map(boxed));
+    }
+
     let app = match app {
         HostType::StaticApp(app) => app,
         _ => panic!("Service is not a static app !"),
@@ -0,0 +1,283 @@
+use axum::extract::State;
+use axum_extra::extract::PrivateCookieJar;
+use http::{header::HOST, HeaderMap, Method, Request, Response, StatusCode};
+use hyper::Body;
+
+use crate::{
+    appstate::{ConfigMap, ConfigState},
+    client_ip::{forwarded_client, ClientIp},
+    configuration::TlsMode,
+    identity::{add_user_headers, forward_identity},
+    policy::Access,
+    users::{check_authorization, query_token_user, UserTokenWithoutXSRFCheck},
+};
+
+static VERIFY_PATH: &str = "/auth/verify";
+static FORWARDED_HOST: &str = "X-Forwarded-Host";
+static FORWARDED_URI: &str = "X-Forwarded-Uri";
+static FORWARDED_METHOD: &str = "X-Forwarded-Method";
+
+/// Authorizes the requests of an external reverse proxy (nginx `auth_request`, Traefik `ForwardAuth`) as for the apps served by atrium
+#[utoipa::path(
+    get,
+    path = "/auth/verify",
+    tag = "auth",
+    params(
+        ("X-Forwarded-Host" = String, Header, description = "Host requested from the reverse proxy"),
+        ("X-Forwarded-Uri" = Option<String>, Header, description = "URI requested from the reverse proxy, defaults to /, its token query parameter authenticating shared links"),
+        ("X-Forwarded-Method" = Option<String>, Header, description = "Method requested from the reverse proxy, defaults to GET"),
+        ("X-Forwarded-For" = Option<String>, Header, description = "Client of the reverse proxy, only read if it is a trusted proxy and the configured forwarding header, defaults to the reverse proxy itself"),
+    ),
+    responses(
+        (status = 200, description = "Access is granted, the user is given in the Remote-User, Remote-Groups (roles) and Remote-Email headers if logged in, and in the Remote-Jwt header if the app has an identity JWT secret"),
+        (status = 401, description = "User is not logged in"),
+        (status = 403, description = "User is not allowed to access the app, or the host is not an app of atrium"),
+    ),
+)]
+pub async fn verify(
+    user: Option<UserTokenWithoutXSRFCheck>,
+    jar: PrivateCookieJar,
+    ClientIp(ip): ClientIp,
+    State(configmap): State<ConfigMap>,
+    State(config): State<ConfigState>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let forwarded = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let host = forwarded(FORWARDED_HOST).unwrap_or_default();
+    let host = host.split(':').next().unwrap_or_default();
+    let uri = forwarded(FORWARDED_URI).unwrap_or("/");
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    };
+    let method = forwarded(FORWARDED_METHOD)
+        .and_then(|m| Method::from_bytes(m.as_bytes()).ok())
+        .unwrap_or(Method::GET);
+    // The caller is a reverse proxy that forwards its client, even if atrium is not behind it
+    let source = match config.tls_mode {
+        TlsMode::BehindProxy => ip,
+        _ => forwarded_client(&config, ip, &headers),
+    };
+    let access = Access {
+        method: &method,
+        path,
+        source,
+    };
+    // Shared links carry their token in the query of the forwarded URI
+    let user = user
+        .map(|u| u.0)
+        .or_else(|| query_token_user(&jar, query).and_then(Result::ok));
+    let user = user.as_ref();
+
+    // Proxies only allow 401 and 403 as refusals, any other status being an error
+    let Some(app) = configmap.get(host) else {
+        return status(StatusCode::FORBIDDEN);
+    };
+    if let Some(response) = check_authorization(app, &user, host, &access) {
+        return response;
+    }
+
+    let mut response = status(StatusCode::OK);
+    if let Some(user) = user {
+        add_user_headers(response.headers_mut(), user);
+        forward_identity(response.headers_mut(), app.app(), user, &config.hostname);
+    }
+    response
+}
+
+/// Tells if a request is a forward auth one sent to atrium itself, as the `X-Forwarded-Host` header would otherwise route it to the app
+pub fn is_verify_request<B>(request: &Request<B>, hostname: &str) -> bool {
+    request.uri().path() == VERIFY_PATH
+        && request
+            .headers()
+            .get(HOST)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|host| host.split(':').next())
+            == Some(hostname)
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+    use axum::{extract::State, response::IntoResponse};
+    use axum_extra::extract::{
+        cookie::{Cookie, Key},
+        PrivateCookieJar,
+    };
+    use http::{header::SET_COOKIE, HeaderMap, Request, StatusCode};
+    use hyper::Body;
+    use time::OffsetDateTime;
+
+    use crate::{
+        apps::App,
+        client_ip::ClientIp,
+        configuration::{Config, HostType},
+        forward_auth::{is_verify_request, verify},
+        users::{Share, UserInfo, UserToken, UserTokenWithoutXSRFCheck, SHARE_TOKEN},
+    };
+
+    #[tokio::test]
+    async fn test_verify() {
+        let app = App {
+            host: "app1".to_owned(),
+            target: "tests/data".to_owned(),
+            secured: true,
+            roles: vec!["USERS".to_owned()],
+            ..Default::default()
+        };
+        let configmap = Arc::new(HashMap::from([(
+            "app1.atrium.io".to_owned(),
+            HostType::StaticApp(Box::new(app)),
+        )]));
+        let config = Arc::new(Config::default());
+        let jar = PrivateCookieJar::new(Key::generate());
+        let ip = ClientIp(IpAddr::from([127, 0, 0, 1]));
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-Host", "app1.atrium.io:443".parse().unwrap());
+        headers.insert("X-Forwarded-Uri", "/some/path?a=b".parse().unwrap());
+        let user = |roles: &[&str]| {
+            Some(UserTokenWithoutXSRFCheck(UserToken {
+                login: "user".to_owned(),
+                roles: roles.iter().map(|r| r.to_string()).collect(),
+                info: Some(UserInfo {
+                    email: "user@atrium.io".to_owned(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }))
+        };
+
+        let response = verify(
+            user(&["USERS", "OTHERS"]),
+            jar.clone(),
+            ip,
+            State(configmap.clone()),
+            State(config.clone()),
+            headers.clone(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Remote-User"], "user");
+        assert_eq!(response.headers()["Remote-Groups"], "USERS,OTHERS");
+        assert_eq!(response.headers()["Remote-Email"], "user@atrium.io");
+
+        let response = verify(
+            user(&["OTHERS"]),
+            jar.clone(),
+            ip,
+            State(configmap.clone()),
+            State(config.clone()),
+            headers.clone(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = verify(
+            None,
+            jar.clone(),
+            ip,
+            State(configmap.clone()),
+            State(config.clone()),
+            headers.clone(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // A shared link authenticates with the token of its query
+        let share = UserToken {
+            login: "user".to_owned(),
+            roles: vec!["USERS".to_owned()],
+            share: Some(Share {
+                hostname: "app1.atrium.io".to_owned(),
+                path: "/shared/doc.pdf".to_owned(),
+                ..Default::default()
+            }),
+            expires: OffsetDateTime::now_utc().unix_timestamp() + 60,
+            ..Default::default()
+        };
+        let response = jar
+            .clone()
+            .add(Cookie::new(
+                SHARE_TOKEN,
+                serde_json::to_string(&share).unwrap(),
+            ))
+            .into_response();
+        let token = response.headers()[SET_COOKIE].to_str().unwrap();
+        let token = token
+            .split(';')
+            .next()
+            .unwrap()
+            .trim_start_matches(&format!("{SHARE_TOKEN}="));
+        let share_headers = |path: &str| {
+            let mut headers = headers.clone();
+            headers.insert(
+                "X-Forwarded-Uri",
+                format!("{path}?token={token}").parse().unwrap(),
+            );
+            headers
+        };
+        let response = verify(
+            None,
+            jar.clone(),
+            ip,
+            State(configmap.clone()),
+            State(config.clone()),
+            share_headers("/shared/doc.pdf"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["Remote-User"], "user");
+        let response = verify(
+            None,
+            jar.clone(),
+            ip,
+            State(configmap.clone()),
+            State(config.clone()),
+            share_headers("/other.pdf"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        headers.insert("X-Forwarded-Host", "unknown.atrium.io".parse().unwrap());
+        let response = verify(
+            user(&["USERS"]),
+            jar.clone(),
+            ip,
+            State(configmap),
+            State(config),
+            headers,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_is_verify_request() {
+        let request = |host: &str, path: &str| {
+            Request::get(path)
+                .header("Host", host)
+                .header("X-Forwarded-Host", "app1.atrium.io")
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert!(is_verify_request(
+            &request("atrium.io:8080", "/auth/verify"),
+            "atrium.io"
+        ));
+        assert!(!is_verify_request(
+            &request("app1.atrium.io", "/auth/verify"),
+            "atrium.io"
+        ));
+        assert!(!is_verify_request(
+            &request("atrium.io", "/auth/local"),
+            "atrium.io"
+        ));
+    }
+}
@@ -1,7 +1,9 @@
 extern crate headers;
 extern crate http;
 
-use headers::{Header, HeaderName, HeaderValue};
+use axum::{async_trait, extract::FromRequestParts};
+use headers::{Header, HeaderMapExt, HeaderName, HeaderValue, IfMatch};
+use http::{header::IF_MATCH, request::Parts, StatusCode};
 
 pub struct XSRFToken(pub String);
 
@@ -31,3 +33,27 @@ impl Header for XSRFToken {
         }
     }
 }
+
+/// `If-Match` precondition, if the request has one.
+///
+/// `Option<TypedHeader<IfMatch>>` cannot be used, as a missing `If-Match` header decodes as an empty one, that never matches.
+pub struct OptionalIfMatch(pub Option<IfMatch>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for OptionalIfMatch
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if !parts.headers.contains_key(IF_MATCH) {
+            return Ok(OptionalIfMatch(None));
+        }
+        let if_match = parts
+            .headers
+            .typed_try_get::<IfMatch>()
+            .map_err(|_| (StatusCode::BAD_REQUEST, "invalid If-Match header"))?;
+        Ok(OptionalIfMatch(if_match))
+    }
+}
@@ -0,0 +1,314 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use axum::async_trait;
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    secrets::{option_secret, Secret},
+    users::{UserInfo, ADMINS_ROLE},
+    utils::is_default,
+};
+
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// LDAP result code of a wrong password
+const INVALID_CREDENTIALS: u32 = 49;
+
+fn user_filter() -> String {
+    "(uid={login})".to_owned()
+}
+
+fn group_attribute() -> String {
+    "memberOf".to_owned()
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct LdapConfig {
+    /// ldap:// or ldaps:// URL of the directory
+    pub url: String,
+    /// DN to bind with for the user search, anonymous if not set
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub bind_dn: Option<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "option_secret"
+    )]
+    pub bind_password: Option<Secret>,
+    pub search_base: String,
+    /// Filter finding the user, where `{login}` is replaced by the escaped login
+    #[serde(default = "user_filter")]
+    pub user_filter: String,
+    /// Attribute of the user entry listing its groups
+    #[serde(default = "group_attribute")]
+    pub group_attribute: String,
+    /// Group (DN or name) mapped to the ADMINS role
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub admins_group: Option<String>,
+}
+
+/// User found in a directory, whose password was checked
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DirectoryUser {
+    pub groups: Vec<String>,
+    pub info: Option<UserInfo>,
+}
+
+/// Directory of users, to authenticate the users that are not in the configuration
+#[async_trait]
+pub trait Directory: Send + Sync {
+    /// Checks the password of a user, returning `None` if the user does not exist or the password is wrong
+    async fn authenticate(&self, login: &str, password: &str) -> Result<Option<DirectoryUser>>;
+}
+
+/// Name of a group given as a DN, its first attribute value
+fn group_name(group: &str) -> &str {
+    let rdn = group.split(',').next().unwrap_or(group);
+    rdn.split_once('=').map_or(rdn, |(_, value)| value).trim()
+}
+
+/// Maps the groups of a directory user to roles : a group gives the role of its name, and only the admins group gives the ADMINS role
+pub fn roles(config: &LdapConfig, groups: &[String]) -> Vec<String> {
+    let mut roles: Vec<String> = groups
+        .iter()
+        .map(|g| group_name(g).to_owned())
+        .filter(|r| r != ADMINS_ROLE)
+        .collect();
+    if let Some(admins) = &config.admins_group {
+        if groups
+            .iter()
+            .any(|g| g.eq_ignore_ascii_case(admins) || group_name(g).eq_ignore_ascii_case(admins))
+        {
+            roles.push(ADMINS_ROLE.to_owned());
+        }
+    }
+    roles.sort();
+    roles.dedup();
+    roles
+}
+
+/// LDAP directory, searched for the user before binding as the user to check the password
+pub struct LdapDirectory<'a> {
+    config: &'a LdapConfig,
+}
+
+impl<'a> LdapDirectory<'a> {
+    pub fn new(config: &'a LdapConfig) -> Self {
+        LdapDirectory { config }
+    }
+}
+
+#[async_trait]
+impl Directory for LdapDirectory<'_> {
+    async fn authenticate(&self, login: &str, password: &str) -> Result<Option<DirectoryUser>> {
+        // LDAP servers accept a bind without password as an anonymous one
+        if login.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+        let settings = LdapConnSettings::new().set_conn_timeout(CONNECTION_TIMEOUT);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(|e| anyhow!("could not connect to LDAP: {e}"))?;
+        // An error of the connection fails the operations below, which report it
+        tokio::spawn(async move {
+            let _ = conn.drive().await;
+        });
+
+        if let Some(bind_dn) = &self.config.bind_dn {
+            let bind_password = self.config.bind_password.as_ref();
+            ldap.simple_bind(bind_dn, bind_password.map_or("", |s| s.expose()))
+                .await
+                .and_then(|r| r.success())
+                .map_err(|e| anyhow!("could not bind to LDAP: {e}"))?;
+        }
+        let filter = self
+            .config
+            .user_filter
+            .replace("{login}", &ldap_escape(login));
+        let attributes = [
+            self.config.group_attribute.as_str(),
+            "givenName",
+            "sn",
+            "mail",
+        ];
+        let (entries, _) = ldap
+            .search(
+                &self.config.search_base,
+                Scope::Subtree,
+                &filter,
+                attributes,
+            )
+            .await
+            .and_then(|r| r.success())
+            .map_err(|e| anyhow!("could not search LDAP: {e}"))?;
+        // The filter must designate a single user
+        let Ok([entry]) = <[_; 1]>::try_from(entries) else {
+            let _ = ldap.unbind().await;
+            return Ok(None);
+        };
+        let entry = SearchEntry::construct(entry);
+
+        let bind = ldap
+            .simple_bind(&entry.dn, password)
+            .await
+            .map_err(|e| anyhow!("could not bind to LDAP: {e}"))?;
+        let _ = ldap.unbind().await;
+        if bind.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bind.success()
+            .map_err(|e| anyhow!("could not bind to LDAP: {e}"))?;
+
+        let first = |attribute: &str| {
+            entry
+                .attrs
+                .get(attribute)
+                .and_then(|values| values.first())
+                .cloned()
+                .unwrap_or_default()
+        };
+        let info = UserInfo {
+            firstname: first("givenName"),
+            lastname: first("sn"),
+            email: first("mail"),
+        };
+        Ok(Some(DirectoryUser {
+            groups: entry
+                .attrs
+                .get(&self.config.group_attribute)
+                .cloned()
+                .unwrap_or_default(),
+            info: (info != UserInfo::default()).then_some(info),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::IpAddr};
+
+    use anyhow::Result;
+    use axum::async_trait;
+
+    use crate::{
+        configuration::Config,
+        ldap::{roles, Directory, DirectoryUser, LdapConfig},
+        users::{authenticate_local_user, hash_password, LocalAuth, User, ADMINS_ROLE},
+    };
+
+    /// In-process directory
+    #[derive(Default)]
+    struct MockDirectory {
+        users: HashMap<String, (String, DirectoryUser)>,
+    }
+
+    #[async_trait]
+    impl Directory for MockDirectory {
+        async fn authenticate(&self, login: &str, password: &str) -> Result<Option<DirectoryUser>> {
+            Ok(self
+                .users
+                .get(login)
+                .filter(|(p, _)| p == password)
+                .map(|(_, user)| user.clone()))
+        }
+    }
+
+    fn ldap_config() -> LdapConfig {
+        LdapConfig {
+            url: "ldap://localhost".to_owned(),
+            search_base: "dc=atrium,dc=io".to_owned(),
+            admins_group: Some("cn=admins,ou=groups,dc=atrium,dc=io".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_roles() {
+        let groups = vec![
+            "cn=users,ou=groups,dc=atrium,dc=io".to_owned(),
+            "CN=Admins,OU=groups,DC=atrium,DC=io".to_owned(),
+        ];
+        assert_eq!(
+            roles(&ldap_config(), &groups),
+            vec!["ADMINS", "Admins", "users"]
+        );
+        let config = LdapConfig {
+            admins_group: Some("admins".to_owned()),
+            ..ldap_config()
+        };
+        assert!(roles(&config, &groups[..1])
+            .iter()
+            .all(|r| r != ADMINS_ROLE));
+        // A group named as the role does not make its members administrators
+        let groups = vec!["cn=ADMINS,ou=people,dc=atrium,dc=io".to_owned()];
+        assert!(roles(&ldap_config(), &groups).is_empty());
+        let config = LdapConfig {
+            admins_group: None,
+            ..ldap_config()
+        };
+        assert!(roles(&config, &groups).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_directory() {
+        let config = Config {
+            ldap_config: Some(ldap_config()),
+            users: vec![User {
+                login: "local".to_owned(),
+                password: hash_password(b"password").unwrap(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut directory = MockDirectory::default();
+        for login in ["local", "ldap"] {
+            directory.users.insert(
+                login.to_owned(),
+                (
+                    "ldap password".to_owned(),
+                    DirectoryUser {
+                        groups: vec!["cn=admins,ou=groups,dc=atrium,dc=io".to_owned()],
+                        info: None,
+                    },
+                ),
+            );
+        }
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let auth = |login: &str, password: &str| LocalAuth {
+            login: login.to_owned(),
+            password: password.to_owned(),
+        };
+
+        let (user, token) =
+            authenticate_local_user(&config, Some(&directory), auth("ldap", "ldap password"), ip)
+                .await
+                .unwrap();
+        assert_eq!(user.login, "ldap");
+        assert_eq!(token.roles, vec!["ADMINS", "admins"]);
+        assert!(
+            authenticate_local_user(&config, Some(&directory), auth("ldap", "wrong"), ip)
+                .await
+                .is_err()
+        );
+        // Local users are not looked up in the directory
+        assert!(authenticate_local_user(
+            &config,
+            Some(&directory),
+            auth("local", "ldap password"),
+            ip
+        )
+        .await
+        .is_err());
+        assert!(
+            authenticate_local_user(&config, Some(&directory), auth("local", "password"), ip)
+                .await
+                .is_ok()
+        );
+        assert!(
+            authenticate_local_user(&config, None, auth("ldap", "ldap password"), ip)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,13 +1,34 @@
 pub mod apps;
 pub mod appstate;
+pub mod audit;
+pub mod bundle;
+pub mod cli;
+pub mod client_ip;
+pub mod compression;
+pub mod config_history;
+pub mod config_writer;
 pub mod configuration;
+pub mod cors;
+pub mod csp_reports;
 
 pub mod dir_server;
+pub mod forward_auth;
 pub mod headers;
+pub mod identity;
+pub mod ldap;
 
 pub mod middlewares;
+pub mod openapi;
+pub mod policy;
+pub mod proxy_protocol;
+
+pub mod secrets;
+pub mod security_headers;
 
 pub mod server;
 pub mod sysinfo;
+pub mod tokens;
+pub mod totp;
 pub mod users;
 pub mod utils;
+pub mod webauthn;
@@ -1,139 +1,300 @@
-use crate::{appstate::ConfigState, configuration::HostType};
+use crate::{
+    appstate::ConfigState, client_ip::ClientIp, configuration::HostType,
+    forward_auth::is_verify_request, security_headers::DEFAULT_PROFILE,
+};
 use axum::{
     extract::State,
     http::{Request, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+};
+use http::{
+    header::{ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, RANGE},
+    Method,
 };
-use http::{HeaderValue, Method};
 
+/// Allows the cross-origin requests of atrium or of the app, answering their preflight requests, any origin being allowed in debug mode
 pub async fn cors_middleware<B>(
     State(cfg): State<ConfigState>,
+    app: Option<HostType>,
     req: Request<B>,
     next: Next<B>,
-) -> Result<Response, StatusCode> {
-    let origin = req.headers().get("origin").map(|o| o.to_owned());
-    let hostname = if origin.is_some() && {
-        let this = &origin.as_ref().unwrap().to_str();
-        let f = |o: &str| o.contains(&cfg.domain);
-        matches!(this, Ok(x) if f(x))
-    } {
-        origin.unwrap()
-    } else {
-        cfg.full_domain()
-            .parse()
-            .expect("could not parse hostname : invalid format")
+) -> Response {
+    // Forward auth requests are for atrium itself, as in Server::build
+    let app = app.filter(|_| !is_verify_request(&req, &cfg.hostname));
+    // Apps without CORS configuration handle it themselves
+    let cors = match &app {
+        Some(app) => match &app.app().cors {
+            Some(cors) => cors,
+            None => return next.run(req).await,
+        },
+        None => &cfg.cors,
+    };
+    let Some(origin) = req.headers().get(ORIGIN).cloned() else {
+        return next.run(req).await;
     };
+    let allowed = cfg.debug_mode || matches!(origin.to_str(), Ok(o) if cors.allows_origin(o, &cfg));
+    if req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        if !allowed {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+        let mut resp = StatusCode::NO_CONTENT.into_response();
+        cors.allow_preflight(resp.headers_mut(), origin);
+        return resp;
+    }
     let mut resp = next.run(req).await;
-    let headers = resp.headers_mut();
-    headers.insert("Access-Control-Allow-Origin", hostname);
-    allow_methods_headers_credentials(headers);
-    Ok(resp)
+    if allowed {
+        cors.allow(resp.headers_mut(), origin);
+    }
+    resp
 }
 
-pub async fn debug_cors_middleware<B>(
+/// Sets the security headers of the profile of the app, or of the default profile for atrium itself
+pub async fn inject_security_headers<B>(
+    State(cfg): State<ConfigState>,
+    host_type: Option<HostType>,
     req: Request<B>,
     next: Next<B>,
-) -> Result<Response, StatusCode> {
-    let method = req.method().clone();
-    let origin = req.headers().get("origin").map(|o| o.to_owned());
+) -> Result<Response, StatusCode>
+where
+    B: std::marker::Send,
+{
+    let profile = match &host_type {
+        Some(app) if !app.inject_security_headers() => return Ok(next.run(req).await),
+        Some(app) => app.app().security_headers.as_str(),
+        None => DEFAULT_PROFILE,
+    };
+    let profile = cfg.security_headers(profile);
     let mut resp = next.run(req).await;
-    if let Some(origin) = origin {
-        let headers = resp.headers_mut();
-        headers.insert("Access-Control-Allow-Origin", origin);
-        allow_methods_headers_credentials(headers);
-        if method == Method::OPTIONS {
-            *resp.status_mut() = StatusCode::OK;
-        }
-    }
+    profile.inject(resp.headers_mut(), &cfg)?;
     Ok(resp)
 }
 
-fn allow_methods_headers_credentials(headers: &mut http::HeaderMap) {
-    headers.insert(
-        "Access-Control-Allow-Methods",
-        "POST, GET, OPTIONS, PUT, DELETE, PROPFIND, PROPPATCH, MKCOL, MOVE, COPY"
-            .parse()
-            .unwrap(),
-    );
-    headers.insert("Access-Control-Allow-Headers", "Accept, Content-Type, Content-Length, Accept-Encoding, XSRF-TOKEN, Authorization, Depth, Destination, Overwrite, X-OC-Mtime".parse().unwrap());
-    headers.insert("Access-Control-Allow-Credentials", "true".parse().unwrap());
+/// Refuses the requests of the clients that the IP filters of atrium, of the app or of the administration API do not allow
+pub async fn filter_ips<B>(
+    State(cfg): State<ConfigState>,
+    ClientIp(ip): ClientIp,
+    app: Option<HostType>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, StatusCode> {
+    // Forward auth requests are for atrium itself, as in Server::build
+    let app = app.filter(|_| !is_verify_request(&req, &cfg.hostname));
+    let allowed = cfg.ip_filter.allows(ip)
+        && match &app {
+            Some(app) => app.app().ip_filter.allows(ip),
+            None => !req.uri().path().starts_with("/api/admin") || cfg.admin_ip_filter.allows(ip),
+        };
+    if !allowed {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(req).await)
 }
 
-pub async fn inject_security_headers<B>(
+/// Selects the compression settings of the app, or of atrium itself, for the compression layer
+pub async fn select_compression<B>(
     State(cfg): State<ConfigState>,
-    host_type: Option<HostType>,
+    app: Option<HostType>,
     req: Request<B>,
     next: Next<B>,
-) -> Result<Response, StatusCode>
-where
-    B: std::marker::Send,
-{
-    let inject = host_type
-        .map(|app| app.inject_security_headers())
-        .unwrap_or(true);
-    if inject {
-        let source = {
-            format!(
-                "{s}://{h}:* {s}://*.{h}:*",
-                s = cfg.scheme(),
-                h = cfg.domain,
+) -> Response {
+    // Forward auth requests are for atrium itself, as in Server::build
+    let app = app.filter(|_| !is_verify_request(&req, &cfg.hostname));
+    let compression = match &app {
+        Some(app) => &app.app().compression,
+        None => &cfg.compression,
+    };
+    // Ranges of a compressed response could not be resumed with the uncompressed content
+    let compression =
+        (compression.enabled && !req.headers().contains_key(RANGE)).then(|| compression.clone());
+    let mut resp = next.run(req).await;
+    if let Some(compression) = compression {
+        resp.extensions_mut().insert(compression);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use axum::{
+        middleware,
+        routing::{any, get},
+        Router,
+    };
+    use axum_extra::extract::cookie::Key;
+    use http::{
+        header::{
+            ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_REQUEST_METHOD, CONTENT_ENCODING, CONTENT_TYPE, HOST, ORIGIN, RANGE,
+        },
+        Method, Request, StatusCode,
+    };
+    use hyper::Body;
+    use tower::ServiceExt;
+    use tower_http::compression::CompressionLayer;
+
+    use crate::{
+        apps::App,
+        appstate::AppState,
+        compression::{Compression, ConfiguredCompression},
+        configuration::{Config, HostType},
+        cors::Cors,
+        middlewares::{cors_middleware, select_compression},
+    };
+
+    fn state() -> AppState {
+        let app = |id: usize, host: &str, cors: Option<Cors>| {
+            (
+                format!("{host}.atrium.io"),
+                HostType::StaticApp(Box::new(App {
+                    id,
+                    host: host.to_owned(),
+                    target: "tests/data".to_owned(),
+                    cors,
+                    compression: Compression {
+                        enabled: true,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                })),
             )
         };
-        let mut resp = next.run(req).await;
-        inject_security_headers_internal(&mut resp, &source)?;
-        Ok(resp)
-    } else {
-        Ok(next.run(req).await)
+        let cors = Cors {
+            allowed_origins: vec!["https://allowed.com".to_owned()],
+            ..Default::default()
+        };
+        let configmap = HashMap::from([app(1, "app1", Some(cors)), app(2, "app2", None)]);
+        let config = Config {
+            hostname: "atrium.io".to_owned(),
+            domain: "atrium.io".to_owned(),
+            ..Default::default()
+        };
+        let file = std::env::temp_dir().join("atrium_middlewares_test.yaml");
+        AppState::new(
+            Key::generate(),
+            Arc::new(config),
+            Arc::new(configmap),
+            file.to_str().unwrap().to_owned(),
+        )
     }
-}
 
-fn inject_security_headers_internal(resp: &mut Response, source: &str) -> Result<(), StatusCode> {
-    let headers = resp.headers_mut();
-    match headers
-        .get("Content-Security-Policy")
-        .and_then(|h| h.to_str().ok())
-        .map(|h| h.to_owned())
-    {
-        // If it exists, alter it to inject the atrium main hostname in authorized frame ancestors
-        Some(csp) => {
-            if csp.contains("frame-ancestors") {
-                headers.insert(
-                    "Content-Security-Policy",
-                    HeaderValue::from_str(&csp.replacen(
-                        "frame-ancestors",
-                        &format!("frame-ancestors {source}"),
-                        1,
-                    ))
-                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
-                );
-            } else {
-                headers.insert(
-                    "Content-Security-Policy",
-                    HeaderValue::from_str(&format!("{csp}; frame-ancestors {source}"))
-                        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
-                );
+    fn router() -> Router {
+        let state = state();
+        Router::new()
+            .route("/", any(|| async { "app" }))
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                cors_middleware,
+            ))
+            .with_state(state)
+    }
+
+    fn preflight(host: &str, origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .header(HOST, host)
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cors_middleware() {
+        let response = router()
+            .oneshot(preflight("app1.atrium.io", "https://allowed.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://allowed.com"
+        );
+        assert!(response
+            .headers()
+            .contains_key(ACCESS_CONTROL_ALLOW_METHODS));
+
+        let response = router()
+            .oneshot(preflight("app1.atrium.io", "https://other.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // The simple requests of an allowed origin get the headers
+        let request = Request::get("/")
+            .header(HOST, "app1.atrium.io")
+            .header(ORIGIN, "https://allowed.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = router().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://allowed.com"
+        );
+
+        // Apps without CORS configuration answer the preflight requests themselves
+        let response = router()
+            .oneshot(preflight("app2.atrium.io", "https://other.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_select_compression() {
+        let state = state();
+        let body = "a".repeat(100);
+        let page = body.clone();
+        let router = Router::new()
+            .route(
+                "/",
+                get(move || async move { ([(CONTENT_TYPE, "text/html")], page) }),
+            )
+            .route(
+                "/encoded",
+                get(|| async {
+                    (
+                        [(CONTENT_TYPE, "text/html"), (CONTENT_ENCODING, "br")],
+                        "encoded",
+                    )
+                }),
+            )
+            .layer(middleware::from_fn_with_state(
+                state.clone(),
+                select_compression,
+            ))
+            .layer(CompressionLayer::new().compress_when(ConfiguredCompression))
+            .with_state(state);
+        let request = |path: &str, range: bool| {
+            let mut request = Request::get(path)
+                .header(HOST, "app1.atrium.io")
+                .header(ACCEPT_ENCODING, "gzip");
+            if range {
+                request = request.header(RANGE, "bytes=0-9");
             }
-        }
-        // If not, forge a default CSP Header
-        None => {
-            headers.insert("Content-Security-Policy", 
-            HeaderValue::from_str(&format!("default-src 'self' {source} https://unpkg.com https://fonts.gstatic.com; script-src 'self' {source} 'wasm-unsafe-eval' https://cdn.jsdelivr.net https://unpkg.com; style-src 'self' {source} 'unsafe-inline'; frame-src {source}; frame-ancestors {source}; img-src 'self' {source} blob:"))
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,);
-        }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = router.clone().oneshot(request("/", false)).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "gzip");
+
+        // Ranges are of the uncompressed content
+        let response = router.clone().oneshot(request("/", true)).await.unwrap();
+        assert!(!response.headers().contains_key(CONTENT_ENCODING));
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(bytes, body.as_bytes());
+
+        // Responses already encoded are left as they are
+        let response = router.oneshot(request("/encoded", false)).await.unwrap();
+        assert_eq!(response.headers()[CONTENT_ENCODING], "br");
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(bytes, "encoded".as_bytes());
     }
-    headers.insert(
-        "X-XSS-Protection",
-        HeaderValue::from_static("1; mode=block"),
-    );
-    headers.insert("Referrer-Policy", HeaderValue::from_static("strict-origin"));
-    headers.insert(
-        "X-Content-Type-Options",
-        HeaderValue::from_static("nosniff"),
-    );
-    headers.insert(
-        "Strict-Transport-Security",
-        HeaderValue::from_static("max-age=63072000"),
-    );
-    Ok(())
 }
@@ -0,0 +1,323 @@
+use axum::Json;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+use crate::{
+    apps::{self, App},
+    audit::{self, AuditAction, AuditEvent, AuditPage},
+    bundle::{self, Bundle, BundleFormat, ConflictKind, ImportConflict, ImportMode, ImportReport},
+    compression::Compression,
+    config_history::{self, ConfigVersion, ConfigVersionDiff},
+    cors::Cors,
+    csp_reports::{self, CspViolation},
+    forward_auth,
+    policy::{self, Effect, IpFilter, PolicyDecision, PolicyRule, PolicyTest},
+    security_headers::SecurityHeaders,
+    sysinfo::{self, SystemInfo},
+    tokens::{self, CreatedToken, NewToken, TokenAccess, TokenInfo, TokenScope},
+    totp::{self, TotpCode, TotpDisable, TotpEnrollment, TotpStatus, UserTotp},
+    users::{self, AuthResponse, LocalAuth, PasswordChange, User, UserInfo, AUTH_COOKIE},
+    webauthn::{
+        self, AssertionResponse, AttestationResponse, AuthenticationCredential,
+        AuthenticatorSelection, CreationOptions, CredentialDescriptor, CredentialParameters,
+        LoginStart, Passkey, PublicKeyEntity, PublicKeyUser, RegisterFinish,
+        RegistrationCredential, RequestOptions,
+    },
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Atrium",
+        description = "Authentication of the users and administration of the apps served by Atrium"
+    ),
+    paths(
+        openapi,
+        users::local_auth,
+        forward_auth::verify,
+        csp_reports::csp_report,
+        totp::totp_login,
+        webauthn::register_start,
+        webauthn::register_finish,
+        webauthn::login_start,
+        webauthn::login_finish,
+        users::whoami,
+        sysinfo::system_info,
+        users::get_profile,
+        users::update_profile,
+        users::change_password,
+        tokens::list_tokens,
+        tokens::create_token,
+        tokens::revoke_token,
+        totp::get_totp,
+        totp::enroll_totp,
+        totp::confirm_totp,
+        totp::disable_totp,
+        webauthn::list_passkeys,
+        webauthn::delete_passkey,
+        users::get_users,
+        users::add_user,
+        users::get_user,
+        users::replace_user,
+        users::patch_user,
+        users::delete_user,
+        totp::reset_user_totp,
+        apps::get_apps,
+        apps::add_app,
+        apps::get_app,
+        apps::replace_app,
+        apps::patch_app,
+        apps::delete_app,
+        policy::test_policy,
+        config_history::get_config_history,
+        config_history::get_config_version,
+        config_history::rollback_config,
+        audit::get_audit,
+        csp_reports::get_csp_reports,
+        csp_reports::clear_csp_reports,
+        bundle::export,
+        bundle::import,
+    ),
+    components(schemas(
+        App,
+        PolicyRule,
+        Effect,
+        IpFilter,
+        Cors,
+        SecurityHeaders,
+        Compression,
+        PolicyTest,
+        PolicyDecision,
+        User,
+        UserInfo,
+        LocalAuth,
+        AuthResponse,
+        PasswordChange,
+        UserTotp,
+        TotpCode,
+        TotpDisable,
+        TotpStatus,
+        TotpEnrollment,
+        Passkey,
+        CreationOptions,
+        PublicKeyEntity,
+        PublicKeyUser,
+        CredentialParameters,
+        CredentialDescriptor,
+        AuthenticatorSelection,
+        RegisterFinish,
+        RegistrationCredential,
+        AttestationResponse,
+        LoginStart,
+        RequestOptions,
+        AuthenticationCredential,
+        AssertionResponse,
+        TokenAccess,
+        TokenScope,
+        TokenInfo,
+        NewToken,
+        CreatedToken,
+        SystemInfo,
+        ConfigVersion,
+        ConfigVersionDiff,
+        AuditAction,
+        AuditEvent,
+        AuditPage,
+        CspViolation,
+        Bundle,
+        BundleFormat,
+        ImportMode,
+        ImportReport,
+        ImportConflict,
+        ConflictKind,
+    )),
+    modifiers(&SecuritySchemes)
+)]
+pub struct ApiDoc;
+
+/// Session cookie set by `/auth/local`, which must come with the `xsrf-token` header it returned, or personal token
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "cookie",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                AUTH_COOKIE,
+                "Session cookie, requests must also carry the xsrf-token header returned at login",
+            ))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("Personal token, created at /api/user/tokens"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/openapi.json",
+    tag = "meta",
+    responses((status = 200, description = "This document", content_type = "application/json")),
+)]
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeSet, HashSet},
+        net::SocketAddr,
+        path::PathBuf,
+    };
+
+    use axum::{extract::ConnectInfo, routing::MethodRouter};
+    use http::{Method, Request, StatusCode};
+    use hyper::Body;
+    use tower::ServiceExt;
+    use utoipa::OpenApi;
+
+    use crate::{configuration::Config, openapi::ApiDoc, server::Server};
+
+    const METHODS: [Method; 5] = [
+        Method::GET,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+    ];
+
+    fn spec_operations() -> Vec<(String, HashSet<Method>)> {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        spec["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(path, item)| {
+                let methods = item
+                    .as_object()
+                    .unwrap()
+                    .keys()
+                    .filter_map(|m| Method::from_bytes(m.to_uppercase().as_bytes()).ok())
+                    .collect();
+                (path.to_owned(), methods)
+            })
+            .collect()
+    }
+
+    /// Fills in the path parameters
+    fn uri(path: &str) -> String {
+        path.split('/')
+            .map(|s| if s.starts_with('{') { "1" } else { s })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Whether a path is matched by a documented one, whose parameters match any segment
+    fn matches(path: &str, documented: &str) -> bool {
+        let (segments, expected): (Vec<_>, Vec<_>) =
+            (path.split('/').collect(), documented.split('/').collect());
+        segments.len() == expected.len()
+            && segments
+                .iter()
+                .zip(expected)
+                .all(|(s, e)| e.starts_with('{') || *s == e)
+    }
+
+    /// Router of the server, built from a configuration file in its own directory
+    async fn router(name: &str) -> (PathBuf, MethodRouter) {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("atrium.yaml");
+        Config {
+            hostname: "atrium.io".to_owned(),
+            ..Default::default()
+        }
+        .to_file(file.to_str().unwrap())
+        .await
+        .unwrap();
+        let router = Server::build(file.to_str().unwrap()).await.unwrap().router;
+        (dir, router)
+    }
+
+    async fn status(router: &MethodRouter, method: Method, uri: &str) -> StatusCode {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Host", "atrium.io")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1234))));
+        router.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_spec_documents_all_routes() {
+        let (dir, router) = router("atrium_openapi_test_routes").await;
+        let documented: Vec<String> = spec_operations().into_iter().map(|(p, _)| p).collect();
+        let filled: BTreeSet<String> = documented.iter().map(|p| uri(p)).collect();
+        // Candidate paths are the prefixes of the documented ones, extended by any segment of the specification
+        let segments: BTreeSet<&str> = filled
+            .iter()
+            .flat_map(|p| p.split('/'))
+            .filter(|s| !s.is_empty())
+            .collect();
+        let prefixes: BTreeSet<String> = filled
+            .iter()
+            .flat_map(|p| p.match_indices('/').map(move |(i, _)| p[..i].to_owned()))
+            .chain(filled.iter().cloned())
+            .collect();
+        let candidates: BTreeSet<String> = prefixes
+            .iter()
+            .flat_map(|p| segments.iter().map(move |s| format!("{p}/{s}")))
+            .chain(prefixes.iter().cloned())
+            .filter(|p| !p.is_empty())
+            .collect();
+        for candidate in candidates {
+            // Unrouted paths fall back to the static files, which answer GET with not found
+            let status = status(&router, Method::GET, &candidate).await;
+            assert!(
+                status == StatusCode::NOT_FOUND
+                    || documented.iter().any(|d| matches(&candidate, d)),
+                "{candidate} is routed but not documented"
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spec_methods_match_routes() {
+        let (dir, router) = router("atrium_openapi_test").await;
+        for (path, methods) in spec_operations() {
+            let uri = uri(&path);
+            for method in METHODS {
+                let status = status(&router, method.clone(), &uri).await;
+                if methods.contains(&method) {
+                    assert!(
+                        status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED,
+                        "{method} {path} is documented but not routed"
+                    );
+                } else {
+                    assert_eq!(
+                        status,
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        "{method} {path} is routed but not documented"
+                    );
+                }
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,454 @@
+use std::net::IpAddr;
+
+use axum::{extract::State, Json};
+use http::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    appstate::{ConfigMap, ConfigState},
+    client_ip::ClientIp,
+    configuration::HostType,
+    users::{
+        check_user_has_role_or_forbid, token_restricted, user_to_token, AdminToken, UserToken,
+    },
+    utils::{is_default, normalize_path, vec_trim_remove_empties},
+};
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Effect {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Rule of an app policy, matching the requests that meet all its non empty conditions
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct PolicyRule {
+    pub effect: Effect,
+    /// The user has one of these roles
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub roles: Vec<String>,
+    /// The user has one of these logins
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub logins: Vec<String>,
+    /// The request has one of these HTTP methods
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub methods: Vec<String>,
+    /// The request path matches one of these globs, `*` matching within a path segment and `**` across segments
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub paths: Vec<String>,
+    /// The request comes from one of these IP addresses or CIDR ranges
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub sources: Vec<String>,
+}
+
+/// Request to authorize
+pub struct Access<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub source: IpAddr,
+}
+
+impl PolicyRule {
+    pub fn matches(&self, user: Option<&UserToken>, access: &Access) -> bool {
+        (self.roles.is_empty()
+            || user.is_some_and(|u| u.roles.iter().any(|r| self.roles.contains(r))))
+            && (self.logins.is_empty() || user.is_some_and(|u| self.logins.contains(&u.login)))
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(access.method.as_str())))
+            && (self.paths.is_empty()
+                || self
+                    .paths
+                    .iter()
+                    .any(|p| glob_match(p.as_bytes(), access.path.as_bytes())))
+            && (self.sources.is_empty()
+                || self.sources.iter().any(|s| cidr_contains(s, access.source)))
+    }
+
+    /// Lists the problems of the rule
+    pub fn check(&self) -> Vec<String> {
+        self.sources
+            .iter()
+            .filter(|s| parse_cidr(s).is_none())
+            .map(|s| format!("{s} is not an IP address or CIDR range"))
+            .collect()
+    }
+}
+
+pub(crate) fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != b'/')
+            .any(|i| glob_match(rest, &path[i..])),
+        [b'?', rest @ ..] => {
+            matches!(path.first(), Some(c) if *c != b'/') && glob_match(rest, &path[1..])
+        }
+        [c, rest @ ..] => path.first() == Some(c) && glob_match(rest, &path[1..]),
+    }
+}
+
+pub(crate) fn parse_cidr(cidr: &str) -> Option<(IpAddr, u32)> {
+    let (ip, len) = cidr
+        .split_once('/')
+        .map_or((cidr, None), |(ip, len)| (ip, Some(len)));
+    let ip: IpAddr = ip.parse().ok()?;
+    let bits = if ip.is_ipv4() { 32 } else { 128 };
+    let len = len.map_or(Some(bits), |l| l.parse().ok())?;
+    (len <= bits).then_some((ip, len))
+}
+
+pub(crate) fn cidr_contains(cidr: &str, ip: IpAddr) -> bool {
+    let Some((network, len)) = parse_cidr(cidr) else {
+        return false;
+    };
+    // IPv4 clients of a dual stack listener have mapped addresses
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+    let (network, ip, bits) = match (network, ip) {
+        (IpAddr::V4(n), IpAddr::V4(i)) => (u32::from(n) as u128, u32::from(i) as u128, 32),
+        (IpAddr::V6(n), IpAddr::V6(i)) => (u128::from(n), u128::from(i), 128),
+        _ => return false,
+    };
+    len == 0 || (network ^ ip) >> (bits - len) == 0
+}
+
+/// IP addresses or CIDR ranges allowed and denied, the denied ones taking precedence
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct IpFilter {
+    /// Only these addresses are allowed if not empty
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub allow: Vec<String>,
+    #[serde(
+        default,
+        skip_serializing_if = "is_default",
+        deserialize_with = "vec_trim_remove_empties"
+    )]
+    pub deny: Vec<String>,
+}
+
+impl IpFilter {
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|c| cidr_contains(c, ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|c| cidr_contains(c, ip)))
+    }
+
+    /// Lists the problems of the filter
+    pub fn check(&self) -> Vec<String> {
+        self.allow
+            .iter()
+            .chain(self.deny.iter())
+            .filter(|s| parse_cidr(s).is_none())
+            .map(|s| format!("{s} is not an IP address or CIDR range"))
+            .collect()
+    }
+}
+
+/// Result of the authorization of a request
+#[derive(Debug, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct PolicyDecision {
+    pub allowed: bool,
+    /// 200 if allowed, 401 if the user must log in, 403 if the user is not allowed
+    pub status: u16,
+    /// Index of the first policy rule matching the request, the public paths and roles of the app deciding if none matches
+    pub rule: Option<usize>,
+}
+
+/// Authorizes a request to an app : the first rule of its policy matching the request decides, else its public paths and roles
+pub fn decide(
+    app: &HostType,
+    user: Option<&UserToken>,
+    hostname: &str,
+    access: &Access,
+) -> PolicyDecision {
+    // Rules and public paths match the path as the app will read it, so that `/%61pi` is taken for `/api`
+    // and `/static/../admin` is not taken for a static file. A path that cannot be resolved is refused.
+    let Some(path) = normalize_path(access.path) else {
+        return PolicyDecision {
+            allowed: false,
+            status: status(false, user).as_u16(),
+            rule: None,
+        };
+    };
+    let access = &Access {
+        path: &path,
+        ..*access
+    };
+    let policy = &app.app().policy;
+    let rule = policy.iter().position(|r| r.matches(user, access));
+    // Public paths are reachable by anyone, whatever the restrictions of their token
+    let public = app.app().is_public(access.path);
+    let allowed = match rule.map(|i| &policy[i].effect) {
+        Some(Effect::Deny) => false,
+        // Personal tokens and shares keep their restrictions
+        Some(Effect::Allow) => {
+            public || !user.is_some_and(|u| token_restricted(u, app, hostname, access.path))
+        }
+        None => {
+            public
+                || !app.secured()
+                || check_user_has_role_or_forbid(&user, app, hostname, access.path).is_none()
+        }
+    };
+    PolicyDecision {
+        allowed,
+        status: status(allowed, user).as_u16(),
+        rule,
+    }
+}
+
+fn status(allowed: bool, user: Option<&UserToken>) -> StatusCode {
+    match (allowed, user) {
+        (true, _) => StatusCode::OK,
+        (false, None) => StatusCode::UNAUTHORIZED,
+        (false, Some(_)) => StatusCode::FORBIDDEN,
+    }
+}
+
+fn get() -> String {
+    "GET".to_owned()
+}
+
+/// Request to authorize with the policy of an app
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct PolicyTest {
+    pub app_id: usize,
+    /// Login of the user, anonymous if not set
+    #[serde(default)]
+    pub login: Option<String>,
+    /// Roles of the user, defaulting to the ones of the configured user
+    #[serde(default)]
+    pub roles: Option<Vec<String>>,
+    #[serde(default = "get")]
+    pub method: String,
+    pub path: String,
+    /// Defaults to the address of the caller
+    #[serde(default)]
+    #[schema(value_type = Option<String>)]
+    pub source: Option<IpAddr>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/policy/test",
+    tag = "apps",
+    security(("cookie" = []), ("bearer" = [])),
+    request_body = PolicyTest,
+    responses(
+        (status = 200, description = "Decision that the policy of the app would take", body = PolicyDecision),
+        (status = 400, description = "Method is invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "App or user does not exist"),
+    ),
+)]
+pub async fn test_policy(
+    State(config): State<ConfigState>,
+    State(configmap): State<ConfigMap>,
+    ClientIp(ip): ClientIp,
+    _admin: AdminToken,
+    Json(payload): Json<PolicyTest>,
+) -> Result<Json<PolicyDecision>, (StatusCode, &'static str)> {
+    let app = configmap
+        .values()
+        .find(|a| a.id() == payload.app_id)
+        .ok_or((StatusCode::NOT_FOUND, "app doesn't exist"))?;
+    let method = Method::from_bytes(payload.method.to_uppercase().as_bytes())
+        .map_err(|_| (StatusCode::BAD_REQUEST, "method is invalid"))?;
+    let user = match payload.login {
+        Some(login) => {
+            let mut token = match config.users.iter().find(|u| u.login == login) {
+                Some(user) => user_to_token(user, &config),
+                // Directory users are not known in advance
+                None if payload.roles.is_some() => UserToken {
+                    login,
+                    ..Default::default()
+                },
+                None => return Err((StatusCode::NOT_FOUND, "user does not exist")),
+            };
+            if let Some(roles) = payload.roles {
+                token.roles = roles;
+            }
+            Some(token)
+        }
+        None => None,
+    };
+    let access = Access {
+        method: &method,
+        path: &payload.path,
+        source: payload.source.unwrap_or(ip),
+    };
+    Ok(Json(decide(app, user.as_ref(), app.host(), &access)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use http::Method;
+
+    use crate::{
+        apps::{App, AppWithUri},
+        configuration::HostType,
+        policy::{cidr_contains, decide, glob_match, Access, Effect, IpFilter, PolicyRule},
+        users::UserToken,
+    };
+
+    #[test]
+    fn test_glob_match() {
+        let glob = |p: &str, path: &str| glob_match(p.as_bytes(), path.as_bytes());
+        assert!(glob("/api/*", "/api/users"));
+        assert!(!glob("/api/*", "/api/users/1"));
+        assert!(glob("/api/**", "/api/users/1"));
+        assert!(glob("/**/*.css", "/static/css/main.css"));
+        assert!(glob("/v?/users", "/v1/users"));
+        assert!(!glob("/v?/users", "/v10/users"));
+        assert!(!glob("/admin", "/admin/"));
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(cidr_contains("10.0.0.0/8", ip("10.1.2.3")));
+        assert!(!cidr_contains("10.0.0.0/8", ip("11.1.2.3")));
+        assert!(cidr_contains("10.0.0.0/8", ip("::ffff:10.1.2.3")));
+        assert!(cidr_contains("192.168.1.1", ip("192.168.1.1")));
+        assert!(cidr_contains("0.0.0.0/0", ip("1.2.3.4")));
+        assert!(cidr_contains("fd00::/8", ip("fd12::1")));
+        assert!(!cidr_contains("fd00::/8", ip("10.1.2.3")));
+        assert!(!cidr_contains("10.0.0.0/33", ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut filter = IpFilter::default();
+        assert!(filter.allows(ip("1.2.3.4")));
+        filter.deny = vec!["1.2.3.0/24".to_owned()];
+        assert!(!filter.allows(ip("1.2.3.4")));
+        assert!(filter.allows(ip("10.0.0.1")));
+        filter.allow = vec!["10.0.0.0/8".to_owned(), "1.2.3.4".to_owned()];
+        assert!(!filter.allows(ip("1.2.3.4")));
+        assert!(filter.allows(ip("10.0.0.1")));
+        assert!(!filter.allows(ip("11.0.0.1")));
+        filter.deny.push("not an ip".to_owned());
+        assert_eq!(filter.check().len(), 1);
+    }
+
+    #[test]
+    fn test_decide() {
+        let app = App {
+            target: "www.example.com".to_owned(), // to prevent failing when parsing url
+            secured: true,
+            roles: vec!["USERS".to_owned()],
+            policy: vec![
+                PolicyRule {
+                    effect: Effect::Deny,
+                    methods: vec!["DELETE".to_owned()],
+                    paths: vec!["/api/**".to_owned()],
+                    ..Default::default()
+                },
+                PolicyRule {
+                    effect: Effect::Allow,
+                    sources: vec!["10.0.0.0/8".to_owned()],
+                    paths: vec!["/public/**".to_owned()],
+                    ..Default::default()
+                },
+                PolicyRule {
+                    effect: Effect::Allow,
+                    logins: vec!["guest".to_owned()],
+                    methods: vec!["get".to_owned()],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let app = HostType::ReverseApp(Box::new(AppWithUri::from_app_domain_and_http_port(
+            app,
+            "atrium.io",
+            None,
+        )));
+        let user = |login: &str, roles: &[&str]| UserToken {
+            login: login.to_owned(),
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        };
+        let decide = |user: Option<&UserToken>, method: Method, path: &str, source: &str| {
+            let access = Access {
+                method: &method,
+                path,
+                source: source.parse().unwrap(),
+            };
+            let decision = decide(&app, user, "app.atrium.io", &access);
+            (decision.status, decision.rule)
+        };
+        let member = user("member", &["USERS"]);
+        let guest = user("guest", &[]);
+
+        assert_eq!(
+            decide(Some(&member), Method::DELETE, "/api/item", "1.2.3.4"),
+            (403, Some(0))
+        );
+        // Encoded and dotted paths are matched as the app will read them
+        for path in ["/%61pi/item", "//api/./item", "/public/../api/item"] {
+            assert_eq!(
+                decide(Some(&member), Method::DELETE, path, "1.2.3.4"),
+                (403, Some(0))
+            );
+        }
+        assert_eq!(
+            decide(Some(&member), Method::GET, "/../api/item", "1.2.3.4"),
+            (403, None)
+        );
+        assert_eq!(
+            decide(Some(&member), Method::GET, "/api/item", "1.2.3.4"),
+            (200, None)
+        );
+        assert_eq!(
+            decide(None, Method::GET, "/public/a", "10.0.0.1"),
+            (200, Some(1))
+        );
+        assert_eq!(
+            decide(None, Method::GET, "/public/a", "1.2.3.4"),
+            (401, None)
+        );
+        assert_eq!(
+            decide(Some(&guest), Method::GET, "/", "1.2.3.4"),
+            (200, Some(2))
+        );
+        assert_eq!(
+            decide(Some(&guest), Method::POST, "/", "1.2.3.4"),
+            (403, None)
+        );
+    }
+}
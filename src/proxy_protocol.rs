@@ -0,0 +1,183 @@
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    time::Duration,
+};
+
+use axum::{extract::FromRef, middleware::AddExtension, Extension};
+use axum_server::accept::Accept;
+use hyper::server::conn::AddrStream;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tower::Layer;
+
+use crate::{
+    appstate::{AppState, ConfigState},
+    client_ip::is_trusted_proxy,
+};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Address of the client given by a trusted proxy with the PROXY protocol, or else the peer address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedPeer(pub SocketAddr);
+
+/// Reads the PROXY protocol (v1 or v2) header at the start of the stream, returning the address of the client if the proxy gives it
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // A v1 header is at least as long as the v2 signature
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let mut payload = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut payload).await?;
+        return parse_v2(header[0], header[1], &payload);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+    // Read byte per byte, so that the request following the header stays in the stream
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line)
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("invalid PROXY protocol header"))?;
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol source address"))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY protocol header")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // LOCAL connections are the ones of the proxy itself, as health checks
+    if version_command & 0x0F == 0 {
+        return Ok(None);
+    }
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip: [u8; 4] = payload[..4].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port(8))))
+        }
+        2 if payload.len() >= 36 => {
+            let ip: [u8; 16] = payload[..16].try_into().unwrap();
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port(32))))
+        }
+        // Unix sockets and unspecified families do not give a usable address
+        _ => Ok(None),
+    }
+}
+
+/// Reads the PROXY protocol header of the connections of the trusted proxies, if enabled in the configuration
+#[derive(Clone)]
+pub struct ProxyProtocolAcceptor {
+    state: AppState,
+}
+
+impl ProxyProtocolAcceptor {
+    pub fn new(state: AppState) -> Self {
+        ProxyProtocolAcceptor { state }
+    }
+}
+
+impl<S: Send + 'static> Accept<AddrStream, S> for ProxyProtocolAcceptor {
+    type Stream = AddrStream;
+    type Service = AddExtension<S, ProxiedPeer>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, mut stream: AddrStream, service: S) -> Self::Future {
+        let config = ConfigState::from_ref(&self.state);
+        Box::pin(async move {
+            let peer = stream.remote_addr();
+            let mut client = peer;
+            if config.proxy_protocol && is_trusted_proxy(&config, peer.ip()) {
+                let header = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "no PROXY protocol header")
+                    })?;
+                if let Some(addr) = header? {
+                    client = addr;
+                }
+            }
+            Ok((stream, Extension(ProxiedPeer(client)).layer(service)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::AsyncReadExt;
+
+    use crate::proxy_protocol::read_header;
+
+    #[tokio::test]
+    async fn test_v1() {
+        let mut stream: &[u8] =
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET / HTTP/1.1\r\n";
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(
+            addr,
+            Some("192.168.0.1:56324".parse::<SocketAddr>().unwrap())
+        );
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n";
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(
+            addr,
+            Some("[2001:db8::1]:4711".parse::<SocketAddr>().unwrap())
+        );
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: atrium.io\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0C".to_vec();
+        header.extend([10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0x01, 0xBB]);
+        header.extend(b"GET");
+        let mut stream: &[u8] = &header;
+        let addr = read_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("10.0.0.1:8080".parse::<SocketAddr>().unwrap()));
+        assert_eq!(stream, b"GET");
+
+        // LOCAL command
+        let mut stream: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+    }
+}
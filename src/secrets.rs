@@ -0,0 +1,197 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::AsyncWriteExt;
+
+use crate::utils::{random_string, TrimInPlace};
+
+static ENV_REFERENCE: &str = "env:";
+static FILE_REFERENCE: &str = "file:";
+
+/// A sensitive configuration value.
+///
+/// In the configuration file, it can either be written as is, or as a reference to its actual value :
+/// `env:NAME` reads the environment variable `NAME` and `file:/path` reads the content of the file at `/path`.
+/// References are resolved by `Config::resolve_secrets` and preserved when the configuration is saved.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret {
+    value: String,
+    reference: Option<String>,
+}
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret {
+            value,
+            reference: None,
+        }
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty() && self.reference.is_none()
+    }
+
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    /// Reads the value of a referenced secret, does nothing for a plain one
+    pub fn resolve(&mut self) -> Result<()> {
+        let Some(reference) = &self.reference else {
+            return Ok(());
+        };
+        self.value = if let Some(name) = reference.strip_prefix(ENV_REFERENCE) {
+            std::env::var(name).map_err(|_| anyhow!("environment variable {name} is not set"))?
+        } else if let Some(path) = reference.strip_prefix(FILE_REFERENCE) {
+            let content = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("could not read secret file {path}: {e}"))?;
+            content.trim_end_matches(['\r', '\n']).to_owned()
+        } else {
+            return Err(anyhow!("unknown secret reference {reference}"));
+        };
+        Ok(())
+    }
+
+    /// Moves a plain value out of the configuration : it is written to a new file in `dir`, named after `name`, and replaced by a reference to that file.
+    ///
+    /// Files are never rewritten, so that a crash cannot leave a truncated secret and older versions of the configuration keep theirs.
+    /// Returns the path of the file, if one was written.
+    pub async fn persist(&mut self, dir: &Path, name: &str) -> Result<Option<PathBuf>> {
+        if self.reference.is_some() || self.value.is_empty() {
+            return Ok(None);
+        }
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|e| anyhow!("could not create secrets directory: {e}"))?;
+        let path = dir.join(format!("{name}_{}", random_string(16)));
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let write = async {
+            let mut file = options.open(&path).await?;
+            file.write_all(self.value.as_bytes()).await?;
+            file.sync_all().await
+        };
+        if let Err(e) = write.await {
+            let _ = tokio::fs::remove_file(&path).await;
+            return Err(anyhow!("could not write secret file: {e}"));
+        }
+        // Sync the directory so that the file is durable before the configuration references it
+        #[cfg(unix)]
+        if let Ok(dir) = tokio::fs::File::open(dir).await {
+            let _ = dir.sync_all().await;
+        }
+        self.reference = Some(format!("{FILE_REFERENCE}{}", path.to_string_lossy()));
+        Ok(Some(path))
+    }
+}
+
+/// Directory where the secrets set through atrium (and not by hand) are stored, next to the configuration file
+pub fn secrets_dir(config_file: &str) -> PathBuf {
+    Path::new(config_file).with_file_name("secrets")
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.reference {
+            Some(reference) => write!(f, "Secret({reference})"),
+            None => write!(f, "Secret(REDACTED)"),
+        }
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.reference {
+            Some(reference) => serializer.serialize_str(reference),
+            None => serializer.serialize_str(&self.value),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let mut raw = String::deserialize(d)?;
+        raw.trim_in_place();
+        if raw.starts_with(ENV_REFERENCE) || raw.starts_with(FILE_REFERENCE) {
+            if raw == ENV_REFERENCE || raw == FILE_REFERENCE {
+                return Err(de::Error::custom("empty secret reference"));
+            }
+            Ok(Secret {
+                value: String::new(),
+                reference: Some(raw),
+            })
+        } else {
+            Ok(Secret::new(raw))
+        }
+    }
+}
+
+/// Deserializes an optional secret, an empty value meaning no secret
+pub fn option_secret<'de, D>(d: D) -> Result<Option<Secret>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let secret: Option<Secret> = Option::deserialize(d)?;
+    Ok(secret.filter(|s| !s.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Secret;
+
+    #[test]
+    fn test_plain_secret() {
+        let secret: Secret = serde_yaml::from_str(" plain ").unwrap();
+        assert_eq!(secret.expose(), "plain");
+        assert!(secret.reference().is_none());
+        assert_eq!(serde_yaml::to_string(&secret).unwrap(), "plain\n");
+        assert_eq!(format!("{secret:?}"), "Secret(REDACTED)");
+    }
+
+    #[test]
+    fn test_env_secret() {
+        std::env::set_var("SECRETS_TEST_ENV", "from_env");
+        let mut secret: Secret = serde_yaml::from_str("env:SECRETS_TEST_ENV").unwrap();
+        assert_eq!(secret.expose(), "");
+        secret.resolve().unwrap();
+        assert_eq!(secret.expose(), "from_env");
+        // The reference, not the value, is written back
+        assert_eq!(
+            serde_yaml::to_string(&secret).unwrap(),
+            "env:SECRETS_TEST_ENV\n"
+        );
+        let mut missing: Secret = serde_yaml::from_str("env:SECRETS_TEST_MISSING").unwrap();
+        assert!(missing.resolve().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_persisted_secret() {
+        let dir = std::env::temp_dir().join("atrium_secrets_test");
+        let mut secret = Secret::new("from_file".to_owned());
+        let path = secret.persist(&dir, "test_secret").await.unwrap().unwrap();
+        let reference = secret.reference().unwrap().to_owned();
+        assert!(reference.starts_with("file:"));
+        let mut read: Secret = serde_yaml::from_str(&reference).unwrap();
+        read.resolve().unwrap();
+        assert_eq!(read.expose(), "from_file");
+        // A referenced secret is not written again
+        assert!(secret.persist(&dir, "test_secret").await.unwrap().is_none());
+        // A new value goes to a new file, the previous one being kept
+        let mut other = Secret::new("other".to_owned());
+        let other_path = other.persist(&dir, "test_secret").await.unwrap().unwrap();
+        assert_ne!(path, other_path);
+        read.resolve().unwrap();
+        assert_eq!(read.expose(), "from_file");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+
+use http::{HeaderMap, HeaderValue, StatusCode};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::configuration::Config;
+
+pub static DEFAULT_PROFILE: &str = "default";
+/// Replaced in the CSP directives by the sources of atrium and of its apps
+static ATRIUM_SOURCE: &str = "'atrium'";
+static CSP: &str = "Content-Security-Policy";
+static CSP_REPORT_ONLY: &str = "Content-Security-Policy-Report-Only";
+/// Name of the endpoint of atrium in the Reporting-Endpoints header
+static REPORT_ENDPOINT: &str = "csp-endpoint";
+
+/// Security headers of the responses of atrium and of the apps referencing the profile, the empty ones not being sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(default)]
+pub struct SecurityHeaders {
+    /// Directives of the Content-Security-Policy forged if the response has none, `'atrium'` standing for atrium and its apps
+    pub csp: BTreeMap<String, String>,
+    /// Allows atrium and its apps to frame the response, even if it has its own Content-Security-Policy
+    pub frame_ancestors: bool,
+    /// Sends the violations of the forged Content-Security-Policy to atrium, at /api/csp-report
+    pub report: bool,
+    /// Only reports the violations of the forged Content-Security-Policy, to try it before enforcing it
+    pub report_only: bool,
+    /// Only sent over TLS
+    pub hsts: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub cross_origin_opener_policy: String,
+    pub cross_origin_embedder_policy: String,
+    pub xss_protection: String,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        let csp = [
+            (
+                "default-src",
+                "'self' 'atrium' https://unpkg.com https://fonts.gstatic.com",
+            ),
+            (
+                "script-src",
+                "'self' 'atrium' 'wasm-unsafe-eval' https://cdn.jsdelivr.net https://unpkg.com",
+            ),
+            ("style-src", "'self' 'atrium' 'unsafe-inline'"),
+            ("frame-src", "'atrium'"),
+            ("frame-ancestors", "'atrium'"),
+            ("img-src", "'self' 'atrium' blob:"),
+        ];
+        SecurityHeaders {
+            csp: csp
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            frame_ancestors: true,
+            report: true,
+            report_only: false,
+            hsts: "max-age=63072000".to_owned(),
+            referrer_policy: "strict-origin".to_owned(),
+            permissions_policy: String::new(),
+            cross_origin_opener_policy: String::new(),
+            cross_origin_embedder_policy: String::new(),
+            xss_protection: "1; mode=block".to_owned(),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// Sets the headers of the profile on the response
+    pub fn inject(&self, headers: &mut HeaderMap, config: &Config) -> Result<(), StatusCode> {
+        let source = format!(
+            "{s}://{h}:* {s}://*.{h}:*",
+            s = config.scheme(),
+            h = config.domain,
+        );
+        let report_uri = format!("{}/api/csp-report", config.full_hostname());
+        let mut values = match headers.get(CSP).and_then(|h| h.to_str().ok()) {
+            Some(csp) => vec![(CSP, self.merge_csp(csp, &source))],
+            None if self.csp.is_empty() => vec![],
+            None => vec![
+                (
+                    if self.report_only {
+                        CSP_REPORT_ONLY
+                    } else {
+                        CSP
+                    },
+                    self.forge_csp(&source, &report_uri),
+                ),
+                (
+                    "Reporting-Endpoints",
+                    if self.report {
+                        format!("{REPORT_ENDPOINT}=\"{report_uri}\"")
+                    } else {
+                        String::new()
+                    },
+                ),
+            ],
+        };
+        // HSTS is ignored by browsers over plain HTTP, and would break the HTTP ports of the apps
+        if config.tls_mode.is_secure() {
+            values.push(("Strict-Transport-Security", self.hsts.clone()));
+        }
+        values.extend([
+            ("Referrer-Policy", self.referrer_policy.clone()),
+            ("Permissions-Policy", self.permissions_policy.clone()),
+            (
+                "Cross-Origin-Opener-Policy",
+                self.cross_origin_opener_policy.clone(),
+            ),
+            (
+                "Cross-Origin-Embedder-Policy",
+                self.cross_origin_embedder_policy.clone(),
+            ),
+            ("X-XSS-Protection", self.xss_protection.clone()),
+        ]);
+        for (name, value) in values.into_iter().filter(|(_, v)| !v.is_empty()) {
+            headers.insert(
+                name,
+                HeaderValue::from_str(&value).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            );
+        }
+        headers.insert(
+            "X-Content-Type-Options",
+            HeaderValue::from_static("nosniff"),
+        );
+        Ok(())
+    }
+
+    /// Alters the CSP of the response to inject atrium in the authorized frame ancestors
+    fn merge_csp(&self, csp: &str, source: &str) -> String {
+        if !self.frame_ancestors {
+            csp.to_owned()
+        } else if csp.contains("frame-ancestors") {
+            csp.replacen("frame-ancestors", &format!("frame-ancestors {source}"), 1)
+        } else {
+            format!("{csp}; frame-ancestors {source}")
+        }
+    }
+
+    fn forge_csp(&self, source: &str, report_uri: &str) -> String {
+        let mut directives: Vec<String> = self
+            .csp
+            .iter()
+            .map(|(name, sources)| {
+                format!("{name} {}", sources.replace(ATRIUM_SOURCE, source))
+                    .trim()
+                    .to_owned()
+            })
+            .collect();
+        if self.report {
+            directives.push(format!("report-uri {report_uri}"));
+            directives.push(format!("report-to {REPORT_ENDPOINT}"));
+        }
+        directives.join("; ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderMap;
+
+    use crate::{
+        configuration::{Config, TlsMode},
+        security_headers::SecurityHeaders,
+    };
+
+    fn inject(profile: &SecurityHeaders, csp: Option<&str>, tls_mode: TlsMode) -> HeaderMap {
+        let config = Config {
+            hostname: "atrium.io".to_owned(),
+            domain: "atrium.io".to_owned(),
+            http_port: 8080,
+            tls_mode,
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        if let Some(csp) = csp {
+            headers.insert("Content-Security-Policy", csp.parse().unwrap());
+        }
+        profile.inject(&mut headers, &config).unwrap();
+        headers
+    }
+
+    #[test]
+    fn test_forged_csp() {
+        let headers = inject(&SecurityHeaders::default(), None, TlsMode::Auto);
+        assert_eq!(
+            headers["Content-Security-Policy"],
+            "default-src 'self' https://atrium.io:* https://*.atrium.io:* https://unpkg.com https://fonts.gstatic.com; \
+            frame-ancestors https://atrium.io:* https://*.atrium.io:*; \
+            frame-src https://atrium.io:* https://*.atrium.io:*; \
+            img-src 'self' https://atrium.io:* https://*.atrium.io:* blob:; \
+            script-src 'self' https://atrium.io:* https://*.atrium.io:* 'wasm-unsafe-eval' https://cdn.jsdelivr.net https://unpkg.com; \
+            style-src 'self' https://atrium.io:* https://*.atrium.io:* 'unsafe-inline'; \
+            report-uri https://atrium.io/api/csp-report; \
+            report-to csp-endpoint"
+        );
+        assert_eq!(
+            headers["Reporting-Endpoints"],
+            "csp-endpoint=\"https://atrium.io/api/csp-report\""
+        );
+        assert_eq!(headers["Strict-Transport-Security"], "max-age=63072000");
+        assert_eq!(headers["Referrer-Policy"], "strict-origin");
+        assert_eq!(headers["X-Content-Type-Options"], "nosniff");
+        assert!(headers.get("Permissions-Policy").is_none());
+
+        let profile = SecurityHeaders {
+            csp: [("default-src", "'self'"), ("upgrade-insecure-requests", "")]
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            report: false,
+            permissions_policy: "camera=(), microphone=()".to_owned(),
+            cross_origin_opener_policy: "same-origin".to_owned(),
+            ..Default::default()
+        };
+        let headers = inject(&profile, None, TlsMode::No);
+        assert_eq!(
+            headers["Content-Security-Policy"],
+            "default-src 'self'; upgrade-insecure-requests"
+        );
+        assert!(headers.get("Reporting-Endpoints").is_none());
+        assert_eq!(headers["Permissions-Policy"], "camera=(), microphone=()");
+        assert_eq!(headers["Cross-Origin-Opener-Policy"], "same-origin");
+        assert!(headers.get("Strict-Transport-Security").is_none());
+    }
+
+    #[test]
+    fn test_report_only_csp() {
+        let profile = SecurityHeaders {
+            csp: [("default-src", "'self' 'atrium'")]
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            report_only: true,
+            ..Default::default()
+        };
+        let headers = inject(&profile, None, TlsMode::No);
+        assert!(headers.get("Content-Security-Policy").is_none());
+        assert_eq!(
+            headers["Content-Security-Policy-Report-Only"],
+            "default-src 'self' http://atrium.io:* http://*.atrium.io:*; \
+            report-uri http://atrium.io:8080/api/csp-report; \
+            report-to csp-endpoint"
+        );
+        // The policy of the app stays enforced
+        let headers = inject(&profile, Some("default-src 'self'"), TlsMode::No);
+        assert!(headers.get("Content-Security-Policy-Report-Only").is_none());
+        assert_eq!(
+            headers["Content-Security-Policy"],
+            "default-src 'self'; frame-ancestors http://atrium.io:* http://*.atrium.io:*"
+        );
+    }
+
+    #[test]
+    fn test_merged_csp() {
+        let profile = SecurityHeaders::default();
+        let headers = inject(
+            &profile,
+            Some("default-src 'self'; frame-ancestors 'self'"),
+            TlsMode::Auto,
+        );
+        assert_eq!(
+            headers["Content-Security-Policy"],
+            "default-src 'self'; frame-ancestors https://atrium.io:* https://*.atrium.io:* 'self'"
+        );
+        let headers = inject(&profile, Some("default-src 'self'"), TlsMode::Auto);
+        assert_eq!(
+            headers["Content-Security-Policy"],
+            "default-src 'self'; frame-ancestors https://atrium.io:* https://*.atrium.io:*"
+        );
+
+        let profile = SecurityHeaders {
+            frame_ancestors: false,
+            ..Default::default()
+        };
+        let headers = inject(&profile, Some("default-src 'self'"), TlsMode::Auto);
+        assert_eq!(headers["Content-Security-Policy"], "default-src 'self'");
+    }
+}
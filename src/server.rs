@@ -1,8 +1,10 @@
 use axum::{
+    extract::State,
+    handler::Handler,
     middleware,
     response::IntoResponse,
-    routing::{delete, get, get_service, post},
-    Router, handler::Handler,
+    routing::{delete, get, get_service, post, put, MethodRouter},
+    Router,
 };
 
 use http::StatusCode;
@@ -10,31 +12,54 @@ use hyper::{Body, Request};
 
 use tower::ServiceExt;
 
-use tower_http::services::ServeDir;
+use tower_http::{compression::CompressionLayer, services::ServeDir};
 
 use crate::{
-    apps::{add_app, delete_app, get_apps, proxy_handler},
-    appstate::AppState,
+    apps::{add_app, delete_app, get_app, get_apps, patch_app, proxy_handler, replace_app},
+    appstate::{AppState, ConfigState},
+    audit::get_audit,
+    bundle::{export, import},
+    compression::ConfiguredCompression,
+    config_history::{get_config_history, get_config_version, rollback_config},
     configuration::{load_config, HostType},
+    csp_reports::{clear_csp_reports, csp_report, get_csp_reports},
     dir_server::dir_handler,
-    middlewares::inject_security_headers,
+    forward_auth::{is_verify_request, verify},
+    middlewares::{cors_middleware, filter_ips, inject_security_headers, select_compression},
+    openapi::openapi,
+    policy::test_policy,
+    proxy_protocol::ProxyProtocolAcceptor,
     sysinfo::system_info,
-    users::{add_user, delete_user, get_users, local_auth, whoami},
+    tokens::{create_token, list_tokens, revoke_token},
+    totp::{confirm_totp, disable_totp, enroll_totp, get_totp, reset_user_totp, totp_login},
+    users::{
+        add_user, change_password, delete_user, get_profile, get_user, get_users, local_auth,
+        patch_user, replace_user, update_profile, whoami,
+    },
+    webauthn::{
+        delete_passkey, list_passkeys, login_finish, login_start, register_finish, register_start,
+    },
 };
 
 pub struct Server {
     pub router: axum::routing::MethodRouter,
     pub port: u16,
+    pub acceptor: ProxyProtocolAcceptor,
 }
 
 impl Server {
     pub async fn build(config_file: &str) -> Result<Self, anyhow::Error> {
         let config = load_config(config_file).await?;
+        let port = config.0.http_port;
+        let key = config.0.cookie_key.as_ref().unwrap().expose();
+        if key.len() < 64 {
+            return Err(anyhow::anyhow!(
+                "cookie_key must be at least 64 characters long"
+            ));
+        }
 
         let state = AppState::new(
-            axum_extra::extract::cookie::Key::from(
-                config.0.cookie_key.as_ref().unwrap().as_bytes(),
-            ),
+            axum_extra::extract::cookie::Key::from(key.as_bytes()),
             config.0,
             config.1,
             config_file.to_owned(),
@@ -42,16 +67,62 @@ impl Server {
 
         let user_router: Router<AppState> = Router::new()
             .route("/api/user/whoami", get(whoami))
-            .route("/api/user/system_info", get(system_info));
+            .route("/api/user/system_info", get(system_info))
+            .route("/api/user/profile", get(get_profile).put(update_profile))
+            .route("/api/user/password", put(change_password))
+            .route("/api/user/tokens", get(list_tokens).post(create_token))
+            .route("/api/user/tokens/:token_id", delete(revoke_token))
+            .route(
+                "/api/user/totp",
+                get(get_totp).post(enroll_totp).delete(disable_totp),
+            )
+            .route("/api/user/totp/confirm", post(confirm_totp))
+            .route("/api/user/passkeys", get(list_passkeys))
+            .route("/api/user/passkeys/:passkey_id", delete(delete_passkey));
 
         let admin_router = Router::new()
             .route("/api/admin/users", get(get_users).post(add_user))
-            .route("/api/admin/users/:user_login", delete(delete_user))
+            .route(
+                "/api/admin/users/:user_login",
+                get(get_user)
+                    .put(replace_user)
+                    .patch(patch_user)
+                    .delete(delete_user),
+            )
+            .route("/api/admin/users/:user_login/totp", delete(reset_user_totp))
             .route("/api/admin/apps", get(get_apps).post(add_app))
-            .route("/api/admin/apps/:app_id", delete(delete_app));
+            .route(
+                "/api/admin/apps/:app_id",
+                get(get_app)
+                    .put(replace_app)
+                    .patch(patch_app)
+                    .delete(delete_app),
+            )
+            .route("/api/admin/policy/test", post(test_policy))
+            .route("/api/admin/config/history", get(get_config_history))
+            .route(
+                "/api/admin/config/history/:version",
+                get(get_config_version),
+            )
+            .route("/api/admin/config/rollback/:version", post(rollback_config))
+            .route("/api/admin/audit", get(get_audit))
+            .route(
+                "/api/admin/csp-reports",
+                get(get_csp_reports).delete(clear_csp_reports),
+            )
+            .route("/api/admin/export", get(export))
+            .route("/api/admin/import", post(import));
 
         let main_router: Router<()> = Router::new()
             .route("/auth/local", post(local_auth))
+            .route("/auth/verify", get(verify))
+            .route("/auth/local/totp", post(totp_login))
+            .route("/auth/webauthn/register/start", post(register_start))
+            .route("/auth/webauthn/register/finish", post(register_finish))
+            .route("/auth/webauthn/login/start", post(login_start))
+            .route("/auth/webauthn/login/finish", post(login_finish))
+            .route("/api/openapi.json", get(openapi))
+            .route("/api/csp-report", post(csp_report))
             .merge(admin_router)
             .merge(user_router)
             .fallback_service(get_service(ServeDir::new("web")).handle_error(error_500))
@@ -61,8 +132,13 @@ impl Server {
 
         let dir_router = dir_handler.with_state(state.clone());
 
-        let router = axum::routing::any(
-            |hostype: Option<HostType>, request: Request<Body>| async move {
+        let router: MethodRouter<AppState> = axum::routing::any(
+            |hostype: Option<HostType>,
+             State(config): State<ConfigState>,
+             request: Request<Body>| async move {
+                if is_verify_request(&request, &config.hostname) {
+                    return main_router.oneshot(request).await;
+                }
                 match hostype {
                     Some(HostType::StaticApp(_)) => dir_router.oneshot(request).await,
                     Some(HostType::ReverseApp(_)) => proxy_router.oneshot(request).await,
@@ -74,9 +150,25 @@ impl Server {
             state.clone(),
             inject_security_headers,
         ))
-        .with_state(state);
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            cors_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            select_compression,
+        ));
+        // The error type of the compression layer follows its inner service, which must be known by then
+        let router = router
+            .layer(CompressionLayer::new().compress_when(ConfiguredCompression))
+            .layer(middleware::from_fn_with_state(state.clone(), filter_ips))
+            .with_state(state.clone());
 
-        Ok(Server { router, port: 8080 })
+        Ok(Server {
+            router,
+            port,
+            acceptor: ProxyProtocolAcceptor::new(state),
+        })
     }
 }
 
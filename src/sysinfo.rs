@@ -9,13 +9,14 @@ use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use sysinfo::{CpuExt, DiskExt, System, SystemExt};
 use tokio::task;
+use utoipa::ToSchema;
 
 use crate::users::UserToken;
 
 static SYSTEM_INFO: Lazy<Arc<Mutex<System>>> =
     Lazy::new(|| Arc::new(Mutex::new(System::new_all())));
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct SystemInfo {
     pub total_memory: u64,
     pub used_memory: u64,
@@ -77,6 +78,16 @@ fn corresponding_disk_info(
         .ok_or("no disks found")
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/user/system_info",
+    tag = "user",
+    security(("cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Memory, processor and uptime of the server", body = SystemInfo),
+        (status = 401, description = "User is not authenticated"),
+    ),
+)]
 pub async fn system_info(_user: UserToken) -> Result<Json<SystemInfo>, (StatusCode, &'static str)> {
     let sysinfo = task::spawn_blocking(|| {
         let mut sys = SYSTEM_INFO.lock().map_err(|_| {
@@ -86,7 +97,7 @@ pub async fn system_info(_user: UserToken) -> Result<Json<SystemInfo>, (StatusCo
             )
         })?;
         sys.refresh_system();
-        Ok(SystemInfo {
+        Ok::<_, (StatusCode, &'static str)>(SystemInfo {
             total_memory: sys.total_memory(),
             used_memory: sys.used_memory(),
             cpu_usage: sys.global_cpu_info().cpu_usage(),
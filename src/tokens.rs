@@ -0,0 +1,495 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Path as UrlPath, State},
+    Json,
+};
+use http::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+use crate::{
+    appstate::ConfigState,
+    audit::{AuditAction, AuditEvent, AuditLog},
+    client_ip::ClientIp,
+    configuration::{write_file_atomically, Config},
+    users::{session_roles, UserToken},
+    utils::random_string,
+};
+
+pub static TOKEN_PREFIX: &str = "atr_";
+const ID_LENGTH: usize = 12;
+const SECRET_LENGTH: usize = 32;
+/// Longest lifetime of an expiring token
+const MAX_EXPIRY_DAYS: i64 = 3650;
+
+/// File where the personal tokens are kept, next to the configuration file.
+///
+/// They are kept out of the configuration, so that rolling back or importing a configuration cannot bring back a revoked token.
+pub fn tokens_file(config_file: &str) -> PathBuf {
+    Path::new(config_file).with_file_name("tokens.json")
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenAccess {
+    /// Only safe methods (GET, HEAD, OPTIONS) are allowed
+    #[default]
+    Read,
+    Write,
+}
+
+/// What a personal token gives access to, within the rights of its user
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TokenScope {
+    /// Roles of the user the token carries, all of them if empty
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Ids of the apps the token gives access to, all of them if empty
+    #[serde(default)]
+    pub apps: Vec<usize>,
+    #[serde(default)]
+    pub access: TokenAccess,
+}
+
+impl TokenScope {
+    pub fn allows_method(&self, method: &Method) -> bool {
+        self.access == TokenAccess::Write || method.is_safe()
+    }
+
+    pub fn allows_app(&self, app_id: usize) -> bool {
+        self.apps.is_empty() || self.apps.contains(&app_id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersonalToken {
+    pub id: String,
+    pub login: String,
+    pub name: String,
+    /// SHA-256 of the secret part of the token, which is random enough not to need a slow hash
+    pub hash: String,
+    pub scope: TokenScope,
+    pub created: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+}
+
+/// Personal token as shown to its user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+}
+
+impl From<&PersonalToken> for TokenInfo {
+    fn from(token: &PersonalToken) -> Self {
+        TokenInfo {
+            id: token.id.clone(),
+            name: token.name.clone(),
+            scope: token.scope.clone(),
+            created: token.created,
+            expires: token.expires,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewToken {
+    pub name: String,
+    #[serde(default)]
+    pub scope: TokenScope,
+    /// The token never expires if absent, at most 3650 days
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub info: TokenInfo,
+    /// The token itself, it is shown only once
+    pub token: String,
+}
+
+pub(crate) fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Store of the personal tokens of all the users
+#[derive(Clone)]
+pub struct TokenStore {
+    file: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl TokenStore {
+    pub fn new(file: PathBuf) -> Self {
+        TokenStore {
+            file,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    async fn load(&self) -> Result<Vec<PersonalToken>> {
+        match tokio::fs::read_to_string(&self.file).await {
+            Ok(data) => serde_json::from_str(&data)
+                .map_err(|e| anyhow!("could not parse personal tokens: {e}")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(anyhow!("could not read personal tokens: {e}")),
+        }
+    }
+
+    async fn save(&self, tokens: &[PersonalToken]) -> Result<()> {
+        let data = serde_json::to_string_pretty(tokens)
+            .map_err(|e| anyhow!("could not serialize personal tokens: {e}"))?;
+        write_file_atomically(&self.file.to_string_lossy(), data.as_bytes()).await
+    }
+
+    pub async fn list(&self, login: &str) -> Result<Vec<PersonalToken>> {
+        Ok(self
+            .load()
+            .await?
+            .into_iter()
+            .filter(|t| t.login == login)
+            .collect())
+    }
+
+    /// Creates a token, returning it along with its secret value
+    pub async fn create(&self, login: &str, new: NewToken) -> Result<(PersonalToken, String)> {
+        let _guard = self.lock.lock().await;
+        let mut tokens = self.load().await?;
+        let id = random_string(ID_LENGTH);
+        let secret = random_string(SECRET_LENGTH);
+        let now = OffsetDateTime::now_utc();
+        let expires = match new.expires_in_days {
+            Some(days) => Some(
+                (1..=MAX_EXPIRY_DAYS)
+                    .contains(&days)
+                    .then(|| now.checked_add(Duration::days(days)))
+                    .flatten()
+                    .ok_or_else(|| anyhow!("token expiry is out of range"))?
+                    .unix_timestamp(),
+            ),
+            None => None,
+        };
+        let token = PersonalToken {
+            id: id.clone(),
+            login: login.to_owned(),
+            name: new.name,
+            hash: hash_secret(&secret),
+            scope: new.scope,
+            created: now.unix_timestamp(),
+            expires,
+        };
+        tokens.push(token.clone());
+        self.save(&tokens).await?;
+        Ok((token, format!("{TOKEN_PREFIX}{id}_{secret}")))
+    }
+
+    /// Revokes a token of a user, returning whether it existed
+    pub async fn revoke(&self, login: &str, id: &str) -> Result<bool> {
+        let _guard = self.lock.lock().await;
+        let mut tokens = self.load().await?;
+        let count = tokens.len();
+        tokens.retain(|t| !(t.login == login && t.id == id));
+        if tokens.len() == count {
+            return Ok(false);
+        }
+        self.save(&tokens).await?;
+        Ok(true)
+    }
+
+    /// Revokes all the tokens of a user
+    pub async fn revoke_all(&self, login: &str) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut tokens = self.load().await?;
+        let count = tokens.len();
+        tokens.retain(|t| t.login != login);
+        if tokens.len() != count {
+            self.save(&tokens).await?;
+        }
+        Ok(())
+    }
+
+    /// Finds the valid token matching a bearer value
+    pub async fn find(&self, bearer: &str) -> Option<PersonalToken> {
+        let (id, secret) = bearer.strip_prefix(TOKEN_PREFIX)?.split_once('_')?;
+        let hash = hash_secret(secret);
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        self.load().await.ok()?.into_iter().find(|t| {
+            t.id == id
+                && constant_time_eq(t.hash.as_bytes(), hash.as_bytes())
+                && t.expires.is_none_or(|e| now <= e)
+        })
+    }
+
+    /// Works out the user token of a request authenticated by a personal token
+    pub async fn authenticate(
+        &self,
+        config: &Config,
+        bearer: &str,
+        method: &Method,
+    ) -> Result<UserToken, (StatusCode, &'static str)> {
+        let token = self
+            .find(bearer)
+            .await
+            .ok_or((StatusCode::UNAUTHORIZED, "personal token is invalid"))?;
+        let user = config
+            .users
+            .iter()
+            .find(|u| u.login == token.login)
+            .ok_or((StatusCode::UNAUTHORIZED, "personal token is invalid"))?;
+        if !token.scope.allows_method(method) {
+            return Err((StatusCode::FORBIDDEN, "personal token is read only"));
+        }
+        let roles = session_roles(user, config)
+            .into_iter()
+            .filter(|r| token.scope.roles.is_empty() || token.scope.roles.contains(r))
+            .collect();
+        Ok(UserToken {
+            login: user.login.clone(),
+            roles,
+            // Bearer tokens are not sent automatically by browsers, there is no cross site request to guard against
+            xsrf_token: String::new(),
+            share: None,
+            expires: token.expires.unwrap_or(i64::MAX),
+            info: user.info.clone(),
+            scope: Some(token.scope),
+        })
+    }
+}
+
+/// Personal tokens can only be managed from a session, so that a token cannot widen its own scope
+fn session_user(token: &UserToken) -> Result<&str, (StatusCode, &'static str)> {
+    if token.scope.is_some() || token.share.is_some() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "personal tokens can only be managed from a session",
+        ));
+    }
+    Ok(&token.login)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/tokens",
+    tag = "user",
+    security(("cookie" = [])),
+    responses(
+        (status = 200, description = "Personal tokens of the user", body = [TokenInfo]),
+        (status = 401, description = "User is not authenticated"),
+    ),
+)]
+pub async fn list_tokens(
+    State(tokens): State<TokenStore>,
+    user: UserToken,
+) -> Result<Json<Vec<TokenInfo>>, (StatusCode, &'static str)> {
+    let login = session_user(&user)?;
+    let list = tokens.list(login).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not read personal tokens",
+        )
+    })?;
+    Ok(Json(list.iter().map(TokenInfo::from).collect()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/tokens",
+    tag = "user",
+    security(("cookie" = [])),
+    request_body = NewToken,
+    responses(
+        (status = 201, description = "Personal token created", body = CreatedToken),
+        (status = 400, description = "Token scope exceeds the rights of the user, or its expiry is out of range"),
+        (status = 401, description = "User is not authenticated"),
+    ),
+)]
+pub async fn create_token(
+    State(tokens): State<TokenStore>,
+    State(audit): State<AuditLog>,
+    State(config): State<ConfigState>,
+    ClientIp(ip): ClientIp,
+    user: UserToken,
+    Json(new): Json<NewToken>,
+) -> Result<(StatusCode, Json<CreatedToken>), (StatusCode, &'static str)> {
+    let login = session_user(&user)?;
+    if new.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "token name is required"));
+    }
+    if !new.scope.roles.iter().all(|r| user.roles.contains(r)) {
+        return Err((StatusCode::BAD_REQUEST, "token roles exceed the user roles"));
+    }
+    if !new
+        .scope
+        .apps
+        .iter()
+        .all(|id| config.apps.iter().any(|a| a.id == *id))
+    {
+        return Err((StatusCode::BAD_REQUEST, "token apps do not exist"));
+    }
+    if new
+        .expires_in_days
+        .is_some_and(|days| !(1..=MAX_EXPIRY_DAYS).contains(&days))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "token expiry must be between 1 and 3650 days",
+        ));
+    }
+    let (token, value) = tokens.create(login, new).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not store personal token",
+        )
+    })?;
+    let info = TokenInfo::from(&token);
+    audit
+        .record_or_error(
+            AuditEvent::new(login, ip, AuditAction::TokenCreated)
+                .target(&token.id)
+                .change(None, Some(&info)),
+        )
+        .await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedToken { info, token: value }),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/user/tokens/{token_id}",
+    tag = "user",
+    security(("cookie" = [])),
+    params(("token_id" = String, Path, description = "Token id")),
+    responses(
+        (status = 200, description = "Personal token revoked"),
+        (status = 401, description = "User is not authenticated"),
+        (status = 404, description = "Token does not exist"),
+    ),
+)]
+pub async fn revoke_token(
+    State(tokens): State<TokenStore>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    user: UserToken,
+    UrlPath(token_id): UrlPath<String>,
+) -> Result<&'static str, (StatusCode, &'static str)> {
+    let login = session_user(&user)?;
+    let revoked = tokens.revoke(login, &token_id).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not revoke personal token",
+        )
+    })?;
+    if !revoked {
+        return Err((StatusCode::NOT_FOUND, "token does not exist"));
+    }
+    audit
+        .record_or_error(AuditEvent::new(login, ip, AuditAction::TokenRevoked).target(&token_id))
+        .await?;
+    Ok("token revoked successfully")
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, StatusCode};
+
+    use crate::{
+        configuration::Config,
+        tokens::{NewToken, TokenAccess, TokenScope, TokenStore, MAX_EXPIRY_DAYS},
+        users::User,
+    };
+
+    #[tokio::test]
+    async fn test_token_lifecycle() {
+        let file = std::env::temp_dir().join("atrium_tokens_test.json");
+        let _ = std::fs::remove_file(&file);
+        let store = TokenStore::new(file.clone());
+        let config = Config {
+            users: vec![User {
+                login: "user".to_owned(),
+                roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let (token, value) = store
+            .create(
+                "user",
+                NewToken {
+                    name: "ci".to_owned(),
+                    scope: TokenScope {
+                        roles: vec!["USERS".to_owned()],
+                        apps: vec![],
+                        access: TokenAccess::Read,
+                    },
+                    expires_in_days: Some(1),
+                },
+            )
+            .await
+            .unwrap();
+        // The secret is not stored
+        assert!(!std::fs::read_to_string(&file)
+            .unwrap()
+            .contains(value.rsplit('_').next().unwrap()));
+
+        let user = store
+            .authenticate(&config, &value, &Method::GET)
+            .await
+            .unwrap();
+        assert_eq!(user.roles, vec!["USERS".to_owned()]);
+        let err = store
+            .authenticate(&config, &value, &Method::POST)
+            .await
+            .unwrap_err();
+        assert_eq!(err.0, StatusCode::FORBIDDEN);
+        let tampered = format!("{}x", value);
+        assert!(store
+            .authenticate(&config, &tampered, &Method::GET)
+            .await
+            .is_err());
+
+        assert!(store.revoke("user", &token.id).await.unwrap());
+        assert!(store
+            .authenticate(&config, &value, &Method::GET)
+            .await
+            .is_err());
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expiry_out_of_range() {
+        let file = std::env::temp_dir().join("atrium_tokens_test_expiry.json");
+        let _ = std::fs::remove_file(&file);
+        let store = TokenStore::new(file.clone());
+        let new = |days: i64| NewToken {
+            name: "ci".to_owned(),
+            scope: TokenScope::default(),
+            expires_in_days: Some(days),
+        };
+        for days in [0, MAX_EXPIRY_DAYS + 1, 10_000_000, i64::MAX, i64::MIN] {
+            assert!(store.create("user", new(days)).await.is_err(), "{days}");
+        }
+        let (token, _) = store.create("user", new(MAX_EXPIRY_DAYS)).await.unwrap();
+        assert!(token.expires.unwrap() > token.created);
+        std::fs::remove_file(file).unwrap();
+    }
+}
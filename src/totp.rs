@@ -0,0 +1,775 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Host, Path, State},
+    Json, TypedHeader,
+};
+use axum_extra::extract::cookie::{Cookie, PrivateCookieJar, SameSite};
+use data_encoding::BASE32_NOPAD;
+use headers::ETag;
+use hmac::{Hmac, Mac};
+use http::StatusCode;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use time::{Duration, OffsetDateTime};
+use utoipa::ToSchema;
+
+use crate::{
+    audit::{AuditAction, AuditEvent, AuditLog},
+    client_ip::ClientIp,
+    config_writer::{ConfigError, ConfigTransaction, ConfigWriter},
+    configuration::Config,
+    headers::OptionalIfMatch,
+    secrets::Secret,
+    tokens::{constant_time_eq, hash_secret},
+    users::{
+        find_user, refresh_session, start_session, token_user, verify_password, AdminToken,
+        AuthResponse, User, UserToken, ADMINS_ROLE, REDACTED,
+    },
+    utils::{is_default, random_string},
+};
+
+pub static TOTP_CHALLENGE_COOKIE: &str = "ATRIUM_TOTP_CHALLENGE";
+static ISSUER: &str = "Atrium";
+const STEP_SECONDS: i64 = 30;
+const DIGITS: usize = 6;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const CHALLENGE_MINUTES: i64 = 5;
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT_SECONDS: i64 = 300;
+
+/// Time based one time password (RFC 6238) second factor of a local user
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct UserTotp {
+    /// Base32 shared secret, stored in the secrets directory
+    #[schema(value_type = String)]
+    pub secret: Secret,
+    /// The factor is only required at login once the enrollment is confirmed with a code
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub confirmed: bool,
+    /// SHA-256 hashes of the unused recovery codes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recovery_codes: Vec<String>,
+}
+
+impl UserTotp {
+    pub fn redacted(mut self) -> Self {
+        self.secret = Secret::new(REDACTED.to_owned());
+        self.recovery_codes.clear();
+        self
+    }
+}
+
+/// Whether the second factor of the user is required at login
+pub fn totp_enabled(user: &User) -> bool {
+    user.totp.as_ref().is_some_and(|t| t.confirmed)
+}
+
+/// Whether the user is an administrator who must, but did not yet, enroll a second factor
+pub fn totp_enrollment_required(user: &User, config: &Config) -> bool {
+    config.totp_required_for_admins
+        && user.roles.iter().any(|r| r == ADMINS_ROLE)
+        && !totp_enabled(user)
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    code % 10u32.pow(DIGITS as u32)
+}
+
+/// Finds the time step a code was generated for, one step of clock drift is allowed either way
+fn matching_step(secret: &str, code: &str, timestamp: i64) -> Option<u64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code = code.trim();
+    if code.len() != DIGITS || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let step = u64::try_from(timestamp / STEP_SECONDS).ok()?;
+    (step.saturating_sub(1)..=step + 1).find(|s| hotp(&secret, *s) == code)
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    BASE32_NOPAD.encode(&bytes)
+}
+
+/// URI to be shown as a QR code to authenticator apps
+fn provisioning_uri(login: &str, secret: &str) -> String {
+    let issuer = urlencoding::encode(ISSUER);
+    format!(
+        "otpauth://totp/{issuer}:{}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECONDS}",
+        urlencoding::encode(login)
+    )
+}
+
+fn secret_name(login: &str) -> String {
+    format!("user_{}_totp", urlencoding::encode(login))
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.trim().to_lowercase()
+}
+
+/// How a second factor was proven
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verified {
+    Code,
+    /// Hash of the recovery code used, which must be removed
+    RecoveryCode(String),
+}
+
+#[derive(Default)]
+struct GuardState {
+    failures: u32,
+    locked_until: i64,
+    last_step: Option<u64>,
+}
+
+/// Guards the second factors against replay and brute force : a code is accepted only once,
+/// and too many wrong codes lock the user out for a while
+#[derive(Clone, Default)]
+pub struct TotpGuard {
+    users: Arc<Mutex<HashMap<String, GuardState>>>,
+}
+
+impl TotpGuard {
+    pub fn verify(
+        &self,
+        login: &str,
+        totp: &UserTotp,
+        code: &str,
+    ) -> Result<Verified, (StatusCode, &'static str)> {
+        self.verify_at(
+            login,
+            totp,
+            code,
+            OffsetDateTime::now_utc().unix_timestamp(),
+        )
+    }
+
+    fn verify_at(
+        &self,
+        login: &str,
+        totp: &UserTotp,
+        code: &str,
+        now: i64,
+    ) -> Result<Verified, (StatusCode, &'static str)> {
+        let mut users = self.users.lock().expect("totp guard lock is poisoned");
+        let state = users.entry(login.to_owned()).or_default();
+        if now < state.locked_until {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many wrong codes, try again later",
+            ));
+        }
+        if let Some(step) = matching_step(totp.secret.expose(), code, now) {
+            if state.last_step.is_none_or(|last| step > last) {
+                state.failures = 0;
+                state.last_step = Some(step);
+                return Ok(Verified::Code);
+            }
+        }
+        let hash = hash_secret(&normalize_recovery_code(code));
+        if let Some(stored) = totp
+            .recovery_codes
+            .iter()
+            .find(|h| constant_time_eq(h.as_bytes(), hash.as_bytes()))
+        {
+            state.failures = 0;
+            return Ok(Verified::RecoveryCode(stored.clone()));
+        }
+        state.failures += 1;
+        if state.failures >= MAX_FAILURES {
+            state.failures = 0;
+            state.locked_until = now + LOCKOUT_SECONDS;
+        }
+        Err((StatusCode::UNAUTHORIZED, "code is invalid"))
+    }
+}
+
+/// Removes a recovery code of the user, failing if a concurrent login used it since the code was checked
+fn use_recovery_code(
+    config: &mut Config,
+    login: &str,
+    hash: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    let codes = &mut config
+        .users
+        .iter_mut()
+        .find(|u| u.login == login)
+        .and_then(|u| u.totp.as_mut())
+        .ok_or((StatusCode::UNAUTHORIZED, "no pending two-factor challenge"))?
+        .recovery_codes;
+    let count = codes.len();
+    codes.retain(|h| h != hash);
+    if codes.len() == count {
+        return Err((StatusCode::UNAUTHORIZED, "code is invalid"));
+    }
+    Ok(())
+}
+
+/// Wrong codes of an authenticated user are forbidden, the session being valid
+fn forbidden(e: (StatusCode, &'static str)) -> (StatusCode, &'static str) {
+    match e {
+        (StatusCode::UNAUTHORIZED, msg) => (StatusCode::FORBIDDEN, msg),
+        e => e,
+    }
+}
+
+/// Login whose password was checked, waiting for the second factor
+#[derive(Serialize, Deserialize)]
+struct TotpChallenge {
+    login: String,
+    expires: i64,
+}
+
+fn challenge_cookie(value: String, hostname: &str, config: &Config) -> Cookie<'static> {
+    let domain = hostname.split(':').next().unwrap_or_default().to_owned();
+    Cookie::build(TOTP_CHALLENGE_COOKIE, value)
+        .domain(domain)
+        .path("/auth")
+        .same_site(SameSite::Strict)
+        .secure(config.tls_mode.is_secure())
+        .max_age(Duration::minutes(CHALLENGE_MINUTES))
+        .http_only(true)
+        .finish()
+}
+
+/// Starts the second step of the login of a user whose password is right
+pub(crate) fn start_challenge(
+    jar: PrivateCookieJar,
+    login: &str,
+    hostname: &str,
+    config: &Config,
+) -> Result<PrivateCookieJar, (StatusCode, &'static str)> {
+    let challenge = TotpChallenge {
+        login: login.to_owned(),
+        expires: (OffsetDateTime::now_utc() + Duration::minutes(CHALLENGE_MINUTES))
+            .unix_timestamp(),
+    };
+    let encoded = serde_json::to_string(&challenge).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not encode challenge",
+        )
+    })?;
+    Ok(jar.add(challenge_cookie(encoded, hostname, config)))
+}
+
+/// Works out the second factor of an imported user : a redacted one is left unchanged, and a plain secret is moved to the secrets directory
+pub(crate) async fn store_imported_totp(
+    transaction: &mut ConfigTransaction,
+    login: &str,
+    stored: Option<UserTotp>,
+    imported: Option<UserTotp>,
+) -> Result<Option<UserTotp>, (StatusCode, &'static str)> {
+    let Some(mut totp) = imported else {
+        return Ok(None);
+    };
+    if totp.secret.reference().is_none() && totp.secret.expose() == REDACTED {
+        return Ok(stored);
+    }
+    // Secret references can only be set in the configuration file, as they give access to the server environment
+    if totp.secret.reference().is_some() && stored.is_none_or(|s| s.secret != totp.secret) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "secret references can only be set in the configuration file",
+        ));
+    }
+    transaction
+        .persist_secret(&mut totp.secret, &secret_name(login))
+        .await?;
+    Ok(Some(totp))
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TotpCode {
+    /// Code of the authenticator app, or one of the recovery codes at login
+    pub code: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TotpDisable {
+    pub password: String,
+    /// Code of the authenticator app, or one of the recovery codes, required once the enrollment is confirmed
+    #[serde(default)]
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TotpStatus {
+    pub enabled: bool,
+    /// An enrollment is waiting for its confirmation code
+    pub pending: bool,
+    /// The user is an administrator who must enroll to get the administration rights
+    pub required: bool,
+    pub recovery_codes_left: usize,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TotpEnrollment {
+    /// Base32 secret, for authenticator apps that cannot scan QR codes
+    pub secret: String,
+    /// otpauth:// URI to be shown as a QR code
+    pub provisioning_uri: String,
+    /// Single use codes replacing the authenticator if it is lost, they are shown only once
+    pub recovery_codes: Vec<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/local/totp",
+    tag = "auth",
+    request_body = TotpCode,
+    responses(
+        (status = 200, description = "Second factor checked, the session cookie is set", body = AuthResponse),
+        (status = 401, description = "No pending challenge, or the code is invalid"),
+        (status = 429, description = "Too many wrong codes"),
+    ),
+)]
+pub async fn totp_login(
+    ClientIp(ip): ClientIp,
+    jar: PrivateCookieJar,
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    State(guard): State<TotpGuard>,
+    Host(hostname): Host,
+    Json(payload): Json<TotpCode>,
+) -> Result<(PrivateCookieJar, Json<AuthResponse>), (StatusCode, &'static str)> {
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let challenge = jar
+        .get(TOTP_CHALLENGE_COOKIE)
+        .and_then(|c| serde_json::from_str::<TotpChallenge>(c.value()).ok())
+        .filter(|c| c.expires >= now)
+        .ok_or((StatusCode::UNAUTHORIZED, "no pending two-factor challenge"))?;
+    let config = writer.current();
+    let user = find_user(&config, &challenge.login)
+        .filter(|u| totp_enabled(u))
+        .ok_or((StatusCode::UNAUTHORIZED, "no pending two-factor challenge"))?;
+    let totp = user.totp.as_ref().expect("totp is enabled");
+
+    match guard.verify(&user.login, totp, &payload.code) {
+        Ok(Verified::Code) => (),
+        Ok(Verified::RecoveryCode(hash)) => {
+            let mut transaction = writer.transaction(None).await?;
+            use_recovery_code(&mut transaction.config, &user.login, &hash)?;
+            // The code is used up, whatever the version of the configuration
+            let _ = transaction.commit(&user.login).await?;
+            audit
+                .record_or_error(
+                    AuditEvent::new(&user.login, ip, AuditAction::RecoveryCodeUsed)
+                        .target(&user.login),
+                )
+                .await?;
+        }
+        Err(e) => {
+            audit
+                .record_failure(
+                    AuditEvent::new(&user.login, ip, AuditAction::TotpFailed).target(&user.login),
+                )
+                .await;
+            return Err(e);
+        }
+    }
+
+    let jar = jar.remove(challenge_cookie(String::new(), &hostname, &config));
+    let (jar, response) = start_session(jar, user, hostname, &config, ip)?;
+    audit
+        .record_or_error(AuditEvent::new(&user.login, ip, AuditAction::Login))
+        .await?;
+    Ok((jar, Json(response)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/totp",
+    tag = "user",
+    security(("cookie" = [])),
+    responses(
+        (status = 200, description = "Two-factor authentication status of the user", body = TotpStatus),
+        (status = 401, description = "User is not authenticated"),
+        (status = 404, description = "User is not a local user"),
+    ),
+)]
+pub async fn get_totp(
+    State(writer): State<ConfigWriter>,
+    token: UserToken,
+) -> Result<Json<TotpStatus>, (StatusCode, &'static str)> {
+    let config = writer.current();
+    let user = token_user(&config, &token)?;
+    Ok(Json(TotpStatus {
+        enabled: totp_enabled(user),
+        pending: user.totp.as_ref().is_some_and(|t| !t.confirmed),
+        required: totp_enrollment_required(user, &config),
+        recovery_codes_left: user
+            .totp
+            .as_ref()
+            .filter(|t| t.confirmed)
+            .map_or(0, |t| t.recovery_codes.len()),
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/totp",
+    tag = "user",
+    security(("cookie" = [])),
+    responses(
+        (status = 201, description = "Enrollment started, it must be confirmed with a code", body = TotpEnrollment),
+        (status = 401, description = "User is not authenticated"),
+        (status = 404, description = "User is not a local user"),
+        (status = 409, description = "Two-factor authentication is already enabled"),
+    ),
+)]
+pub async fn enroll_totp(
+    State(writer): State<ConfigWriter>,
+    token: UserToken,
+) -> Result<(StatusCode, TypedHeader<ETag>, Json<TotpEnrollment>), (StatusCode, &'static str)> {
+    let mut transaction = writer.transaction(None).await?;
+    if totp_enabled(token_user(&transaction.config, &token)?) {
+        return Err((
+            StatusCode::CONFLICT,
+            "two-factor authentication is already enabled",
+        ));
+    }
+    let secret = generate_secret();
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| normalize_recovery_code(&random_string(RECOVERY_CODE_LENGTH)))
+        .collect();
+    let mut totp = UserTotp {
+        secret: Secret::new(secret.clone()),
+        confirmed: false,
+        recovery_codes: recovery_codes.iter().map(|c| hash_secret(c)).collect(),
+    };
+    // Do not inline the secret in the configuration file
+    transaction
+        .persist_secret(&mut totp.secret, &secret_name(&token.login))
+        .await?;
+    transaction
+        .config
+        .users
+        .iter_mut()
+        .find(|u| u.login == token.login)
+        .expect("user was found above")
+        .totp = Some(totp);
+
+    let etag = transaction.commit(&token.login).await?;
+    Ok((
+        StatusCode::CREATED,
+        etag,
+        Json(TotpEnrollment {
+            provisioning_uri: provisioning_uri(&token.login, &secret),
+            secret,
+            recovery_codes,
+        }),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/totp/confirm",
+    tag = "user",
+    security(("cookie" = [])),
+    request_body = TotpCode,
+    responses(
+        (status = 200, description = "Two-factor authentication enabled, the session cookie is refreshed"),
+        (status = 401, description = "User is not authenticated"),
+        (status = 403, description = "Code is invalid"),
+        (status = 404, description = "User is not a local user"),
+        (status = 409, description = "No enrollment is pending"),
+        (status = 429, description = "Too many wrong codes"),
+    ),
+)]
+pub async fn confirm_totp(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    State(guard): State<TotpGuard>,
+    ClientIp(ip): ClientIp,
+    jar: PrivateCookieJar,
+    token: UserToken,
+    Json(payload): Json<TotpCode>,
+) -> Result<(PrivateCookieJar, TypedHeader<ETag>, &'static str), (StatusCode, &'static str)> {
+    // The live configuration has the secret resolved
+    let pending = token_user(&writer.current(), &token)?
+        .totp
+        .clone()
+        .filter(|t| !t.confirmed)
+        .ok_or((StatusCode::CONFLICT, "no two-factor enrollment is pending"))?;
+    // Recovery codes only replace the authenticator once it is confirmed
+    if guard
+        .verify(&token.login, &pending, &payload.code)
+        .map_err(forbidden)?
+        != Verified::Code
+    {
+        return Err((StatusCode::FORBIDDEN, "code is invalid"));
+    }
+    let mut transaction = writer.transaction(None).await?;
+    token_user(&transaction.config, &token)?;
+    let user = transaction
+        .config
+        .users
+        .iter_mut()
+        .find(|u| u.login == token.login)
+        .expect("user was found above");
+    let totp = user
+        .totp
+        .as_mut()
+        .filter(|t| !t.confirmed)
+        .ok_or((StatusCode::CONFLICT, "no two-factor enrollment is pending"))?;
+    totp.confirmed = true;
+    let user = user.clone();
+
+    let etag = transaction.commit(&token.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&token.login, ip, AuditAction::TotpEnabled).target(&token.login),
+        )
+        .await?;
+
+    // The hostname of the configuration, the Host header coming from the client
+    let config = writer.current();
+    let jar = refresh_session(jar, &token, &user, config.hostname.clone(), &config, ip)?;
+    Ok((jar, etag, "two-factor authentication enabled"))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/user/totp",
+    tag = "user",
+    security(("cookie" = [])),
+    request_body = TotpDisable,
+    responses(
+        (status = 200, description = "Two-factor authentication disabled, the session cookie is refreshed"),
+        (status = 401, description = "User is not authenticated"),
+        (status = 403, description = "Password or code is wrong"),
+        (status = 404, description = "User is not a local user, or has no second factor"),
+        (status = 429, description = "Too many wrong codes"),
+    ),
+)]
+pub async fn disable_totp(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    State(guard): State<TotpGuard>,
+    ClientIp(ip): ClientIp,
+    jar: PrivateCookieJar,
+    token: UserToken,
+    Json(payload): Json<TotpDisable>,
+) -> Result<(PrivateCookieJar, TypedHeader<ETag>, &'static str), (StatusCode, &'static str)> {
+    let mut transaction = writer.transaction(None).await?;
+    let user = token_user(&transaction.config, &token)?;
+    if !verify_password(&user.password, &payload.password) {
+        return Err((StatusCode::FORBIDDEN, "password is wrong"));
+    }
+    // The password alone does not remove a confirmed second factor, the live configuration has its secret resolved
+    if let Some(totp) = token_user(&writer.current(), &token)?
+        .totp
+        .as_ref()
+        .filter(|t| t.confirmed)
+    {
+        if let Err(e) = guard.verify(&token.login, totp, &payload.code) {
+            audit
+                .record_or_error(
+                    AuditEvent::new(&token.login, ip, AuditAction::TotpFailed).target(&token.login),
+                )
+                .await?;
+            return Err(forbidden(e));
+        }
+    }
+    let user = transaction
+        .config
+        .users
+        .iter_mut()
+        .find(|u| u.login == token.login)
+        .expect("user was found above");
+    if user.totp.take().is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "two-factor authentication is not enabled",
+        ));
+    }
+    let user = user.clone();
+
+    let etag = transaction.commit(&token.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&token.login, ip, AuditAction::TotpDisabled).target(&token.login),
+        )
+        .await?;
+
+    // The hostname of the configuration, the Host header coming from the client
+    let config = writer.current();
+    let jar = refresh_session(jar, &token, &user, config.hostname.clone(), &config, ip)?;
+    Ok((jar, etag, "two-factor authentication disabled"))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/users/{user_login}/totp",
+    tag = "users",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("user_login" = String, Path, description = "User login"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    responses(
+        (status = 200, description = "Second factor of the user removed, for instance after the loss of the authenticator", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "User does not exist or has no second factor"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
+pub async fn reset_user_totp(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Path(user_login): Path<String>,
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), ConfigError> {
+    let mut transaction = writer.transaction(if_match).await?;
+    let user = transaction
+        .config
+        .users
+        .iter_mut()
+        .find(|u| u.login == user_login)
+        .ok_or((StatusCode::NOT_FOUND, "user does not exist"))?;
+    if user.totp.take().is_none() {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "two-factor authentication is not enabled",
+        )
+            .into());
+    }
+
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::TotpDisabled).target(&user_login),
+        )
+        .await?;
+
+    Ok((
+        StatusCode::OK,
+        etag,
+        "two-factor authentication reset successfully",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use data_encoding::BASE32_NOPAD;
+    use http::StatusCode;
+
+    use crate::{
+        configuration::Config,
+        secrets::Secret,
+        tokens::hash_secret,
+        totp::{
+            hotp, matching_step, use_recovery_code, TotpGuard, UserTotp, Verified, LOCKOUT_SECONDS,
+        },
+        users::User,
+    };
+
+    // Secret of the RFC 6238 test vectors
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        for (time, code) in [
+            (59, 287082),
+            (1111111109, 81804),
+            (1234567890, 5924),
+            (2000000000, 279037),
+        ] {
+            assert_eq!(hotp(RFC_SECRET, time / 30), code);
+        }
+        let secret = BASE32_NOPAD.encode(RFC_SECRET);
+        assert_eq!(matching_step(&secret, "081804", 1111111109), Some(37037036));
+        // One step of clock drift is accepted, not two
+        assert!(matching_step(&secret, "081804", 1111111109 + 30).is_some());
+        assert!(matching_step(&secret, "081804", 1111111109 + 60).is_none());
+        assert!(matching_step(&secret, "81804", 1111111109).is_none());
+    }
+
+    #[test]
+    fn test_guard() {
+        let totp = UserTotp {
+            secret: Secret::new(BASE32_NOPAD.encode(RFC_SECRET)),
+            confirmed: true,
+            recovery_codes: vec![hash_secret("recovery")],
+        };
+        let guard = TotpGuard::default();
+        let now = 1111111109;
+        assert_eq!(
+            guard.verify_at("user", &totp, "081804", now),
+            Ok(Verified::Code)
+        );
+        // A code cannot be replayed
+        assert!(guard.verify_at("user", &totp, "081804", now).is_err());
+        assert_eq!(
+            guard.verify_at("user", &totp, " Recovery ", now),
+            Ok(Verified::RecoveryCode(hash_secret("recovery")))
+        );
+
+        for _ in 0..4 {
+            assert_eq!(
+                guard.verify_at("user", &totp, "000000", now).unwrap_err().0,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        // The fifth failure locks the user out, even with a right code
+        assert!(guard.verify_at("user", &totp, "000000", now).is_err());
+        assert_eq!(
+            guard
+                .verify_at("user", &totp, "recovery", now)
+                .unwrap_err()
+                .0,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert!(guard
+            .verify_at("user", &totp, "recovery", now + LOCKOUT_SECONDS)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_recovery_code_is_used_once() {
+        let hash = hash_secret("recovery");
+        let mut config = Config {
+            users: vec![User {
+                login: "user".to_owned(),
+                totp: Some(UserTotp {
+                    secret: Secret::new(BASE32_NOPAD.encode(RFC_SECRET)),
+                    confirmed: true,
+                    recovery_codes: vec![hash.clone(), hash_secret("other")],
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(use_recovery_code(&mut config, "user", &hash).is_ok());
+        // A second login checked against the configuration read before the first one is refused
+        assert_eq!(
+            use_recovery_code(&mut config, "user", &hash).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        let codes = &config.users[0].totp.as_ref().unwrap().recovery_codes;
+        assert_eq!(codes, &vec![hash_secret("other")]);
+    }
+}
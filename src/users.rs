@@ -1,33 +1,56 @@
 use crate::{
-    appstate::{ConfigFile, ConfigState},
-    configuration::{config_or_error, Config, HostType},
-    headers::XSRFToken,
-    utils::{is_default, random_string, raw_query_pairs, string_trim, vec_trim_remove_empties},
+    appstate::ConfigState,
+    audit::{AuditAction, AuditEvent, AuditLog},
+    client_ip::ClientIp,
+    config_writer::{ConfigError, ConfigWriter},
+    configuration::{Config, HostType},
+    headers::{OptionalIfMatch, XSRFToken},
+    ldap::{self, Directory, LdapDirectory},
+    policy::{decide, Access},
+    tokens::{TokenScope, TokenStore, TOKEN_PREFIX},
+    totp::{start_challenge, totp_enabled, totp_enrollment_required, UserTotp},
+    utils::{
+        is_default, merge_patch, random_string, raw_query_pairs, string_trim,
+        vec_trim_remove_empties,
+    },
+    webauthn::Passkey,
 };
 
+use argon2::{
+    password_hash::{PasswordHasher, PasswordVerifier, SaltString},
+    Argon2, PasswordHash,
+};
 use axum::{
     async_trait,
-    extract::{ConnectInfo, FromRef, FromRequestParts, Host, Path, RawQuery, State},
+    extract::{FromRef, FromRequestParts, Host, Path, RawQuery, State},
     middleware::Next,
     response::{IntoResponse, Response},
-    Extension, Json, RequestPartsExt, TypedHeader,
+    Json, TypedHeader,
 };
 use axum_extra::extract::cookie::{Cookie, Key, PrivateCookieJar};
-use headers::{authorization::Basic, Authorization, HeaderName};
-use http::{header::CONTENT_LENGTH, request::Parts, HeaderValue, Request, StatusCode};
+use headers::{
+    authorization::{Basic, Bearer},
+    Authorization, ETag, HeaderName,
+};
+use http::{
+    header::{CONTENT_LENGTH, LOCATION},
+    request::Parts,
+    HeaderValue, Request, StatusCode,
+};
 use hyper::Body;
 
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::net::IpAddr;
 use time::{Duration, OffsetDateTime};
+use utoipa::ToSchema;
 
 pub static AUTH_COOKIE: &str = "ATRIUM_AUTH";
-static SHARE_TOKEN: &str = "SHARE_TOKEN";
+pub(crate) static SHARE_TOKEN: &str = "SHARE_TOKEN";
 static WWWAUTHENTICATE: HeaderName = HeaderName::from_static("www-authenticate");
 pub static ADMINS_ROLE: &str = "ADMINS";
 pub static REDACTED: &str = "REDACTED";
 
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub struct UserInfo {
     #[serde(
         skip_serializing_if = "is_default",
@@ -49,7 +72,7 @@ pub struct UserInfo {
     pub email: String,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
 pub struct User {
     #[serde(deserialize_with = "string_trim")]
     pub login: String,
@@ -67,6 +90,23 @@ pub struct User {
     pub roles: Vec<String>,
     #[serde(default, skip_serializing_if = "is_default")]
     pub info: Option<UserInfo>,
+    /// Second factor, managed by the user at /api/user/totp
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<UserTotp>,
+    /// WebAuthn credentials, registered by the user at /auth/webauthn/register
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub passkeys: Vec<Passkey>,
+}
+
+impl User {
+    /// Replaces the password hash and the second factor secrets, so that the user can be sent to the clients
+    pub fn redacted(mut self) -> Self {
+        if !self.password.is_empty() {
+            self.password = REDACTED.to_owned();
+        }
+        self.totp = self.totp.map(UserTotp::redacted);
+        self
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -85,6 +125,9 @@ pub struct UserToken {
     pub share: Option<Share>,
     pub expires: i64,
     pub info: Option<UserInfo>,
+    /// Restrictions of the personal token the user authenticated with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<TokenScope>,
 }
 
 impl UserToken {
@@ -114,9 +157,16 @@ where
     S: Send + Sync,
     Key: FromRef<S>,
     ConfigState: FromRef<S>,
+    AuditLog: FromRef<S>,
+    TokenStore: FromRef<S>,
 {
     type Rejection = (StatusCode, &'static str);
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        // Personal tokens come as bearer tokens
+        if let Some(user_token) = personal_token_user(parts, state).await {
+            return user_token;
+        }
+
         let jar = PrivateCookieJar::from_request_parts(parts, state)
             .await
             .expect("Could not find cookie jar");
@@ -138,18 +188,9 @@ where
         }
 
         // OR Try to get user_token from the query
-        if let Ok(query) = RawQuery::from_request_parts(parts, state).await {
-            if let Some(Some(password)) = raw_query_pairs(query.0.as_deref())
-                .ok()
-                .map(|hm| hm.get("token").map(|v| v.to_owned()))
-            {
-                let res = cookie_from_password(AUTH_COOKIE, &jar, password);
-                if res.is_ok() {
-                    return res;
-                } else {
-                    return cookie_from_password(SHARE_TOKEN, &jar, password);
-                }
-            }
+        let Ok(query) = RawQuery::from_request_parts(parts, state).await;
+        if let Some(res) = query_token_user(&jar, query.0.as_deref()) {
+            return res;
         }
 
         // OR Try to get user_token from basic auth headers
@@ -162,19 +203,35 @@ where
                 Err(_) => {
                     let config = ConfigState::from_ref(state);
 
-                    let Extension(addr) = parts
-                        .extract::<Extension<ConnectInfo<SocketAddr>>>()
-                        .await
-                        .expect("Could not find socket address");
+                    let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+                    let directory = config.ldap_config.as_ref().map(LdapDirectory::new);
                     return match authenticate_local_user(
                         &config,
+                        directory.as_ref().map(|d| d as &dyn Directory),
                         LocalAuth {
                             login: basic.username().to_string(),
+                            password: basic.password().to_string(),
                         },
-                        addr.0,
-                    ) {
+                        ip,
+                    )
+                    .await
+                    {
+                        // Basic auth cannot carry the second factor, personal tokens are to be used instead
+                        Ok((user, _)) if totp_enabled(&user) => Err((
+                            StatusCode::UNAUTHORIZED,
+                            "two-factor authentication is required",
+                        )),
                         Ok(user) => Ok(user.1),
-                        Err(e) => Err((e.0, "no user found in basic auth")),
+                        Err(e) => {
+                            AuditLog::from_ref(state)
+                                .record_failure(AuditEvent::new(
+                                    basic.username(),
+                                    ip,
+                                    AuditAction::LoginFailed,
+                                ))
+                                .await;
+                            Err((e.0, "no user found in basic auth"))
+                        }
                     };
                 }
             }
@@ -187,6 +244,45 @@ where
     }
 }
 
+/// Authenticates the request with its personal token, if it has one: other bearer tokens are left to the proxied apps
+async fn personal_token_user<S>(
+    parts: &mut Parts,
+    state: &S,
+) -> Option<Result<UserToken, (StatusCode, &'static str)>>
+where
+    S: Send + Sync,
+    ConfigState: FromRef<S>,
+    TokenStore: FromRef<S>,
+{
+    let TypedHeader(Authorization(bearer)) =
+        TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+            .await
+            .ok()?;
+    if !bearer.token().starts_with(TOKEN_PREFIX) {
+        return None;
+    }
+    let config = ConfigState::from_ref(state);
+    Some(
+        TokenStore::from_ref(state)
+            .authenticate(&config, bearer.token(), &parts.method)
+            .await,
+    )
+}
+
+/// Authenticates the request with the session or share token given in its query, if it has one
+pub(crate) fn query_token_user(
+    jar: &PrivateCookieJar,
+    query: Option<&str>,
+) -> Option<Result<UserToken, (StatusCode, &'static str)>> {
+    let password = *raw_query_pairs(query).ok()?.get("token")?;
+    let res = cookie_from_password(AUTH_COOKIE, jar, password);
+    if res.is_ok() {
+        Some(res)
+    } else {
+        Some(cookie_from_password(SHARE_TOKEN, jar, password))
+    }
+}
+
 fn cookie_from_password(
     cookie_name: &str,
     jar: &PrivateCookieJar,
@@ -209,7 +305,7 @@ fn cookie_from_password(
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct AdminToken(UserToken);
+pub struct AdminToken(pub UserToken);
 
 #[async_trait]
 impl<S> FromRequestParts<S> for AdminToken
@@ -217,6 +313,8 @@ where
     S: Send + Sync,
     Key: FromRef<S>,
     ConfigState: FromRef<S>,
+    AuditLog: FromRef<S>,
+    TokenStore: FromRef<S>,
 {
     type Rejection = (StatusCode, &'static str);
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
@@ -236,9 +334,15 @@ impl<S> FromRequestParts<S> for UserTokenWithoutXSRFCheck
 where
     S: Send + Sync,
     Key: FromRef<S>,
+    ConfigState: FromRef<S>,
+    TokenStore: FromRef<S>,
 {
     type Rejection = (StatusCode, &'static str);
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(user_token) = personal_token_user(parts, state).await {
+            return user_token.map(UserTokenWithoutXSRFCheck);
+        }
+
         let jar: PrivateCookieJar = PrivateCookieJar::from_request_parts(parts, state)
             .await
             .expect("Could not find cookie jar");
@@ -254,34 +358,93 @@ where
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct LocalAuth {
-    login: String,
+    pub(crate) login: String,
+    pub(crate) password: String,
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Default, Deserialize, Serialize, ToSchema)]
 pub struct AuthResponse {
     pub is_admin: bool,
     pub xsrf_token: String,
+    /// The password is right, the second factor must now be sent to /auth/local/totp
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub totp_required: bool,
+    /// The user is an administrator who must enroll a second factor to get the administration rights
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub totp_enrollment_required: bool,
 }
 
+#[utoipa::path(
+    post,
+    path = "/auth/local",
+    tag = "auth",
+    request_body = LocalAuth,
+    responses(
+        (status = 200, description = "User authenticated, the session cookie is set", body = AuthResponse),
+        (status = 202, description = "Password is right, the second factor is required", body = AuthResponse),
+        (status = 401, description = "Authentication failed"),
+    ),
+)]
 pub async fn local_auth(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ClientIp(ip): ClientIp,
     jar: PrivateCookieJar,
     State(config): State<ConfigState>,
+    State(audit): State<AuditLog>,
     Host(hostname): Host,
     Json(payload): Json<LocalAuth>,
-) -> Result<(PrivateCookieJar, Json<AuthResponse>), (StatusCode, &'static str)> {
+) -> Result<(StatusCode, PrivateCookieJar, Json<AuthResponse>), (StatusCode, &'static str)> {
+    let login = payload.login.clone();
     // Find the user in configuration
-    let (user, user_token) = authenticate_local_user(&config, payload, addr)?;
-    let cookie = create_user_cookie(&user_token, hostname, &config, addr, user)?;
+    let directory = config.ldap_config.as_ref().map(LdapDirectory::new);
+    let directory = directory.as_ref().map(|d| d as &dyn Directory);
+    let (user, _) = match authenticate_local_user(&config, directory, payload, ip).await {
+        Ok(authenticated) => authenticated,
+        Err(e) => {
+            audit
+                .record_failure(AuditEvent::new(&login, ip, AuditAction::LoginFailed))
+                .await;
+            return Err(e);
+        }
+    };
+    if totp_enabled(&user) {
+        let jar = start_challenge(jar, &user.login, &hostname, &config)?;
+        return Ok((
+            StatusCode::ACCEPTED,
+            jar,
+            Json(AuthResponse {
+                totp_required: true,
+                ..Default::default()
+            }),
+        ));
+    }
+    let (jar, response) = start_session(jar, &user, hostname, &config, ip)?;
+    audit
+        .record_or_error(AuditEvent::new(&login, ip, AuditAction::Login))
+        .await?;
 
+    Ok((StatusCode::OK, jar, Json(response)))
+}
+
+/// Sets the session cookie of an authenticated user
+pub(crate) fn start_session(
+    jar: PrivateCookieJar,
+    user: &User,
+    hostname: String,
+    config: &Config,
+    ip: IpAddr,
+) -> Result<(PrivateCookieJar, AuthResponse), (StatusCode, &'static str)> {
+    let user_token = user_to_token(user, config);
+    let cookie = create_user_cookie(&user_token, hostname, config, ip, user)?;
     Ok((
         jar.add(cookie),
-        Json(AuthResponse {
-            is_admin: user.roles.contains(&ADMINS_ROLE.to_owned()),
+        AuthResponse {
+            is_admin: user_token.roles.contains(&ADMINS_ROLE.to_owned()),
             xsrf_token: user_token.xsrf_token,
-        }),
+            totp_required: false,
+            totp_enrollment_required: totp_enrollment_required(user, config),
+        },
     ))
 }
 
@@ -289,7 +452,7 @@ pub(crate) fn create_user_cookie(
     user_token: &UserToken,
     hostname: String,
     config: &Config,
-    _addr: SocketAddr,
+    _ip: IpAddr,
     _user: &User,
 ) -> Result<Cookie<'static>, (StatusCode, &'static str)> {
     let encoded = serde_json::to_string(user_token)
@@ -311,109 +474,579 @@ pub(crate) fn create_user_cookie(
     Ok(cookie)
 }
 
-pub fn authenticate_local_user(
+/// Authenticates a user of the configuration, or else a user of the LDAP directory
+pub async fn authenticate_local_user(
     config: &Config,
+    directory: Option<&dyn Directory>,
     payload: LocalAuth,
-    _addr: SocketAddr,
-) -> Result<(&User, UserToken), (StatusCode, &'static str)> {
-    let user = config
-        .users
-        .iter()
-        .find(|u| u.login == payload.login)
-        .ok_or(StatusCode::UNAUTHORIZED)
-        .map_err(|e| (e, "user does not exist"))?;
+    _ip: IpAddr,
+) -> Result<(User, UserToken), (StatusCode, &'static str)> {
+    let user = match config.users.iter().find(|u| u.login == payload.login) {
+        Some(user) => {
+            if !verify_password(&user.password, &payload.password) {
+                return Err((StatusCode::UNAUTHORIZED, "password is wrong"));
+            }
+            user.clone()
+        }
+        None => {
+            let (Some(directory), Some(ldap_config)) = (directory, &config.ldap_config) else {
+                return Err((StatusCode::UNAUTHORIZED, "user does not exist"));
+            };
+            let found = directory
+                .authenticate(&payload.login, &payload.password)
+                .await
+                .map_err(|_| (StatusCode::BAD_GATEWAY, "could not reach the directory"))?
+                .ok_or((
+                    StatusCode::UNAUTHORIZED,
+                    "user does not exist or password is wrong",
+                ))?;
+            User {
+                login: payload.login,
+                roles: ldap::roles(ldap_config, &found.groups),
+                info: found.info,
+                ..Default::default()
+            }
+        }
+    };
 
     // Create a token payload from the user
-    let user_token = user_to_token(user, config);
+    let user_token = user_to_token(&user, config);
     Ok((user, user_token))
 }
 
+/// Roles carried by the sessions of a user, administrators who must enroll a second factor do not get their administration rights
+pub(crate) fn session_roles(user: &User, config: &Config) -> Vec<String> {
+    let enrollment_required = totp_enrollment_required(user, config);
+    user.roles
+        .iter()
+        .filter(|r| !enrollment_required || *r != ADMINS_ROLE)
+        .cloned()
+        .collect()
+}
+
 pub(crate) fn user_to_token(user: &User, config: &Config) -> UserToken {
     UserToken {
         login: user.login.to_owned(),
-        roles: user.roles.to_owned(),
+        roles: session_roles(user, config),
         xsrf_token: random_string(16),
         share: None,
         expires: (OffsetDateTime::now_utc()
             + Duration::days(config.session_duration_days.unwrap_or(1)))
         .unix_timestamp(),
         info: user.info.clone(),
+        scope: None,
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/admin/users",
+    tag = "users",
+    security(("cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "All the users, without their password hashes", body = [User], headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 401, description = "User is not an administrator"),
+    ),
+)]
 pub async fn get_users(
-    State(config_file): State<ConfigFile>,
+    State(writer): State<ConfigWriter>,
     _admin: AdminToken,
-) -> Result<Json<Vec<User>>, (StatusCode, &'static str)> {
-    let config = config_or_error(&config_file).await?;
-    // Return all the users as Json
-    Ok(Json(config.users))
+) -> Result<(TypedHeader<ETag>, Json<Vec<User>>), (StatusCode, &'static str)> {
+    let (config, etag) = writer.read().await?;
+    // Return all the users as Json, without their password hashes
+    Ok((
+        etag,
+        Json(config.users.into_iter().map(User::redacted).collect()),
+    ))
 }
 
-pub async fn delete_user(
-    State(config_file): State<ConfigFile>,
+#[utoipa::path(
+    get,
+    path = "/api/admin/users/{user_login}",
+    tag = "users",
+    security(("cookie" = []), ("bearer" = [])),
+    params(("user_login" = String, Path, description = "User login")),
+    responses(
+        (status = 200, description = "The user, without its password hash", body = User, headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "User does not exist"),
+    ),
+)]
+pub async fn get_user(
+    State(writer): State<ConfigWriter>,
     _admin: AdminToken,
     Path(user_login): Path<String>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let mut config = config_or_error(&config_file).await?;
-    // Find the user
-    if let Some(pos) = config.users.iter().position(|u| u.login == user_login) {
-        // It is an existing user, delete it
-        config.users.remove(pos);
-    } else {
-        // If the user does not exist, respond with an error
-        return Err((StatusCode::BAD_REQUEST, "user does not exist"));
-    }
+) -> Result<(TypedHeader<ETag>, Json<User>), (StatusCode, &'static str)> {
+    let (config, etag) = writer.read().await?;
+    let user =
+        find_user(&config, &user_login).ok_or((StatusCode::NOT_FOUND, "user does not exist"))?;
+    Ok((etag, Json(user.clone().redacted())))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/users/{user_login}",
+    tag = "users",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("user_login" = String, Path, description = "User login"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    responses(
+        (status = 200, description = "User deleted", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "User does not exist"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
+pub async fn delete_user(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    State(tokens): State<TokenStore>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Path(user_login): Path<String>,
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), ConfigError> {
+    let mut transaction = writer.transaction(if_match).await?;
+    let deleted = remove_user(&mut transaction.config, &user_login)?;
 
-    config
-        .to_file_or_internal_server_error(&config_file)
+    let etag = transaction.commit(&admin.0.login).await?;
+    tokens.revoke_all(&user_login).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not revoke personal tokens",
+        )
+    })?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::UserDeleted)
+                .target(&user_login)
+                .change(Some(&deleted.redacted()), None),
+        )
         .await?;
 
-    Ok((StatusCode::OK, "user deleted successfully"))
+    Ok((StatusCode::OK, etag, "user deleted successfully"))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/admin/users",
+    tag = "users",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    request_body(content = User, description = "User to create, with a plain password"),
+    responses(
+        (status = 201, description = "User created", headers(("ETag" = String, description = "Entity tag of the configuration"), ("Location" = String, description = "URL of the created user"))),
+        (status = 400, description = "Configuration would be invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 406, description = "Password is missing"),
+        (status = 409, description = "User already exists"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
 pub async fn add_user(
-    State(config_file): State<ConfigFile>,
-    State(config): State<ConfigState>,
-    _admin: AdminToken,
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Json(payload): Json<User>,
+) -> Result<
+    (
+        StatusCode,
+        TypedHeader<ETag>,
+        [(HeaderName, String); 1],
+        &'static str,
+    ),
+    ConfigError,
+> {
+    // Work on the file configuration, so that environment overrides are not written back
+    let mut transaction = writer.transaction(if_match).await?;
+    let login = payload.login.clone();
+    if find_user(&transaction.config, &login).is_some() {
+        return Err((StatusCode::CONFLICT, "user already exists").into());
+    }
+    upsert_user(&mut transaction.config, payload)?;
+    let after = find_user(&transaction.config, &login).map(|u| u.clone().redacted());
+
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::UserCreated)
+                .target(&login)
+                .change(None, after.as_ref()),
+        )
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        etag,
+        [(
+            LOCATION,
+            format!("/api/admin/users/{}", urlencoding::encode(&login)),
+        )],
+        "user created successfully",
+    ))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/admin/users/{user_login}",
+    tag = "users",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("user_login" = String, Path, description = "User login"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    request_body(content = User, description = "New value of the user, an empty or REDACTED password is left unchanged"),
+    responses(
+        (status = 200, description = "User replaced", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 400, description = "Configuration would be invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "User does not exist"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+    ),
+)]
+pub async fn replace_user(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Path(user_login): Path<String>,
     Json(mut payload): Json<User>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    // Clone the config
-    let mut config = (*config).clone();
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), ConfigError> {
+    let mut transaction = writer.transaction(if_match).await?;
+    let before = find_user(&transaction.config, &user_login)
+        .map(|u| u.clone().redacted())
+        .ok_or((StatusCode::NOT_FOUND, "user does not exist"))?;
+    payload.login = user_login.clone();
+    upsert_user(&mut transaction.config, payload)?;
+    let after = find_user(&transaction.config, &user_login).map(|u| u.clone().redacted());
+
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::UserUpdated)
+                .target(&user_login)
+                .change(Some(&before), after.as_ref()),
+        )
+        .await?;
+
+    Ok((StatusCode::OK, etag, "user updated successfully"))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/admin/users/{user_login}",
+    tag = "users",
+    security(("cookie" = []), ("bearer" = [])),
+    params(
+        ("user_login" = String, Path, description = "User login"),
+        ("If-Match" = Option<String>, Header, description = "Entity tag of the configuration the change is based on"),
+    ),
+    request_body(content = Object, description = "JSON merge patch (RFC 7396) of the user"),
+    responses(
+        (status = 200, description = "User updated", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 400, description = "Configuration would be invalid"),
+        (status = 401, description = "User is not an administrator"),
+        (status = 404, description = "User does not exist"),
+        (status = 412, description = "Configuration was modified in the meantime"),
+        (status = 422, description = "Patched user is invalid"),
+    ),
+)]
+pub async fn patch_user(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    admin: AdminToken,
+    OptionalIfMatch(if_match): OptionalIfMatch,
+    Path(user_login): Path<String>,
+    Json(patch): Json<serde_json::Value>,
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), ConfigError> {
+    let mut transaction = writer.transaction(if_match).await?;
+    let before = find_user(&transaction.config, &user_login)
+        .map(|u| u.clone().redacted())
+        .ok_or((StatusCode::NOT_FOUND, "user does not exist"))?;
+    // Patch the redacted user, so that an untouched password hash stays as it is
+    let mut value = serde_json::to_value(&before)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "could not encode user"))?;
+    merge_patch(&mut value, patch);
+    let mut payload: User = serde_json::from_value(value)
+        .map_err(|_| (StatusCode::UNPROCESSABLE_ENTITY, "patched user is invalid"))?;
+    payload.login = user_login.clone();
+    upsert_user(&mut transaction.config, payload)?;
+    let after = find_user(&transaction.config, &user_login).map(|u| u.clone().redacted());
+
+    let etag = transaction.commit(&admin.0.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&admin.0.login, ip, AuditAction::UserUpdated)
+                .target(&user_login)
+                .change(Some(&before), after.as_ref()),
+        )
+        .await?;
+
+    Ok((StatusCode::OK, etag, "user updated successfully"))
+}
+
+pub(crate) fn find_user<'a>(config: &'a Config, login: &str) -> Option<&'a User> {
+    config.users.iter().find(|u| u.login == login)
+}
+
+/// Removes a user from the configuration, returning it
+pub fn remove_user(config: &mut Config, login: &str) -> Result<User, (StatusCode, &'static str)> {
+    // Find the user
+    if let Some(pos) = config.users.iter().position(|u| u.login == login) {
+        // It is an existing user, delete it
+        Ok(config.users.remove(pos))
+    } else {
+        // If the user does not exist, respond with an error
+        Err((StatusCode::NOT_FOUND, "user does not exist"))
+    }
+}
+
+pub fn upsert_user(
+    config: &mut Config,
+    mut payload: User,
+) -> Result<(), (StatusCode, &'static str)> {
     // Find the user
     if let Some(user) = config.users.iter_mut().find(|u| u.login == payload.login) {
-        // It is an existing user, we only hash the password if it is not empty
-        if !payload.password.is_empty() {
+        // It is an existing user, we only hash the password if it is not empty nor redacted
+        if !payload.password.is_empty() && payload.password != REDACTED {
+            payload.password = hash_password_or_error(&payload.password)?;
         } else {
             payload.password = user.password.clone();
         }
+        // The second factor and the passkeys are managed by the user only
+        payload.totp = user.totp.clone();
+        payload.passkeys = user.passkeys.clone();
         *user = payload;
     } else {
         // It is a new user, we need to hash the password
-        if payload.password.is_empty() {
+        if payload.password.is_empty() || payload.password == REDACTED {
             return Err((StatusCode::NOT_ACCEPTABLE, "password is required"));
         }
-
+        payload.password = hash_password_or_error(&payload.password)?;
+        payload.totp = None;
+        payload.passkeys.clear();
         config.users.push(payload);
     }
+    Ok(())
+}
 
-    config
-        .to_file_or_internal_server_error(&config_file)
-        .await?;
+pub fn hash_password(password: &[u8]) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    Ok(Argon2::default()
+        .hash_password(password, &salt)?
+        .to_string())
+}
+
+fn hash_password_or_error(password: &str) -> Result<String, (StatusCode, &'static str)> {
+    hash_password(password.as_bytes())
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "could not hash password"))
+}
 
-    Ok((StatusCode::CREATED, "user created or updated successfully"))
+/// Checks a password against an argon2 hash, an invalid hash never matches
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/user/whoami",
+    tag = "user",
+    security(("cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Authenticated user", body = User),
+        (status = 401, description = "User is not authenticated"),
+    ),
+)]
 pub async fn whoami(token: UserToken) -> Json<User> {
     let user = User {
         login: token.login,
         password: REDACTED.to_owned(),
         roles: token.roles,
         info: token.info,
+        ..Default::default()
     };
     Json(user)
 }
 
+#[derive(Deserialize, ToSchema)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Finds the configured user behind a token, share tokens cannot act on behalf of the user
+pub(crate) fn token_user<'a>(
+    config: &'a Config,
+    token: &UserToken,
+) -> Result<&'a User, (StatusCode, &'static str)> {
+    if token.share.is_some() || token.scope.is_some() {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "share and personal tokens cannot manage the user",
+        ));
+    }
+    find_user(config, &token.login).ok_or((StatusCode::NOT_FOUND, "user does not exist"))
+}
+
+/// Re-issues the session cookie, so that it carries the updated user
+pub(crate) fn refresh_session(
+    jar: PrivateCookieJar,
+    token: &UserToken,
+    user: &User,
+    hostname: String,
+    config: &Config,
+    ip: IpAddr,
+) -> Result<PrivateCookieJar, (StatusCode, &'static str)> {
+    let token = UserToken {
+        roles: session_roles(user, config),
+        info: user.info.clone(),
+        ..token.clone()
+    };
+    let cookie = create_user_cookie(&token, hostname, config, ip, user)?;
+    Ok(jar.add(cookie))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/profile",
+    tag = "user",
+    security(("cookie" = []), ("bearer" = [])),
+    responses(
+        (status = 200, description = "Profile of the authenticated user", body = UserInfo),
+        (status = 401, description = "User is not authenticated"),
+        (status = 404, description = "User is not a local user"),
+    ),
+)]
+pub async fn get_profile(
+    State(writer): State<ConfigWriter>,
+    token: UserToken,
+) -> Result<Json<UserInfo>, (StatusCode, &'static str)> {
+    let (config, _) = writer.read().await?;
+    let user = token_user(&config, &token)?;
+    Ok(Json(user.info.clone().unwrap_or_default()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/user/profile",
+    tag = "user",
+    security(("cookie" = []), ("bearer" = [])),
+    request_body = UserInfo,
+    responses(
+        (status = 200, description = "Profile updated, the session cookie is refreshed", body = UserInfo),
+        (status = 401, description = "User is not authenticated"),
+        (status = 404, description = "User is not a local user"),
+    ),
+)]
+pub async fn update_profile(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    Host(hostname): Host,
+    jar: PrivateCookieJar,
+    token: UserToken,
+    Json(info): Json<UserInfo>,
+) -> Result<(PrivateCookieJar, TypedHeader<ETag>, Json<UserInfo>), (StatusCode, &'static str)> {
+    let mut transaction = writer.transaction(None).await?;
+    let before = token_user(&transaction.config, &token)?.clone().redacted();
+    let user = transaction
+        .config
+        .users
+        .iter_mut()
+        .find(|u| u.login == token.login)
+        .expect("user was found above");
+    user.info = if info == UserInfo::default() {
+        None
+    } else {
+        Some(info.clone())
+    };
+    let user = user.clone();
+
+    let etag = transaction.commit(&token.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&token.login, ip, AuditAction::ProfileUpdated)
+                .target(&token.login)
+                .change(Some(&before), Some(&user.clone().redacted())),
+        )
+        .await?;
+
+    let jar = refresh_session(jar, &token, &user, hostname, &writer.current(), ip)?;
+    Ok((jar, etag, Json(info)))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/user/password",
+    tag = "user",
+    security(("cookie" = []), ("bearer" = [])),
+    request_body = PasswordChange,
+    responses(
+        (status = 200, description = "Password changed, the session cookie is refreshed"),
+        (status = 400, description = "New password is empty"),
+        (status = 401, description = "User is not authenticated"),
+        (status = 403, description = "Current password is wrong"),
+        (status = 404, description = "User is not a local user"),
+    ),
+)]
+pub async fn change_password(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    Host(hostname): Host,
+    jar: PrivateCookieJar,
+    token: UserToken,
+    Json(payload): Json<PasswordChange>,
+) -> Result<(PrivateCookieJar, TypedHeader<ETag>, &'static str), (StatusCode, &'static str)> {
+    if payload.new_password.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "new password is empty"));
+    }
+    let mut transaction = writer.transaction(None).await?;
+    let user = token_user(&transaction.config, &token)?;
+    if !verify_password(&user.password, &payload.current_password) {
+        audit
+            .record_or_error(
+                AuditEvent::new(&token.login, ip, AuditAction::PasswordChangeFailed)
+                    .target(&token.login),
+            )
+            .await?;
+        return Err((StatusCode::FORBIDDEN, "current password is wrong"));
+    }
+    let hash = hash_password_or_error(&payload.new_password)?;
+    let user = transaction
+        .config
+        .users
+        .iter_mut()
+        .find(|u| u.login == token.login)
+        .expect("user was found above");
+    user.password = hash;
+    let user = user.clone();
+
+    let etag = transaction.commit(&token.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&token.login, ip, AuditAction::PasswordChanged).target(&token.login),
+        )
+        .await?;
+
+    let jar = refresh_session(jar, &token, &user, hostname, &writer.current(), ip)?;
+    Ok((jar, etag, "password changed successfully"))
+}
+
 pub async fn cookie_to_body<B>(
     req: Request<B>,
     next: Next<B>,
@@ -448,6 +1081,21 @@ pub fn check_user_has_role(user: &UserToken, roles: &[String]) -> bool {
     false
 }
 
+/// Tells if the restrictions of a personal token or a share forbid the access to the app
+pub(crate) fn token_restricted(
+    user: &UserToken,
+    target: &HostType,
+    hostname: &str,
+    path: &str,
+) -> bool {
+    user.scope
+        .as_ref()
+        .is_some_and(|scope| !scope.allows_app(target.id()))
+        || (user.share.is_some()
+            && (user.share.as_ref().unwrap().path != path
+                || user.share.as_ref().unwrap().hostname != hostname))
+}
+
 pub fn check_user_has_role_or_forbid(
     user: &Option<&UserToken>,
     target: &HostType,
@@ -456,40 +1104,33 @@ pub fn check_user_has_role_or_forbid(
 ) -> Option<Response<Body>> {
     if let Some(user) = user {
         if !check_user_has_role(user, target.roles())
-            || (user.share.is_some()
-                && (user.share.as_ref().unwrap().path != path
-                    || user.share.as_ref().unwrap().hostname != hostname))
+            || token_restricted(user, target, hostname, path)
         {
-            return Some(
-                Response::builder()
-                    .status(StatusCode::FORBIDDEN)
-                    .body(Body::empty())
-                    .unwrap(),
-            );
+            return Some(refusal(StatusCode::FORBIDDEN));
         }
         return None;
     }
-    Some(
-        Response::builder()
-            .status(StatusCode::UNAUTHORIZED)
-            .header(&WWWAUTHENTICATE, r#"Basic realm="server""#)
-            .body(Body::empty())
-            .unwrap(),
-    )
+    Some(refusal(StatusCode::UNAUTHORIZED))
 }
 
+fn refusal(status: StatusCode) -> Response<Body> {
+    let mut response = Response::builder().status(status);
+    if status == StatusCode::UNAUTHORIZED {
+        response = response.header(&WWWAUTHENTICATE, r#"Basic realm="server""#);
+    }
+    response.body(Body::empty()).unwrap()
+}
+
+/// Returns the refusal of the request if the policy of the app does not allow it
 pub fn check_authorization(
     app: &HostType,
     user: &Option<&UserToken>,
     hostname: &str,
-    path: &str,
+    access: &Access,
 ) -> Option<Response<Body>> {
-    if app.secured() {
-        if let Some(response) = check_user_has_role_or_forbid(user, app, hostname, path) {
-            return Some(response);
-        }
-    }
-    None
+    let decision = decide(app, *user, hostname, access);
+    (!decision.allowed)
+        .then(|| refusal(StatusCode::from_u16(decision.status).unwrap_or(StatusCode::FORBIDDEN)))
 }
 
 #[cfg(test)]
@@ -521,6 +1162,7 @@ mod check_user_has_role_or_forbid_tests {
     use crate::{
         apps::{App, AppWithUri},
         configuration::HostType,
+        tokens::TokenScope,
         users::{check_user_has_role_or_forbid, UserToken},
     };
 
@@ -613,6 +1255,29 @@ mod check_user_has_role_or_forbid_tests {
         assert!(check_user_has_role_or_forbid(&Some(&user), &target, "", "").is_some());
     }
 
+    #[test]
+    fn test_personal_token_app_scope() {
+        let mut user = UserToken {
+            roles: vec!["role1".to_string()],
+            scope: Some(TokenScope {
+                apps: vec![2],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let app = App {
+            id: 1,
+            target: "www.example.com".to_string(), // to prevent failing when parsing url
+            roles: vec!["role1".to_string()],
+            ..Default::default()
+        };
+        let app = AppWithUri::from_app_domain_and_http_port(app, "atrium.io", None);
+        let target = HostType::ReverseApp(Box::new(app));
+        assert!(check_user_has_role_or_forbid(&Some(&user), &target, "", "").is_some());
+        user.scope.as_mut().unwrap().apps.push(1);
+        assert!(check_user_has_role_or_forbid(&Some(&user), &target, "", "").is_none());
+    }
+
     #[test]
     fn test_all_roles_are_empty() {
         let user = UserToken::default();
@@ -625,3 +1290,237 @@ mod check_user_has_role_or_forbid_tests {
         assert!(check_user_has_role_or_forbid(&Some(&user), &target, "", "").is_some());
     }
 }
+
+#[cfg(test)]
+mod check_authorization_public_paths_tests {
+    use http::Method;
+
+    use crate::{
+        apps::{App, AppWithUri},
+        configuration::HostType,
+        policy::Access,
+        users::{check_authorization, Share, UserToken},
+    };
+
+    fn target() -> HostType {
+        let app = App {
+            target: "www.example.com".to_string(), // to prevent failing when parsing url
+            secured: true,
+            roles: vec!["role1".to_string()],
+            public_paths: vec!["/health".to_string(), "/static/**".to_string()],
+            ..Default::default()
+        };
+        let app = AppWithUri::from_app_domain_and_http_port(app, "atrium.io", None);
+        HostType::ReverseApp(Box::new(app))
+    }
+
+    fn check(user: &Option<&UserToken>, path: &str) -> Option<u16> {
+        let access = Access {
+            method: &Method::GET,
+            path,
+            source: [127, 0, 0, 1].into(),
+        };
+        check_authorization(&target(), user, "app.atrium.io", &access).map(|r| r.status().as_u16())
+    }
+
+    #[test]
+    fn test_public_paths_without_user() {
+        assert_eq!(check(&None, "/health"), None);
+        assert_eq!(check(&None, "/static/js/app.js"), None);
+        assert_eq!(check(&None, "/health/details"), Some(401));
+        assert_eq!(check(&None, "/"), Some(401));
+    }
+
+    #[test]
+    fn test_public_paths_traversal() {
+        assert_eq!(check(&None, "/static/../admin"), Some(401));
+        assert_eq!(check(&None, "/static/%2e%2e/admin"), Some(401));
+        assert_eq!(check(&None, "/static/..%2Fadmin"), Some(401));
+        assert_eq!(check(&None, "/static/../../admin"), Some(401));
+        assert_eq!(check(&None, "//static/./js/../app.js"), None);
+        assert_eq!(check(&None, "/%68ealth"), None);
+    }
+
+    #[test]
+    fn test_public_paths_with_user_without_role() {
+        let user = UserToken::default();
+        assert_eq!(check(&Some(&user), "/health"), None);
+        assert_eq!(check(&Some(&user), "/private"), Some(403));
+    }
+
+    #[test]
+    fn test_public_paths_with_share() {
+        let user = UserToken {
+            roles: vec!["role1".to_string()],
+            share: Some(Share {
+                hostname: "app.atrium.io".to_string(),
+                path: "/shared/doc.pdf".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(check(&Some(&user), "/shared/doc.pdf"), None);
+        // The assets of the shared page are public
+        assert_eq!(check(&Some(&user), "/static/viewer.js"), None);
+        assert_eq!(check(&Some(&user), "/shared/other.pdf"), Some(403));
+    }
+}
+
+#[cfg(test)]
+mod upsert_user_tests {
+    use crate::{
+        configuration::Config,
+        secrets::Secret,
+        totp::UserTotp,
+        users::{hash_password, upsert_user, verify_password, User, REDACTED},
+    };
+
+    fn config_with_user() -> Config {
+        Config {
+            users: vec![User {
+                login: "user".to_owned(),
+                password: "stored_hash".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_redacted_password_is_kept() {
+        let mut config = config_with_user();
+        let user = User {
+            login: "user".to_owned(),
+            password: REDACTED.to_owned(),
+            roles: vec!["USERS".to_owned()],
+            ..Default::default()
+        };
+        upsert_user(&mut config, user.clone()).unwrap();
+        assert_eq!(config.users[0].password, "stored_hash");
+        assert_eq!(config.users[0].roles, user.roles);
+        assert_eq!(config.users[0].clone().redacted().password, REDACTED);
+    }
+
+    #[test]
+    fn test_totp_is_kept() {
+        let mut config = config_with_user();
+        let totp = UserTotp {
+            secret: Secret::new("SECRET".to_owned()),
+            confirmed: true,
+            ..Default::default()
+        };
+        config.users[0].totp = Some(totp.clone());
+        let user = config.users[0].clone().redacted();
+        assert_eq!(user.totp.as_ref().unwrap().secret.expose(), REDACTED);
+        upsert_user(&mut config, User { totp: None, ..user }).unwrap();
+        assert_eq!(config.users[0].totp, Some(totp));
+    }
+
+    #[test]
+    fn test_new_password_is_hashed() {
+        let mut config = config_with_user();
+        let user = User {
+            login: "user".to_owned(),
+            password: "new_password".to_owned(),
+            ..Default::default()
+        };
+        upsert_user(&mut config, user).unwrap();
+        assert!(config.users[0].password.starts_with("$argon2"));
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let hash = hash_password(b"password").unwrap();
+        assert!(verify_password(&hash, "password"));
+        assert!(!verify_password(&hash, "wrong"));
+        assert!(!verify_password("not a hash", "not a hash"));
+    }
+
+    #[test]
+    fn test_new_user_requires_password() {
+        let mut config = config_with_user();
+        let user = User {
+            login: "new_user".to_owned(),
+            password: REDACTED.to_owned(),
+            ..Default::default()
+        };
+        assert!(upsert_user(&mut config, user).is_err());
+    }
+}
+
+#[cfg(test)]
+mod personal_token_user_tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use axum::{extract::FromRequestParts, response::IntoResponse};
+    use axum_extra::extract::{
+        cookie::{Cookie, Key},
+        PrivateCookieJar,
+    };
+    use http::{header::SET_COOKIE, Request, StatusCode};
+    use time::{Duration, OffsetDateTime};
+
+    use crate::{
+        appstate::AppState,
+        configuration::Config,
+        users::{UserToken, UserTokenWithoutXSRFCheck, AUTH_COOKIE},
+    };
+
+    #[tokio::test]
+    async fn test_foreign_bearer_token_falls_back_to_cookie() {
+        let key = Key::generate();
+        let config = Config {
+            hostname: "atrium.io".to_owned(),
+            ..Default::default()
+        };
+        let file = std::env::temp_dir().join("atrium_personal_token_user_test.yaml");
+        let state = AppState::new(
+            key.clone(),
+            Arc::new(config),
+            Arc::new(HashMap::new()),
+            file.to_str().unwrap().to_owned(),
+        );
+        let token = UserToken {
+            login: "user".to_owned(),
+            xsrf_token: "xsrf".to_owned(),
+            expires: (OffsetDateTime::now_utc() + Duration::minutes(1)).unix_timestamp(),
+            ..Default::default()
+        };
+        let jar = PrivateCookieJar::new(key).add(Cookie::new(
+            AUTH_COOKIE,
+            serde_json::to_string(&token).unwrap(),
+        ));
+        let response = jar.into_response();
+        let cookie = response.headers()[SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_owned();
+        let parts = |bearer: &str| {
+            Request::builder()
+                .header("Cookie", &cookie)
+                .header("xsrf-token", "xsrf")
+                .header("Authorization", format!("Bearer {bearer}"))
+                .body(())
+                .unwrap()
+                .into_parts()
+                .0
+        };
+
+        let user =
+            UserToken::from_request_parts(&mut parts("eyJhbGciOiJSUzI1NiJ9.e30.sig"), &state)
+                .await
+                .unwrap();
+        assert_eq!(user.login, "user");
+        let user = UserTokenWithoutXSRFCheck::from_request_parts(&mut parts("oauth-token"), &state)
+            .await
+            .unwrap();
+        assert_eq!(user.0.login, "user");
+
+        // A personal token is authoritative, even if it is not valid
+        let res = UserToken::from_request_parts(&mut parts("atr_1_invalid"), &state).await;
+        assert_eq!(res.err().unwrap().0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -64,11 +64,30 @@ pub(crate) fn is_default<T: Default + PartialEq>(t: &T) -> bool {
     t == &T::default()
 }
 
+/// Applies a JSON merge patch (RFC 7396) : objects are merged recursively, `null` removes a member, anything else replaces the target
+pub fn merge_patch(target: &mut serde_json::Value, patch: serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    let target = target.as_object_mut().expect("target is an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge_patch(target.entry(key).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
 const QUERY_ERROR: (StatusCode, &str) = (StatusCode::INTERNAL_SERVER_ERROR, "query is empty");
 
-pub fn raw_query_pairs<'a>(
-    query: Option<&'a str>,
-) -> Result<std::collections::HashMap<&'a str, &'a str>, (StatusCode, &'static str)> {
+pub fn raw_query_pairs(
+    query: Option<&str>,
+) -> Result<std::collections::HashMap<&str, &str>, (StatusCode, &'static str)> {
     let query = query.ok_or(QUERY_ERROR)?;
     if query.is_empty() {
         return Err(QUERY_ERROR);
@@ -84,11 +103,32 @@ pub fn raw_query_pairs<'a>(
     Ok(ooq)
 }
 
+/// Decodes a request path and resolves its `.` and `..` segments and repeated slashes, as the app would,
+/// so that it can be matched against globs. Fails if it is not valid UTF-8 once decoded or goes above the root.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let decoded = urlencoding::decode(path).ok()?;
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    if !segments.is_empty() && (decoded.ends_with('/') || decoded.ends_with("/.")) {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::utils::{
-        option_string_trim, option_vec_trim_remove_empties, raw_query_pairs, string_trim,
-        vec_trim_remove_empties,
+        merge_patch, normalize_path, option_string_trim, option_vec_trim_remove_empties,
+        raw_query_pairs, string_trim, vec_trim_remove_empties,
     };
     use serde::Deserialize;
 
@@ -186,6 +226,38 @@ mod tests {
         let query = Some("a=1&b=2&c=");
         let qp = raw_query_pairs(query).unwrap();
         assert_eq!(qp.get("a").unwrap(), &"1");
-        assert!(qp.get("c").is_none());
+        assert!(!qp.contains_key("c"));
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut target = serde_json::json!({"a": "b", "c": {"d": "e", "f": "g"}, "h": [1]});
+        merge_patch(
+            &mut target,
+            serde_json::json!({"a": "z", "c": {"f": null}, "h": [2, 3]}),
+        );
+        assert_eq!(
+            target,
+            serde_json::json!({"a": "z", "c": {"d": "e"}, "h": [2, 3]})
+        );
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/").as_deref(), Some("/"));
+        assert_eq!(normalize_path("/a/b/").as_deref(), Some("/a/b/"));
+        assert_eq!(normalize_path("//a/./b//c").as_deref(), Some("/a/b/c"));
+        assert_eq!(
+            normalize_path("/static/../admin").as_deref(),
+            Some("/admin")
+        );
+        assert_eq!(
+            normalize_path("/static/%2e%2e/admin").as_deref(),
+            Some("/admin")
+        );
+        assert_eq!(normalize_path("/%61pi/item").as_deref(), Some("/api/item"));
+        assert_eq!(normalize_path("/a%2F..%2Fb").as_deref(), Some("/b"));
+        assert_eq!(normalize_path("/../admin"), None);
+        assert_eq!(normalize_path("/%ff"), None);
     }
 }
@@ -0,0 +1,995 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Host, Path, State},
+    Json, TypedHeader,
+};
+use axum_extra::extract::cookie::{Cookie, PrivateCookieJar, SameSite};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use ciborium::Value;
+use headers::ETag;
+use http::StatusCode;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{Duration, OffsetDateTime};
+use utoipa::ToSchema;
+
+use crate::{
+    audit::{AuditAction, AuditEvent, AuditLog},
+    client_ip::ClientIp,
+    config_writer::ConfigWriter,
+    configuration::Config,
+    users::{start_session, token_user, AuthResponse, User, UserToken},
+};
+
+pub static WEBAUTHN_COOKIE: &str = "ATRIUM_WEBAUTHN";
+static RP_NAME: &str = "Atrium";
+const CHALLENGE_BYTES: usize = 32;
+const CEREMONY_MINUTES: i64 = 5;
+/// Ceremonies pending at once, the login ones being started without authentication
+const MAX_PENDING_CEREMONIES: usize = 10_000;
+/// COSE identifier of ECDSA with P-256 and SHA-256, the only algorithm supported
+const COSE_ES256: i64 = -7;
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+/// Public key credential of a local user
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct Passkey {
+    /// Base64url credential id
+    pub id: String,
+    pub name: String,
+    /// Base64url SEC1 encoded P-256 public key
+    pub public_key: String,
+    /// Signature counter of the authenticator, 0 if it does not keep one
+    #[serde(default)]
+    pub sign_count: u32,
+    pub created: i64,
+}
+
+/// Base64url user handle of a login
+fn user_handle(login: &str) -> String {
+    Base64UrlUnpadded::encode_string(&Sha256::digest(login.as_bytes()))
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    Base64UrlUnpadded::decode_vec(value.trim_end_matches('='))
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid base64url value"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum CeremonyKind {
+    Register,
+    Login,
+}
+
+/// Ceremony in progress, kept in an encrypted cookie, its challenge being kept by atrium until it is answered
+#[derive(Debug, Serialize, Deserialize)]
+struct Ceremony {
+    kind: CeremonyKind,
+    challenge: String,
+    login: Option<String>,
+    expires: i64,
+}
+
+impl Ceremony {
+    fn new(kind: CeremonyKind, login: Option<String>) -> Self {
+        let mut challenge = [0u8; CHALLENGE_BYTES];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        Ceremony {
+            kind,
+            challenge: Base64UrlUnpadded::encode_string(&challenge),
+            login,
+            expires: (OffsetDateTime::now_utc() + Duration::minutes(CEREMONY_MINUTES))
+                .unix_timestamp(),
+        }
+    }
+
+    fn start(
+        &self,
+        jar: PrivateCookieJar,
+        pending: &PendingCeremonies,
+        config: &Config,
+    ) -> Result<PrivateCookieJar, (StatusCode, &'static str)> {
+        pending.issue(self)?;
+        let encoded = serde_json::to_string(self).map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "could not encode ceremony",
+            )
+        })?;
+        Ok(jar.add(ceremony_cookie(encoded, config)))
+    }
+
+    fn take(
+        jar: PrivateCookieJar,
+        kind: CeremonyKind,
+        pending: &PendingCeremonies,
+        config: &Config,
+    ) -> Result<(PrivateCookieJar, Self), (StatusCode, &'static str)> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let ceremony = jar
+            .get(WEBAUTHN_COOKIE)
+            .and_then(|c| serde_json::from_str::<Ceremony>(c.value()).ok())
+            .filter(|c| c.kind == kind && pending.consume(&c.challenge, now))
+            .ok_or((StatusCode::UNAUTHORIZED, "no pending webauthn ceremony"))?;
+        let jar = jar.remove(ceremony_cookie(String::new(), config));
+        Ok((jar, ceremony))
+    }
+}
+
+/// Challenges issued and not answered yet. The cookie of a ceremony can be sent again, but its challenge
+/// is consumed by the first response, so that a captured response cannot be replayed.
+#[derive(Clone, Default)]
+pub struct PendingCeremonies {
+    challenges: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl PendingCeremonies {
+    fn issue(&self, ceremony: &Ceremony) -> Result<(), (StatusCode, &'static str)> {
+        let mut challenges = self
+            .challenges
+            .lock()
+            .expect("webauthn challenges lock is poisoned");
+        if challenges.len() >= MAX_PENDING_CEREMONIES {
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            challenges.retain(|_, expires| *expires >= now);
+            if challenges.len() >= MAX_PENDING_CEREMONIES {
+                return Err((
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "too many pending webauthn ceremonies",
+                ));
+            }
+        }
+        challenges.insert(ceremony.challenge.clone(), ceremony.expires);
+        Ok(())
+    }
+
+    /// Tells if the challenge was issued and has not expired, it cannot be used anymore afterwards
+    fn consume(&self, challenge: &str, now: i64) -> bool {
+        self.challenges
+            .lock()
+            .expect("webauthn challenges lock is poisoned")
+            .remove(challenge)
+            .is_some_and(|expires| expires >= now)
+    }
+}
+
+/// The ceremonies only take place on the main hostname, which is the relying party
+fn ceremony_cookie(value: String, config: &Config) -> Cookie<'static> {
+    Cookie::build(WEBAUTHN_COOKIE, value)
+        .domain(config.hostname.clone())
+        .path("/auth/webauthn")
+        .same_site(SameSite::Strict)
+        .secure(config.tls_mode.is_secure())
+        .max_age(Duration::minutes(CEREMONY_MINUTES))
+        .http_only(true)
+        .finish()
+}
+
+/// Relying party of the ceremonies : the main hostname, and the origin the browser must report
+struct RelyingParty {
+    id: String,
+    origin: String,
+}
+
+impl RelyingParty {
+    /// The origin is the one of the configuration, the Host header coming from the client
+    fn new(config: &Config) -> Self {
+        RelyingParty {
+            id: config.hostname.clone(),
+            origin: config.full_hostname(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+fn check_client_data(
+    raw: &[u8],
+    kind: &str,
+    ceremony: &Ceremony,
+    rp: &RelyingParty,
+) -> Result<(), (StatusCode, &'static str)> {
+    let client_data: ClientData = serde_json::from_slice(raw)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid client data"))?;
+    if client_data.kind != kind {
+        return Err((StatusCode::BAD_REQUEST, "wrong client data type"));
+    }
+    if client_data.challenge.trim_end_matches('=') != ceremony.challenge {
+        return Err((StatusCode::UNAUTHORIZED, "challenge does not match"));
+    }
+    if client_data.origin != rp.origin {
+        return Err((StatusCode::UNAUTHORIZED, "origin does not match"));
+    }
+    Ok(())
+}
+
+/// Authenticator data, with the credential it attests at registration
+struct AuthenticatorData {
+    sign_count: u32,
+    credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+fn parse_authenticator_data(
+    data: &[u8],
+    rp: &RelyingParty,
+) -> Result<AuthenticatorData, (StatusCode, &'static str)> {
+    const INVALID: (StatusCode, &str) = (StatusCode::BAD_REQUEST, "invalid authenticator data");
+    if data.len() < 37 {
+        return Err(INVALID);
+    }
+    if data[..32] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err((StatusCode::UNAUTHORIZED, "relying party does not match"));
+    }
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err((StatusCode::UNAUTHORIZED, "user is not present"));
+    }
+    // The passkey replaces the password, it must check the user itself
+    if flags & FLAG_USER_VERIFIED == 0 {
+        return Err((StatusCode::UNAUTHORIZED, "user is not verified"));
+    }
+    let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+    let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        // AAGUID (16 bytes), then the length of the credential id (2 bytes), the id and the COSE key
+        let rest = data.get(37 + 16..).ok_or(INVALID)?;
+        let (length, rest) = rest.split_at_checked(2).ok_or(INVALID)?;
+        let (id, key) = rest
+            .split_at_checked(u16::from_be_bytes([length[0], length[1]]) as usize)
+            .ok_or(INVALID)?;
+        Some((id.to_vec(), key.to_vec()))
+    } else {
+        None
+    };
+    Ok(AuthenticatorData {
+        sign_count,
+        credential,
+    })
+}
+
+fn map_get<'a>(map: &'a [(Value, Value)], key: &Value) -> Option<&'a Value> {
+    map.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Converts a COSE EC2 P-256 key (which may be followed by extensions) to its SEC1 encoding
+fn cose_to_sec1(cose: &[u8]) -> Result<Vec<u8>, (StatusCode, &'static str)> {
+    const INVALID: (StatusCode, &str) = (StatusCode::BAD_REQUEST, "unsupported public key");
+    let key: Value = ciborium::de::from_reader(cose).map_err(|_| INVALID)?;
+    let map = key.as_map().ok_or(INVALID)?;
+    let int = |k: i64| map_get(map, &Value::Integer(k.into()));
+    let bytes = |k: i64| int(k).and_then(Value::as_bytes).ok_or(INVALID);
+    // kty EC2, alg ES256, crv P-256
+    if int(1) != Some(&Value::Integer(2.into()))
+        || int(3) != Some(&Value::Integer(COSE_ES256.into()))
+        || int(-1) != Some(&Value::Integer(1.into()))
+    {
+        return Err(INVALID);
+    }
+    let mut sec1 = vec![0x04];
+    sec1.extend_from_slice(bytes(-2)?);
+    sec1.extend_from_slice(bytes(-3)?);
+    VerifyingKey::from_sec1_bytes(&sec1).map_err(|_| INVALID)?;
+    Ok(sec1)
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// Result of `navigator.credentials.create()`, in its JSON form
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+/// Result of `navigator.credentials.get()`, in its JSON form
+#[derive(Deserialize, Serialize, ToSchema)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+/// Checks a registration response and works out the passkey it creates.
+///
+/// Attestation is not requested, so the attestation statement is not checked.
+fn verify_registration(
+    ceremony: &Ceremony,
+    rp: &RelyingParty,
+    credential: &RegistrationCredential,
+    name: String,
+) -> Result<Passkey, (StatusCode, &'static str)> {
+    check_client_data(
+        &decode(&credential.response.client_data_json)?,
+        "webauthn.create",
+        ceremony,
+        rp,
+    )?;
+    let attestation: Value =
+        ciborium::de::from_reader(&decode(&credential.response.attestation_object)?[..])
+            .map_err(|_| (StatusCode::BAD_REQUEST, "invalid attestation object"))?;
+    let auth_data = attestation
+        .as_map()
+        .and_then(|m| map_get(m, &Value::Text("authData".to_owned())))
+        .and_then(Value::as_bytes)
+        .ok_or((StatusCode::BAD_REQUEST, "invalid attestation object"))?;
+    let auth_data = parse_authenticator_data(auth_data, rp)?;
+    let (id, key) = auth_data
+        .credential
+        .ok_or((StatusCode::BAD_REQUEST, "no credential was attested"))?;
+    let id = Base64UrlUnpadded::encode_string(&id);
+    if id != credential.id.trim_end_matches('=') {
+        return Err((StatusCode::BAD_REQUEST, "credential id does not match"));
+    }
+    Ok(Passkey {
+        id,
+        name,
+        public_key: Base64UrlUnpadded::encode_string(&cose_to_sec1(&key)?),
+        sign_count: auth_data.sign_count,
+        created: OffsetDateTime::now_utc().unix_timestamp(),
+    })
+}
+
+/// Checks an authentication response against a passkey of the user, returning the new signature counter
+fn verify_assertion(
+    ceremony: &Ceremony,
+    rp: &RelyingParty,
+    user: &User,
+    passkey: &Passkey,
+    credential: &AuthenticationCredential,
+) -> Result<u32, (StatusCode, &'static str)> {
+    let response = &credential.response;
+    if ceremony.login.as_ref().is_some_and(|l| *l != user.login)
+        || response
+            .user_handle
+            .as_ref()
+            .is_some_and(|h| h.trim_end_matches('=') != user_handle(&user.login))
+    {
+        return Err((StatusCode::UNAUTHORIZED, "passkey is not registered"));
+    }
+    let client_data = decode(&response.client_data_json)?;
+    check_client_data(&client_data, "webauthn.get", ceremony, rp)?;
+    let raw_auth_data = decode(&response.authenticator_data)?;
+    let auth_data = parse_authenticator_data(&raw_auth_data, rp)?;
+
+    let key = VerifyingKey::from_sec1_bytes(&decode(&passkey.public_key)?).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "stored public key is invalid",
+        )
+    })?;
+    let signature = Signature::from_der(&decode(&response.signature)?)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "invalid signature"))?;
+    let mut signed = raw_auth_data;
+    signed.extend_from_slice(&Sha256::digest(&client_data));
+    key.verify(&signed, &signature)
+        .map_err(|_| (StatusCode::UNAUTHORIZED, "signature is invalid"))?;
+
+    // A counter that does not increase reveals a cloned authenticator
+    if (auth_data.sign_count != 0 || passkey.sign_count != 0)
+        && auth_data.sign_count <= passkey.sign_count
+    {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "signature counter did not increase",
+        ));
+    }
+    Ok(auth_data.sign_count)
+}
+
+fn find_passkey<'a>(config: &'a Config, id: &str) -> Option<(&'a User, &'a Passkey)> {
+    let id = id.trim_end_matches('=');
+    config
+        .users
+        .iter()
+        .find_map(|u| u.passkeys.iter().find(|p| p.id == id).map(|p| (u, p)))
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PublicKeyEntity {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicKeyUser {
+    /// Base64url user handle
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub alg: i64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Base64url credential id
+    pub id: String,
+}
+
+impl From<&Passkey> for CredentialDescriptor {
+    fn from(passkey: &Passkey) -> Self {
+        CredentialDescriptor {
+            kind: "public-key".to_owned(),
+            id: passkey.id.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String,
+}
+
+/// Options of `navigator.credentials.create()`, as taken by `PublicKeyCredential.parseCreationOptionsFromJSON()`
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub challenge: String,
+    pub rp: PublicKeyEntity,
+    pub user: PublicKeyUser,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    /// Milliseconds
+    pub timeout: i64,
+    pub attestation: String,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection,
+}
+
+/// Options of `navigator.credentials.get()`, as taken by `PublicKeyCredential.parseRequestOptionsFromJSON()`
+#[derive(Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    /// Milliseconds
+    pub timeout: i64,
+    pub user_verification: String,
+    /// Always empty, the user picks one of the discoverable passkeys
+    pub allow_credentials: Vec<CredentialDescriptor>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterFinish {
+    /// Name of the passkey, to tell it from the others
+    #[serde(default)]
+    pub name: String,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Default, Deserialize, ToSchema)]
+pub struct LoginStart {
+    /// Login of the user, to refuse the passkeys of anyone else
+    #[serde(default)]
+    pub login: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/webauthn/register/start",
+    tag = "auth",
+    security(("cookie" = [])),
+    responses(
+        (status = 200, description = "Options of the passkey creation, the ceremony cookie is set", body = CreationOptions),
+        (status = 401, description = "User is not authenticated"),
+        (status = 404, description = "User is not a local user"),
+    ),
+)]
+pub async fn register_start(
+    State(writer): State<ConfigWriter>,
+    State(pending): State<PendingCeremonies>,
+    jar: PrivateCookieJar,
+    token: UserToken,
+) -> Result<(PrivateCookieJar, Json<CreationOptions>), (StatusCode, &'static str)> {
+    let config = writer.current();
+    let user = token_user(&config, &token)?;
+    let ceremony = Ceremony::new(CeremonyKind::Register, Some(user.login.clone()));
+    let jar = ceremony.start(jar, &pending, &config)?;
+    let display_name = user
+        .info
+        .as_ref()
+        .map(|i| format!("{} {}", i.firstname, i.lastname).trim().to_owned())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| user.login.clone());
+    Ok((
+        jar,
+        Json(CreationOptions {
+            challenge: ceremony.challenge,
+            rp: PublicKeyEntity {
+                id: config.hostname.clone(),
+                name: RP_NAME.to_owned(),
+            },
+            user: PublicKeyUser {
+                id: user_handle(&user.login),
+                name: user.login.clone(),
+                display_name,
+            },
+            pub_key_cred_params: vec![CredentialParameters {
+                kind: "public-key".to_owned(),
+                alg: COSE_ES256,
+            }],
+            timeout: CEREMONY_MINUTES * 60 * 1000,
+            attestation: "none".to_owned(),
+            exclude_credentials: user
+                .passkeys
+                .iter()
+                .map(CredentialDescriptor::from)
+                .collect(),
+            authenticator_selection: AuthenticatorSelection {
+                // Passkeys are never listed at login, so they must be discoverable
+                resident_key: "required".to_owned(),
+                user_verification: "required".to_owned(),
+            },
+        }),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/webauthn/register/finish",
+    tag = "auth",
+    security(("cookie" = [])),
+    request_body = RegisterFinish,
+    responses(
+        (status = 201, description = "Passkey registered", body = Passkey),
+        (status = 400, description = "Response of the authenticator is invalid or unsupported"),
+        (status = 401, description = "User is not authenticated, or the ceremony failed"),
+        (status = 409, description = "Passkey is already registered"),
+    ),
+)]
+pub async fn register_finish(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    State(pending): State<PendingCeremonies>,
+    ClientIp(ip): ClientIp,
+    jar: PrivateCookieJar,
+    token: UserToken,
+    Json(payload): Json<RegisterFinish>,
+) -> Result<
+    (
+        StatusCode,
+        PrivateCookieJar,
+        TypedHeader<ETag>,
+        Json<Passkey>,
+    ),
+    (StatusCode, &'static str),
+> {
+    let config = writer.current();
+    let (jar, ceremony) = Ceremony::take(jar, CeremonyKind::Register, &pending, &config)?;
+    if ceremony.login.as_ref() != Some(&token.login) {
+        return Err((StatusCode::UNAUTHORIZED, "no pending webauthn ceremony"));
+    }
+    let name = match payload.name.trim() {
+        "" => "Passkey".to_owned(),
+        name => name.to_owned(),
+    };
+    let passkey = verify_registration(
+        &ceremony,
+        &RelyingParty::new(&config),
+        &payload.credential,
+        name,
+    )?;
+
+    let mut transaction = writer.transaction(None).await?;
+    token_user(&transaction.config, &token)?;
+    if find_passkey(&transaction.config, &passkey.id).is_some() {
+        return Err((StatusCode::CONFLICT, "passkey is already registered"));
+    }
+    transaction
+        .config
+        .users
+        .iter_mut()
+        .find(|u| u.login == token.login)
+        .expect("user was found above")
+        .passkeys
+        .push(passkey.clone());
+
+    let etag = transaction.commit(&token.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&token.login, ip, AuditAction::PasskeyRegistered)
+                .target(&passkey.id)
+                .change(None, Some(&passkey)),
+        )
+        .await?;
+    Ok((StatusCode::CREATED, jar, etag, Json(passkey)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/webauthn/login/start",
+    tag = "auth",
+    request_body = LoginStart,
+    responses(
+        (status = 200, description = "Options of the passkey assertion, the ceremony cookie is set", body = RequestOptions),
+        (status = 429, description = "Too many ceremonies are pending"),
+    ),
+)]
+pub async fn login_start(
+    State(writer): State<ConfigWriter>,
+    State(pending): State<PendingCeremonies>,
+    jar: PrivateCookieJar,
+    Json(payload): Json<LoginStart>,
+) -> Result<(PrivateCookieJar, Json<RequestOptions>), (StatusCode, &'static str)> {
+    let config = writer.current();
+    let ceremony = Ceremony::new(CeremonyKind::Login, payload.login);
+    let jar = ceremony.start(jar, &pending, &config)?;
+    Ok((
+        jar,
+        Json(RequestOptions {
+            challenge: ceremony.challenge,
+            rp_id: config.hostname.clone(),
+            timeout: CEREMONY_MINUTES * 60 * 1000,
+            user_verification: "required".to_owned(),
+            // Listing the passkeys of the login would disclose them to anyone asking
+            allow_credentials: Vec::new(),
+        }),
+    ))
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth/webauthn/login/finish",
+    tag = "auth",
+    request_body = AuthenticationCredential,
+    responses(
+        (status = 200, description = "User authenticated, the session cookie is set", body = AuthResponse),
+        (status = 400, description = "Response of the authenticator is invalid"),
+        (status = 401, description = "Authentication failed"),
+    ),
+)]
+pub async fn login_finish(
+    ClientIp(ip): ClientIp,
+    jar: PrivateCookieJar,
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    State(pending): State<PendingCeremonies>,
+    Host(hostname): Host,
+    Json(credential): Json<AuthenticationCredential>,
+) -> Result<(PrivateCookieJar, Json<AuthResponse>), (StatusCode, &'static str)> {
+    let config = writer.current();
+    let (jar, ceremony) = Ceremony::take(jar, CeremonyKind::Login, &pending, &config)?;
+    let (user, passkey) = find_passkey(&config, &credential.id)
+        .ok_or((StatusCode::UNAUTHORIZED, "passkey is not registered"))?;
+    let rp = RelyingParty::new(&config);
+    let sign_count = match verify_assertion(&ceremony, &rp, user, passkey, &credential) {
+        Ok(sign_count) => sign_count,
+        Err(e) => {
+            audit
+                .record_failure(
+                    AuditEvent::new(&user.login, ip, AuditAction::LoginFailed).target(&passkey.id),
+                )
+                .await;
+            return Err(e);
+        }
+    };
+
+    if sign_count != passkey.sign_count {
+        let mut transaction = writer.transaction(None).await?;
+        if let Some(stored) = transaction
+            .config
+            .users
+            .iter_mut()
+            .find(|u| u.login == user.login)
+            .and_then(|u| u.passkeys.iter_mut().find(|p| p.id == passkey.id))
+        {
+            stored.sign_count = sign_count;
+        }
+        // The counter must be kept, whatever the version of the configuration
+        let _ = transaction.commit(&user.login).await?;
+    }
+
+    let (jar, response) = start_session(jar, user, hostname, &config, ip)?;
+    audit
+        .record_or_error(AuditEvent::new(&user.login, ip, AuditAction::Login).target(&passkey.id))
+        .await?;
+    Ok((jar, Json(response)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/passkeys",
+    tag = "user",
+    security(("cookie" = [])),
+    responses(
+        (status = 200, description = "Passkeys of the user", body = [Passkey]),
+        (status = 401, description = "User is not authenticated"),
+        (status = 404, description = "User is not a local user"),
+    ),
+)]
+pub async fn list_passkeys(
+    State(writer): State<ConfigWriter>,
+    token: UserToken,
+) -> Result<Json<Vec<Passkey>>, (StatusCode, &'static str)> {
+    let config = writer.current();
+    Ok(Json(token_user(&config, &token)?.passkeys.clone()))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/user/passkeys/{passkey_id}",
+    tag = "user",
+    security(("cookie" = [])),
+    params(("passkey_id" = String, Path, description = "Base64url credential id")),
+    responses(
+        (status = 200, description = "Passkey removed", headers(("ETag" = String, description = "Entity tag of the configuration"))),
+        (status = 401, description = "User is not authenticated"),
+        (status = 404, description = "Passkey does not exist"),
+    ),
+)]
+pub async fn delete_passkey(
+    State(writer): State<ConfigWriter>,
+    State(audit): State<AuditLog>,
+    ClientIp(ip): ClientIp,
+    token: UserToken,
+    Path(passkey_id): Path<String>,
+) -> Result<(StatusCode, TypedHeader<ETag>, &'static str), (StatusCode, &'static str)> {
+    let mut transaction = writer.transaction(None).await?;
+    token_user(&transaction.config, &token)?;
+    let passkeys = &mut transaction
+        .config
+        .users
+        .iter_mut()
+        .find(|u| u.login == token.login)
+        .expect("user was found above")
+        .passkeys;
+    let position = passkeys
+        .iter()
+        .position(|p| p.id == passkey_id)
+        .ok_or((StatusCode::NOT_FOUND, "passkey does not exist"))?;
+    let removed = passkeys.remove(position);
+
+    let etag = transaction.commit(&token.login).await?;
+    audit
+        .record_or_error(
+            AuditEvent::new(&token.login, ip, AuditAction::PasskeyRemoved)
+                .target(&passkey_id)
+                .change(Some(&removed), None),
+        )
+        .await?;
+    Ok((StatusCode::OK, etag, "passkey removed successfully"))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64ct::{Base64UrlUnpadded, Encoding};
+    use ciborium::Value;
+    use http::StatusCode;
+    use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+    use sha2::{Digest, Sha256};
+
+    use crate::{
+        configuration::{Config, TlsMode},
+        users::User,
+        webauthn::{
+            verify_assertion, verify_registration, AssertionResponse, AttestationResponse,
+            AuthenticationCredential, Ceremony, CeremonyKind, Passkey, PendingCeremonies,
+            RegistrationCredential, RelyingParty, COSE_ES256,
+        },
+    };
+
+    /// Software authenticator with a single P-256 credential
+    struct Authenticator {
+        key: SigningKey,
+        id: Vec<u8>,
+        sign_count: u32,
+    }
+
+    impl Authenticator {
+        fn new() -> Self {
+            Authenticator {
+                key: SigningKey::from_bytes(&[7u8; 32].into()).unwrap(),
+                id: b"software credential".to_vec(),
+                sign_count: 0,
+            }
+        }
+
+        fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+            serde_json::to_vec(&serde_json::json!({
+                "type": kind,
+                "challenge": challenge,
+                "origin": origin,
+            }))
+            .unwrap()
+        }
+
+        fn authenticator_data(&self, rp_id: &str, flags: u8) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            data
+        }
+
+        fn create(&self, rp_id: &str, origin: &str, challenge: &str) -> RegistrationCredential {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let cose = Value::Map(vec![
+                (Value::Integer(1.into()), Value::Integer(2.into())),
+                (Value::Integer(3.into()), Value::Integer(COSE_ES256.into())),
+                (Value::Integer((-1).into()), Value::Integer(1.into())),
+                (
+                    Value::Integer((-2).into()),
+                    Value::Bytes(point.x().unwrap().to_vec()),
+                ),
+                (
+                    Value::Integer((-3).into()),
+                    Value::Bytes(point.y().unwrap().to_vec()),
+                ),
+            ]);
+            let mut auth_data = self.authenticator_data(rp_id, 0x45);
+            auth_data.extend_from_slice(&[0u8; 16]);
+            auth_data.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+            auth_data.extend_from_slice(&self.id);
+            ciborium::ser::into_writer(&cose, &mut auth_data).unwrap();
+            let attestation = Value::Map(vec![
+                (Value::Text("fmt".into()), Value::Text("none".into())),
+                (Value::Text("attStmt".into()), Value::Map(vec![])),
+                (Value::Text("authData".into()), Value::Bytes(auth_data)),
+            ]);
+            let mut attestation_object = Vec::new();
+            ciborium::ser::into_writer(&attestation, &mut attestation_object).unwrap();
+            RegistrationCredential {
+                id: Base64UrlUnpadded::encode_string(&self.id),
+                response: AttestationResponse {
+                    client_data_json: Base64UrlUnpadded::encode_string(&Self::client_data(
+                        "webauthn.create",
+                        challenge,
+                        origin,
+                    )),
+                    attestation_object: Base64UrlUnpadded::encode_string(&attestation_object),
+                },
+            }
+        }
+
+        fn get(&mut self, rp_id: &str, origin: &str, challenge: &str) -> AuthenticationCredential {
+            self.get_with_flags(rp_id, origin, challenge, 0x05)
+        }
+
+        fn get_with_flags(
+            &mut self,
+            rp_id: &str,
+            origin: &str,
+            challenge: &str,
+            flags: u8,
+        ) -> AuthenticationCredential {
+            self.sign_count += 1;
+            let client_data = Self::client_data("webauthn.get", challenge, origin);
+            let auth_data = self.authenticator_data(rp_id, flags);
+            let mut signed = auth_data.clone();
+            signed.extend_from_slice(&Sha256::digest(&client_data));
+            let signature: Signature = self.key.sign(&signed);
+            AuthenticationCredential {
+                id: Base64UrlUnpadded::encode_string(&self.id),
+                response: AssertionResponse {
+                    client_data_json: Base64UrlUnpadded::encode_string(&client_data),
+                    authenticator_data: Base64UrlUnpadded::encode_string(&auth_data),
+                    signature: Base64UrlUnpadded::encode_string(signature.to_der().as_bytes()),
+                    user_handle: None,
+                },
+            }
+        }
+    }
+
+    fn rp() -> RelyingParty {
+        RelyingParty {
+            id: "atrium.io".to_owned(),
+            origin: "https://atrium.io".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_challenges_are_consumed_once() {
+        let pending = PendingCeremonies::default();
+        let ceremony = Ceremony::new(CeremonyKind::Login, None);
+        pending.issue(&ceremony).unwrap();
+        assert!(!pending.consume("unknown", 0));
+        assert!(pending.consume(&ceremony.challenge, ceremony.expires));
+        assert!(!pending.consume(&ceremony.challenge, ceremony.expires));
+
+        let ceremony = Ceremony::new(CeremonyKind::Login, None);
+        pending.issue(&ceremony).unwrap();
+        assert!(!pending.consume(&ceremony.challenge, ceremony.expires + 1));
+    }
+
+    #[test]
+    fn test_relying_party_of_configuration() {
+        let config = Config {
+            hostname: "atrium.io".to_owned(),
+            http_port: 8080,
+            ..Default::default()
+        };
+        assert_eq!(RelyingParty::new(&config).origin, "http://atrium.io:8080");
+        let config = Config {
+            tls_mode: TlsMode::BehindProxy,
+            ..config
+        };
+        let rp = RelyingParty::new(&config);
+        assert_eq!(rp.id, "atrium.io");
+        assert_eq!(rp.origin, "https://atrium.io");
+    }
+
+    #[test]
+    fn test_register_and_login() {
+        let rp = rp();
+        let mut authenticator = Authenticator::new();
+        let ceremony = Ceremony::new(CeremonyKind::Register, Some("user".to_owned()));
+        let credential = authenticator.create(&rp.id, &rp.origin, &ceremony.challenge);
+        let passkey = verify_registration(&ceremony, &rp, &credential, "key".to_owned()).unwrap();
+        assert_eq!(passkey.id, credential.id);
+        let user = User {
+            login: "user".to_owned(),
+            passkeys: vec![passkey.clone()],
+            ..Default::default()
+        };
+
+        let ceremony = Ceremony::new(CeremonyKind::Login, None);
+        let assertion = authenticator.get(&rp.id, &rp.origin, &ceremony.challenge);
+        assert_eq!(
+            verify_assertion(&ceremony, &rp, &user, &passkey, &assertion),
+            Ok(1)
+        );
+
+        // Another challenge, origin or a tampered signature are rejected
+        let other = Ceremony::new(CeremonyKind::Login, None);
+        assert!(verify_assertion(&other, &rp, &user, &passkey, &assertion).is_err());
+        let phished = authenticator.get(&rp.id, "https://evil.io", &ceremony.challenge);
+        assert!(verify_assertion(&ceremony, &rp, &user, &passkey, &phished).is_err());
+        let mut tampered = authenticator.get(&rp.id, &rp.origin, &ceremony.challenge);
+        tampered.response.authenticator_data = assertion.response.authenticator_data.clone();
+        assert_eq!(
+            verify_assertion(&ceremony, &rp, &user, &passkey, &tampered).unwrap_err(),
+            (StatusCode::UNAUTHORIZED, "signature is invalid")
+        );
+        // The user must be verified, not only present
+        let unverified =
+            authenticator.get_with_flags(&rp.id, &rp.origin, &ceremony.challenge, 0x01);
+        assert_eq!(
+            verify_assertion(&ceremony, &rp, &user, &passkey, &unverified).unwrap_err(),
+            (StatusCode::UNAUTHORIZED, "user is not verified")
+        );
+        // A counter going backwards reveals a cloned authenticator
+        let passkey = Passkey {
+            sign_count: 10,
+            ..passkey
+        };
+        let assertion = authenticator.get(&rp.id, &rp.origin, &ceremony.challenge);
+        assert!(verify_assertion(&ceremony, &rp, &user, &passkey, &assertion).is_err());
+    }
+}